@@ -493,13 +493,13 @@ EOL
     fi
 
     # Add RustTaTor environment variables
-    if ! grep -q "RUSTATOR_TOR_SOCKS_PORT" "$ENV_FILE"; then
+    if ! grep -q "RUSTTATOR_TOR_SOCKS_PORT" "$ENV_FILE"; then
         cat >> "$ENV_FILE" << EOL
 
 # RustTaTor environment
-export RUSTATOR_TOR_SOCKS_PORT=9052
-export RUSTATOR_TOR_CONTROL_PORT=9053
-export RUSTATOR_TOR_CONTROL_PASSWORD="${TOR_PASS}"
+export RUSTTATOR_TOR_SOCKS_PORT=9052
+export RUSTTATOR_TOR_CONTROL_PORT=9053
+export RUSTTATOR_TOR_CONTROL_PASSWORD="${TOR_PASS}"
 EOL
         print_success "Added RustTaTor environment variables to $ENV_FILE"
     fi
@@ -0,0 +1,501 @@
+//! Local management API: JSON status and control over HTTP on a loopback
+//! address or a Unix socket, protected by a bearer token.
+//!
+//! GET  /status      instances, their policy, traffic and last rotation
+//! GET  /ip          current exit IP and location per instance
+//! GET  /circuits    built circuits per instance
+//! GET  /history     recent rotations, oldest first
+//! POST /rotate      rotate now
+//! PUT  /interval    {"interval": "5m"}
+//! PUT  /policy      {"policy": "interval:5m | requests:100"}
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::fmt;
+use std::net::SocketAddr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::console::Command;
+use crate::http_proxy::{read_head, write_response, RequestHead};
+use crate::pool::{TorInstance, TorPool};
+use crate::relay::RelayCache;
+use crate::shutdown::Shutdown;
+use crate::status::Status;
+
+/// Largest request body we accept.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Clients must send their whole request within this time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the API listens: `127.0.0.1:8080` or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiEndpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ApiEndpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ApiEndpoint::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(ApiEndpoint::Tcp)
+                .map_err(|_| anyhow!("expected HOST:PORT or unix:PATH, got {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ApiEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiEndpoint::Tcp(addr) => write!(f, "{}", addr),
+            ApiEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl fmt::Display) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+pub struct ApiServer {
+    token: String,
+    status: Arc<Status>,
+    pool: Arc<TorPool>,
+    relay_cache: RelayCache,
+    commands: broadcast::Sender<Command>,
+}
+
+impl ApiServer {
+    pub fn new(
+        token: String,
+        status: Arc<Status>,
+        pool: Arc<TorPool>,
+        relay_cache: RelayCache,
+        commands: broadcast::Sender<Command>,
+    ) -> Self {
+        Self {
+            token,
+            status,
+            pool,
+            relay_cache,
+            commands,
+        }
+    }
+
+    /// Binds `endpoint` and serves requests in the background until
+    /// shutdown, after which a Unix socket is removed again.
+    pub async fn listen(self: Arc<Self>, endpoint: &ApiEndpoint, shutdown: Shutdown) -> Result<JoinHandle<()>> {
+        let task = match endpoint {
+            ApiEndpoint::Tcp(addr) => {
+                if !addr.ip().is_loopback() {
+                    warn!("Management API on non-loopback address {}; anyone who can reach it and knows the token can control RustTaTor", addr);
+                }
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to listen on {}", addr))?;
+                tokio::spawn(async move {
+                    loop {
+                        let accepted = tokio::select! {
+                            accepted = listener.accept() => accepted,
+                            _ = shutdown.requested() => return,
+                        };
+                        match accepted {
+                            Ok((stream, _)) => self.spawn_handler(stream),
+                            Err(e) => {
+                                warn!("Failed to accept API connection: {}", e);
+                                time::sleep(Duration::from_millis(100)).await;
+                            }
+                        }
+                    }
+                })
+            }
+            #[cfg(unix)]
+            ApiEndpoint::Unix(path) => {
+                let listener = bind_private(path)?;
+                let path = path.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let accepted = tokio::select! {
+                            accepted = listener.accept() => accepted,
+                            _ = shutdown.requested() => break,
+                        };
+                        match accepted {
+                            Ok((stream, _)) => self.spawn_handler(stream),
+                            Err(e) => {
+                                warn!("Failed to accept API connection: {}", e);
+                                time::sleep(Duration::from_millis(100)).await;
+                            }
+                        }
+                    }
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        warn!("Failed to remove {}: {}", path.display(), e);
+                    }
+                })
+            }
+            #[cfg(not(unix))]
+            ApiEndpoint::Unix(_) => return Err(anyhow!("Unix sockets are not supported on this platform")),
+        };
+        info!("🛠️ Management API listening on {}", endpoint);
+        Ok(task)
+    }
+
+    fn spawn_handler<S>(self: &Arc<Self>, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.handle(stream).await {
+                debug!("API connection ended: {}", e);
+            }
+        });
+    }
+
+    async fn handle<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<()> {
+        let mut stream = BufReader::new(stream);
+        let request = time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await;
+        let response = match request {
+            Ok(Ok(Some((head, body)))) => self.respond(&head, &body).await,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(e)) => Response::error(400, e),
+            Err(_) => Response::error(408, "request timed out"),
+        };
+        let body = format!("{:#}\n", response.body);
+        write_response(stream.get_mut(), response.status, reason(response.status), "application/json", &body).await
+    }
+
+    async fn respond(&self, head: &RequestHead, body: &[u8]) -> Response {
+        let authorized = head
+            .header("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), self.token.as_bytes()));
+        if !authorized {
+            return Response::error(401, "missing or wrong bearer token");
+        }
+
+        let path = head.target.split('?').next().unwrap_or_default();
+        debug!("API {} {}", head.method, path);
+        match (head.method.as_str(), path) {
+            ("GET", "/status") => Response::ok(self.status_json()),
+            ("GET", "/ip") => {
+                let exits: serde_json::Map<_, _> = self
+                    .status
+                    .instances()
+                    .into_iter()
+                    .map(|(name, status)| (name, json!(status.exit)))
+                    .collect();
+                Response::ok(Value::Object(exits))
+            }
+            ("GET", "/circuits") => {
+                let mut circuits = serde_json::Map::new();
+                for instance in self.pool.instances() {
+                    match self.circuits_json(instance).await {
+                        Ok(list) => circuits.insert(instance.name.clone(), list),
+                        Err(e) => return Response::error(502, format!("{}: {:#}", instance.name, e)),
+                    };
+                }
+                Response::ok(Value::Object(circuits))
+            }
+            ("GET", "/history") => Response::ok(json!(self.status.history())),
+            ("POST", "/rotate") => self.send(Command::Rotate, "rotation requested"),
+            ("PUT" | "POST", "/interval") => match json_field(body, "interval") {
+                Ok(interval) => match Command::policy(&format!("interval:{}", interval)) {
+                    Ok(command) => self.send(command, "interval change requested"),
+                    Err(e) => Response::error(400, e),
+                },
+                Err(e) => Response::error(400, e),
+            },
+            ("PUT" | "POST", "/policy") => match json_field(body, "policy") {
+                Ok(spec) => match Command::policy(&spec) {
+                    Ok(command) => self.send(command, "policy change requested"),
+                    Err(e) => Response::error(400, e),
+                },
+                Err(e) => Response::error(400, e),
+            },
+            (_, "/status" | "/ip" | "/circuits" | "/history" | "/rotate" | "/interval" | "/policy") => {
+                Response::error(405, format!("{} not allowed on {}", head.method, path))
+            }
+            _ => Response::error(404, format!("no such endpoint {}", path)),
+        }
+    }
+
+    fn send(&self, command: Command, message: &str) -> Response {
+        match self.commands.send(command) {
+            Ok(_) => Response {
+                status: 202,
+                body: json!({ "status": message }),
+            },
+            Err(_) => Response::error(503, "no rotation loop is running"),
+        }
+    }
+
+    fn status_json(&self) -> Value {
+        let statuses = self.status.instances();
+        let instances: Vec<Value> = self
+            .pool
+            .instances()
+            .iter()
+            .map(|instance| {
+                let status = statuses.get(&instance.name).cloned().unwrap_or_default();
+                json!({
+                    "name": instance.name,
+                    "socks": instance.socks.to_string(),
+                    "active_connections": instance.active_connections(),
+                    "traffic": instance.traffic.snapshot(),
+                    "exit": status.exit,
+                    "policy": status.policy,
+                    "last_rotation": status.last_rotation,
+                    "rotations": status.rotations,
+                })
+            })
+            .collect();
+        json!({ "instances": instances })
+    }
+
+    async fn circuits_json(&self, instance: &TorInstance) -> Result<Value> {
+        let mut circuits = Vec::new();
+        for circuit in instance.control.get_circuit_info().await? {
+            if !circuit.is_usable() {
+                continue;
+            }
+            let mut path = Vec::new();
+            for hop in &circuit.path {
+                let relay = self.relay_cache.lookup(&instance.control, &hop.fingerprint).await.ok();
+                path.push(json!({
+                    "fingerprint": hop.fingerprint,
+                    "nickname": relay.as_ref().map(|r| r.nickname.clone()).or_else(|| hop.nickname.clone()),
+                    "address": relay.as_ref().map(|r| r.address.to_string()),
+                    "country": relay.as_ref().and_then(|r| r.country.clone()),
+                    "flags": relay.as_ref().map(|r| r.flags.clone()).unwrap_or_default(),
+                }));
+            }
+            circuits.push(json!({
+                "id": circuit.id,
+                "status": circuit.status.to_string(),
+                "purpose": circuit.purpose,
+                "created": circuit.time_created,
+                "path": path,
+            }));
+        }
+        Ok(Value::Array(circuits))
+    }
+}
+
+/// Binds a Unix socket at `path` that only we can connect to. It is bound
+/// in a directory nobody else can enter and moved to `path` once it is
+/// owner-only, so there is no moment anyone else could connect.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    // Created owner-only
+    let staging = tempfile::Builder::new()
+        .prefix(".rusttator-")
+        .tempdir_in(dir)
+        .with_context(|| format!("Failed to create a directory in {}", dir.display()))?;
+    let staged = staging.path().join("api.sock");
+    let listener = tokio::net::UnixListener::bind(&staged)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict {}", path.display()))?;
+    // Replaces a socket left over from an earlier run
+    std::fs::rename(&staged, path).with_context(|| format!("Failed to listen on {}", path.display()))?;
+    Ok(listener)
+}
+
+async fn read_request<R: AsyncRead + Unpin>(stream: &mut BufReader<R>) -> Result<Option<(RequestHead, Vec<u8>)>> {
+    let Some(head) = read_head(stream).await? else {
+        return Ok(None);
+    };
+    let head = RequestHead::parse(&head)?;
+    let length = match head.header("Content-Length") {
+        Some(length) => length.parse().map_err(|_| anyhow!("invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(anyhow!("request body too large"));
+    }
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).await?;
+    Ok(Some((head, body)))
+}
+
+/// A string field of a JSON object body.
+fn json_field(body: &[u8], field: &str) -> Result<String> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| anyhow!("invalid JSON body: {}", e))?;
+    value
+        .get(field)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("expected a JSON object with a string {:?} field", field))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+/// A random token for when none is configured.
+pub fn generate_token() -> String {
+    let bytes: [u8; 16] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Where a generated token is written: `$XDG_RUNTIME_DIR/rusttator/api-token`,
+/// falling back to `~/.local/state/rusttator/api-token`.
+pub fn token_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("state"),
+    };
+    Some(base.join("rusttator").join("api-token"))
+}
+
+/// Writes `token` to `path`, readable by us alone, instead of logging it.
+pub fn write_token(path: &Path, token: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    // Replaced rather than truncated, so a file we don't own is never reused
+    let _ = std::fs::remove_file(path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    writeln!(file, "{}", token).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::pool::Balance;
+    use crate::testutil;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn token_file_is_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rusttator").join("api-token");
+        write_token(&path, "first").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_token(&path, "second").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+    }
+
+    /// An API server on a Unix socket in `dir`, accepting the token `token`.
+    async fn serve(dir: &Path) -> (PathBuf, Shutdown, JoinHandle<()>) {
+        let path = dir.join("api.sock");
+        let pool = Arc::new(TorPool::new(
+            vec![testutil::instance("tor0", "127.0.0.1:9".parse().unwrap()).await],
+            Balance::RoundRobin,
+        ));
+        let (commands, _) = broadcast::channel(1);
+        let server = ApiServer::new("token".to_string(), Arc::default(), pool, RelayCache::default(), commands);
+        let shutdown = Shutdown::default();
+        let task = Arc::new(server).listen(&ApiEndpoint::Unix(path.clone()), shutdown.clone()).await.unwrap();
+        (path, shutdown, task)
+    }
+
+    /// Sends `request` and returns the whole response.
+    async fn exchange(path: &Path, request: &str) -> String {
+        let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stream, request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn unix_socket_is_private() {
+        let dir = tempfile::tempdir().unwrap();
+        // Left over from an earlier run
+        std::fs::write(dir.path().join("api.sock"), "").unwrap();
+        let (path, _shutdown, _) = serve(dir.path()).await;
+        assert_eq!(mode(&path), 0o600);
+        // Nothing of where it was bound is left behind
+        let entries: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(entries, ["api.sock"]);
+    }
+
+    #[tokio::test]
+    async fn requires_the_bearer_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _shutdown, _) = serve(dir.path()).await;
+        let response = exchange(&path, "GET /status HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+        let response = exchange(&path, "GET /status HTTP/1.1\r\nAuthorization: Bearer tokem\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+        let response = exchange(&path, "GET /status HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\"name\": \"tor0\""), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn removes_the_socket_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, shutdown, task) = serve(dir.path()).await;
+        assert!(path.exists());
+        shutdown.request();
+        time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,168 @@
+//! Following Tor's bootstrap through `status/bootstrap-phase` and
+//! `STATUS_CLIENT BOOTSTRAP` events, and explaining the problems Tor
+//! reports on the way.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::control::TorControl;
+use crate::events::{StatusEvent, TorEvent};
+
+/// How often the phase is re-read in case an event went missing.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+/// One `BOOTSTRAP` status, e.g.
+/// `NOTICE BOOTSTRAP PROGRESS=45 TAG=requesting_descriptors SUMMARY="..."`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapPhase {
+    pub progress: u8,
+    pub tag: String,
+    pub summary: String,
+    /// Set on `WARN BOOTSTRAP`: what is going wrong.
+    pub warning: Option<String>,
+    /// `REASON`, and `HOSTADDR` of the relay involved, if any.
+    pub reason: Option<String>,
+    /// Tor's advice: "warn" for problems worth telling the user about,
+    /// "ignore" for ones it expects to recover from.
+    pub recommendation: Option<String>,
+}
+
+impl BootstrapPhase {
+    pub fn from_status(status: &StatusEvent) -> Option<Self> {
+        if status.action != "BOOTSTRAP" {
+            return None;
+        }
+        let arg = |key: &str| status.args.get(key).cloned();
+        let reason = match (arg("REASON"), arg("HOSTADDR")) {
+            (Some(reason), Some(host)) => Some(format!("{} at {}", reason, host)),
+            (reason, _) => reason,
+        };
+        Some(Self {
+            progress: arg("PROGRESS")?.parse().ok()?,
+            tag: arg("TAG").unwrap_or_default(),
+            summary: arg("SUMMARY").unwrap_or_default(),
+            warning: arg("WARNING"),
+            reason,
+            recommendation: arg("RECOMMENDATION"),
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress >= 100
+    }
+}
+
+impl fmt::Display for BootstrapPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let filled = usize::from(self.progress.min(100)) * PROGRESS_BAR_WIDTH / 100;
+        write!(
+            f,
+            "[{}{}] {:>3}% {}: {}",
+            "█".repeat(filled),
+            "░".repeat(PROGRESS_BAR_WIDTH - filled),
+            self.progress,
+            self.tag,
+            self.summary
+        )
+    }
+}
+
+/// Waits until Tor reports 100% bootstrapped, logging progress as it goes.
+pub async fn wait_for_bootstrap(control: &TorControl, timeout: Duration) -> Result<()> {
+    let mut events = control.subscribe();
+    let deadline = time::Instant::now() + timeout;
+    let mut poll = time::interval(POLL_INTERVAL);
+    let mut last: Option<BootstrapPhase> = None;
+
+    loop {
+        let phase = tokio::select! {
+            _ = time::sleep_until(deadline) => {
+                return Err(anyhow!(
+                    "Tor did not finish bootstrapping within {}s (stuck at {})",
+                    timeout.as_secs(),
+                    last.map(|p| format!("{}% {}", p.progress, p.tag)).unwrap_or_else(|| "0%".to_string())
+                ));
+            }
+            _ = poll.tick() => match control.bootstrap_phase().await? {
+                Some(phase) => phase,
+                None => continue,
+            },
+            event = events.recv() => match event {
+                Ok(TorEvent::StatusClient(status)) => match BootstrapPhase::from_status(&status) {
+                    Some(phase) => phase,
+                    None => continue,
+                },
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(anyhow!("Tor control connection closed while bootstrapping"));
+                }
+            },
+        };
+
+        report(&phase, last.as_ref());
+        if phase.is_done() {
+            return Ok(());
+        }
+        last = Some(phase);
+    }
+}
+
+fn report(phase: &BootstrapPhase, last: Option<&BootstrapPhase>) {
+    if let Some(warning) = &phase.warning {
+        let message = format!(
+            "Bootstrap problem at {}% ({}): {}{}",
+            phase.progress,
+            phase.tag,
+            warning,
+            phase.reason.as_ref().map(|r| format!(" [{}]", r)).unwrap_or_default()
+        );
+        if phase.recommendation.as_deref() == Some("warn") {
+            warn!("{}", message);
+        } else {
+            debug!("{}", message);
+        }
+        return;
+    }
+    let changed = last.is_none_or(|last| last.progress != phase.progress || last.tag != phase.tag);
+    if changed {
+        if phase.is_done() {
+            info!("✓ Tor bootstrapped {}", phase);
+        } else {
+            info!("Bootstrapping {}", phase);
+        }
+    }
+}
+
+/// Human-readable explanation for the `STATUS_GENERAL`/`STATUS_CLIENT`
+/// problems that usually keep Tor from working, `None` for anything else.
+pub fn describe_problem(status: &StatusEvent) -> Option<String> {
+    let arg = |key: &str| status.args.get(key).map(String::as_str).unwrap_or("?");
+    Some(match status.action.as_str() {
+        "CLOCK_SKEW" => format!(
+            "Clock skew of {}s detected ({}); Tor cannot work until the system clock is correct",
+            arg("SKEW"),
+            arg("SOURCE")
+        ),
+        "CLOCK_JUMPED" => format!("System clock jumped by {}s", arg("TIME")),
+        "DIR_ALL_UNREACHABLE" => "All directory servers are unreachable; check the network or firewall".to_string(),
+        "DANGEROUS_VERSION" => format!(
+            "This Tor version ({}) is {}; please upgrade",
+            arg("CURRENT"),
+            arg("REASON")
+        ),
+        "NOT_ENOUGH_DIR_INFO" => "Not enough directory information to build circuits".to_string(),
+        "CIRCUIT_NOT_ESTABLISHED" => format!("Tor cannot establish circuits: {}", arg("REASON")),
+        "DANGEROUS_SOCKS" => format!(
+            "An application connected with {} to {}, leaking DNS; use socks5h or socks4a",
+            arg("PROTOCOL"),
+            arg("ADDRESS")
+        ),
+        _ => return None,
+    })
+}
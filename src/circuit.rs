@@ -0,0 +1,290 @@
+//! Circuit model shared by `GETINFO circuit-status` and `650 CIRC` events
+//! (control-spec §4.1.1).
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::reply::parse_args;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitStatus {
+    Launched,
+    Built,
+    GuardWait,
+    Extended,
+    Failed,
+    Closed,
+    Other(String),
+}
+
+impl CircuitStatus {
+    fn parse(s: &str) -> Self {
+        match s {
+            "LAUNCHED" => Self::Launched,
+            "BUILT" => Self::Built,
+            "GUARD_WAIT" => Self::GuardWait,
+            "EXTENDED" => Self::Extended,
+            "FAILED" => Self::Failed,
+            "CLOSED" => Self::Closed,
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CircuitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Launched => "LAUNCHED",
+            Self::Built => "BUILT",
+            Self::GuardWait => "GUARD_WAIT",
+            Self::Extended => "EXTENDED",
+            Self::Failed => "FAILED",
+            Self::Closed => "CLOSED",
+            Self::Other(s) => s,
+        })
+    }
+}
+
+/// One relay in a circuit path (`$FINGERPRINT~nickname`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub fingerprint: String,
+    pub nickname: Option<String>,
+}
+
+impl Hop {
+    /// Parses a LongName. Entries without a fingerprint (only emitted by
+    /// Tor versions predating VERBOSE_NAMES) are rejected.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.strip_prefix('$')?;
+        let (fingerprint, nickname) = match s.find(['~', '=']) {
+            Some(idx) => (&s[..idx], Some(s[idx + 1..].to_string())),
+            None => (s, None),
+        };
+        if fingerprint.len() != 40 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Self {
+            fingerprint: fingerprint.to_uppercase(),
+            nickname: nickname.filter(|n| !n.is_empty()),
+        })
+    }
+
+    /// Nickname if Tor told us one, otherwise an abbreviated fingerprint.
+    pub fn short_name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.fingerprint[..8])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Circuit {
+    pub id: String,
+    pub status: CircuitStatus,
+    pub path: Vec<Hop>,
+    pub build_flags: Vec<String>,
+    pub purpose: Option<String>,
+    pub hs_state: Option<String>,
+    pub rend_query: Option<String>,
+    pub time_created: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+    pub remote_reason: Option<String>,
+    pub socks_username: Option<String>,
+    pub socks_password: Option<String>,
+    /// Keywords this version doesn't know about, kept verbatim.
+    pub extra: HashMap<String, String>,
+}
+
+impl Circuit {
+    /// Parses a `circuit-status` entry or the body of a `650 CIRC` event.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(3, ' ');
+        let id = parts.next().filter(|s| !s.is_empty())?.to_string();
+        let status = CircuitStatus::parse(parts.next()?);
+        let mut rest = parts.next().unwrap_or("");
+
+        // The path is optional (e.g. for LAUNCHED circuits). It has to be
+        // split off by hand since legacy `$ID=nick` hops contain '='. One
+        // bad hop rejects the entry; a path with a hop missing is wrong.
+        let mut path = Vec::new();
+        if rest.starts_with('$') {
+            let (path_str, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            path = path_str.split(',').map(Hop::parse).collect::<Option<_>>()?;
+            rest = tail;
+        }
+
+        let (_, mut args) = parse_args(rest);
+        let build_flags = args
+            .remove("BUILD_FLAGS")
+            .map(|f| f.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        let time_created = args.remove("TIME_CREATED").and_then(|t| {
+            NaiveDateTime::parse_from_str(&t, "%Y-%m-%dT%H:%M:%S%.f")
+                .ok()
+                .map(|t| t.and_utc())
+        });
+
+        Some(Circuit {
+            id,
+            status,
+            path,
+            build_flags,
+            purpose: args.remove("PURPOSE"),
+            hs_state: args.remove("HS_STATE"),
+            rend_query: args.remove("REND_QUERY"),
+            time_created,
+            reason: args.remove("REASON"),
+            remote_reason: args.remove("REMOTE_REASON"),
+            socks_username: args.remove("SOCKS_USERNAME"),
+            socks_password: args.remove("SOCKS_PASSWORD"),
+            extra: args,
+        })
+    }
+
+    /// Built, general-purpose circuits are the ones our traffic exits over.
+    pub fn is_usable(&self) -> bool {
+        self.status == CircuitStatus::Built
+            && self.purpose.as_deref() == Some("GENERAL")
+            && !self.build_flags.iter().any(|f| f == "IS_INTERNAL" || f == "ONEHOP_TUNNEL")
+    }
+
+    /// Human readable failure reason, including the remote side's if any.
+    pub fn failure_reason(&self) -> String {
+        match (&self.reason, &self.remote_reason) {
+            (Some(reason), Some(remote)) => format!("{} (remote: {})", reason, remote),
+            (Some(reason), None) => reason.clone(),
+            (None, _) => "unknown reason".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Circuit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {}", self.id, self.status)?;
+        if !self.path.is_empty() {
+            let hops: Vec<_> = self.path.iter().map(Hop::short_name).collect();
+            write!(f, " {}", hops.join(","))?;
+        }
+        if let Some(purpose) = &self.purpose {
+            write!(f, " purpose={}", purpose)?;
+        }
+        if !self.build_flags.is_empty() {
+            write!(f, " flags={}", self.build_flags.join(","))?;
+        }
+        if let Some(state) = &self.hs_state {
+            write!(f, " hs_state={}", state)?;
+        }
+        if let Some(query) = &self.rend_query {
+            write!(f, " rend_query={}", query)?;
+        }
+        if let Some(created) = &self.time_created {
+            write!(f, " created={}", created.format("%H:%M:%S"))?;
+        }
+        if self.reason.is_some() {
+            write!(f, " reason={}", self.failure_reason())?;
+        }
+        if self.socks_username.is_some() || self.socks_password.is_some() {
+            // Isolation credentials can be identifying; don't print them.
+            write!(f, " socks_auth=<set>")?;
+        }
+        for (key, value) in &self.extra {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FP1: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+    const FP2: &str = "89abcdef0123456789abcdef0123456789ABCDEF";
+
+    #[test]
+    fn parses_hops() {
+        let cases: &[(String, Option<&str>)] = &[
+            (format!("${}~relay1", FP1), Some("relay1")),
+            (format!("${}=relay1", FP1), Some("relay1")),
+            (format!("${}", FP1), None),
+            (format!("${}~", FP1), None),
+        ];
+        for (input, nickname) in cases {
+            let hop = Hop::parse(input).unwrap_or_else(|| panic!("{} should parse", input));
+            assert_eq!(hop.fingerprint, FP1, "{}", input);
+            assert_eq!(hop.nickname.as_deref(), *nickname, "{}", input);
+        }
+        // Fingerprints are upper-cased so lookups match
+        assert_eq!(Hop::parse(&format!("${}", FP2)).unwrap().fingerprint, FP2.to_uppercase());
+        assert_eq!(Hop::parse(&format!("${}", FP1)).unwrap().short_name(), "01234567");
+    }
+
+    #[test]
+    fn rejects_malformed_hops() {
+        let too_long = format!("${}0", FP1);
+        let non_hex = format!("${}G~relay", &FP1[..39]);
+        let multibyte = format!("${}é~relay", &FP1[..38]);
+        for input in ["", "$", "relay1", FP1, "$~relay", "$0123~relay", &too_long, &non_hex, &multibyte] {
+            assert_eq!(Hop::parse(input), None, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parses_circuit_status_entries() {
+        let line = format!(
+            "7 BUILT ${}~entry,${}=exit BUILD_FLAGS=NEED_CAPACITY,NEED_UPTIME PURPOSE=GENERAL \
+             TIME_CREATED=2024-03-01T12:34:56.789012 SOCKS_USERNAME=\"user\" SOCKS_PASSWORD=\"pass\" \
+             NEW_KEY=value",
+            FP1, FP2
+        );
+        let circuit = Circuit::parse(&line).unwrap();
+        assert_eq!(circuit.id, "7");
+        assert_eq!(circuit.status, CircuitStatus::Built);
+        let names: Vec<&str> = circuit.path.iter().map(Hop::short_name).collect();
+        assert_eq!(names, ["entry", "exit"]);
+        assert_eq!(circuit.build_flags, ["NEED_CAPACITY", "NEED_UPTIME"]);
+        assert_eq!(circuit.purpose.as_deref(), Some("GENERAL"));
+        assert_eq!(circuit.time_created.unwrap().to_rfc3339(), "2024-03-01T12:34:56.789012+00:00");
+        assert_eq!(circuit.socks_username.as_deref(), Some("user"));
+        // Keywords from newer Tors are kept, not fatal
+        assert_eq!(circuit.extra.get("NEW_KEY").map(String::as_str), Some("value"));
+        assert!(circuit.is_usable());
+
+        let shown = circuit.to_string();
+        assert!(shown.contains("entry,exit") && shown.contains("socks_auth=<set>"), "{}", shown);
+        assert!(!shown.contains("pass"), "{}", shown);
+    }
+
+    #[test]
+    fn parses_entries_without_a_path() {
+        let circuit = Circuit::parse("8 LAUNCHED BUILD_FLAGS=IS_INTERNAL PURPOSE=HS_CLIENT_REND").unwrap();
+        assert_eq!(circuit.status, CircuitStatus::Launched);
+        assert!(circuit.path.is_empty());
+        assert!(!circuit.is_usable());
+
+        let circuit = Circuit::parse("9 FAILED REASON=TIMEOUT REMOTE_REASON=DESTROYED").unwrap();
+        assert_eq!(circuit.failure_reason(), "TIMEOUT (remote: DESTROYED)");
+        assert_eq!(
+            Circuit::parse("10 SOMETHING_NEW").unwrap().status,
+            CircuitStatus::Other("SOMETHING_NEW".to_string())
+        );
+        // An unparseable timestamp is dropped, not fatal
+        assert!(Circuit::parse("11 BUILT TIME_CREATED=yesterday").unwrap().time_created.is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        let cases = [
+            String::new(),
+            "7".to_string(),
+            " BUILT".to_string(),
+            "7 BUILT $".to_string(),
+            format!("7 BUILT ${},$nothex PURPOSE=GENERAL", FP1),
+            format!("7 BUILT ${},,${} PURPOSE=GENERAL", FP1, FP2),
+            format!("7 BUILT ${}, PURPOSE=GENERAL", FP1),
+        ];
+        for line in &cases {
+            assert!(Circuit::parse(line).is_none(), "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! Settings from a TOML config file, layered under the command line and
+//! environment: CLI > env vars > file > defaults.
+//!
+//! Keys are the long flag names (`control-port = 9063`, `exit_countries =
+//! "de,nl"`); lists and repeatable flags take arrays, switches take booleans.
+//! On SIGHUP the file is read again and the settings in [`LIVE_SETTINGS`]
+//! take effect without a restart.
+
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, CommandFactory, FromArgMatches};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+use tracing_subscriber::{reload, Registry};
+
+use crate::console::Command;
+use crate::geoip::Geolocator;
+use crate::ip_provider::ProviderChain;
+use crate::Args;
+
+/// Flags that make no sense in the file itself.
+const NOT_IN_FILE: &[&str] = &["config", "help", "version"];
+
+/// Settings a reload applies to the running process.
+const LIVE_SETTINGS: &[&str] = &[
+    "interval",
+    "rotate",
+    "exit-countries",
+    "exclude-countries",
+    "ip-provider",
+    "ip-quorum",
+    "tor-check-url",
+    "geo-url",
+    "geo-source",
+    "geoip-file",
+    "geoip6-file",
+    "geoip-mmdb",
+    "check-timeout",
+    "request-timeout",
+    "circuit-timeout",
+    "client-retries",
+    "retry-delay",
+    "log-level",
+];
+
+/// Changes the log level of the running subscriber.
+pub type LogLevel = reload::Handle<LevelFilter, Registry>;
+
+/// Service URLs, timeouts and retries used when talking through Tor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tuning {
+    /// IP echo services asked for the exit IP.
+    pub ip_providers: Arc<ProviderChain>,
+    /// Returns `{"IsTor": bool}` for the address it sees; only asked when
+    /// the consensus can't confirm an exit IP.
+    pub tor_check_url: Option<String>,
+    /// Offline databases and the optional remote service locating exits.
+    pub geo: Arc<Geolocator>,
+    pub check_timeout: Duration,
+    pub request_timeout: Duration,
+    pub circuit_timeout: Duration,
+    pub client_retries: u32,
+    pub retry_delay: Duration,
+}
+
+/// The parsed arguments and where each value came from.
+#[derive(Clone)]
+pub struct Config {
+    pub args: Args,
+    /// The config file that was read, if any.
+    pub file: Option<PathBuf>,
+    matches: ArgMatches,
+    from_file: HashSet<String>,
+}
+
+/// Parses the command line, filling in anything it and the environment
+/// leave at its default from the config file. Exits on `--help` and
+/// command line errors like `Args::parse` does.
+pub fn load() -> Result<Config> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    Args::command().get_matches_from(&argv);
+    read(&argv)
+}
+
+/// Like [`load`], re-reading the config file, but never exits.
+pub fn reload() -> Result<Config> {
+    read(&std::env::args_os().collect::<Vec<_>>())
+}
+
+fn read(argv: &[OsString]) -> Result<Config> {
+    let command = Args::command();
+    let matches = command.clone().try_get_matches_from(argv)?;
+
+    let file = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None => default_path().filter(|path| path.exists()),
+    };
+
+    let mut injected = Vec::new();
+    let mut from_file = HashSet::new();
+    if let Some(path) = &file {
+        let table = read_file(path)?;
+        for (key, value) in &table {
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(key.replace('_', "-").as_str()))
+                .filter(|arg| !NOT_IN_FILE.contains(&arg.get_id().as_str()))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Unknown setting {:?} in {}; settings are named like the long command line flags (see --help)",
+                        key,
+                        path.display()
+                    )
+                })?;
+            let flags = to_flags(arg, value).with_context(|| format!("Invalid {:?} in {}", key, path.display()))?;
+
+            // Check the value on its own so errors point at the file
+            let alone = std::iter::once(argv[0].clone()).chain(flags.iter().map(OsString::from));
+            if let Err(e) = command.clone().try_get_matches_from(alone) {
+                let message = e.to_string();
+                let message: Vec<&str> = message.lines().take_while(|line| !line.is_empty()).map(str::trim).collect();
+                return Err(anyhow!(
+                    "Invalid {:?} in {}: {}",
+                    key,
+                    path.display(),
+                    message.join(" ").trim_start_matches("error: ")
+                ));
+            }
+
+            let id = arg.get_id().to_string();
+            match matches.value_source(&id) {
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable) => {}
+                _ => {
+                    injected.extend(flags.into_iter().map(OsString::from));
+                    from_file.insert(id);
+                }
+            }
+        }
+    }
+
+    // File values go before the user's own arguments so they stay in front
+    // of any subcommand
+    let argv: Vec<OsString> = argv[..1].iter().cloned().chain(injected).chain(argv[1..].iter().cloned()).collect();
+    let matches = command.try_get_matches_from(argv)?;
+    let args = Args::from_arg_matches(&matches)?;
+    Ok(Config {
+        args,
+        file,
+        matches,
+        from_file,
+    })
+}
+
+impl Config {
+    /// Long names of the settings whose values differ in `other`.
+    pub fn changed_settings(&self, other: &Config) -> Vec<String> {
+        Args::command()
+            .get_arguments()
+            .filter(|arg| !NOT_IN_FILE.contains(&arg.get_id().as_str()))
+            .filter(|arg| {
+                let id = arg.get_id().as_str();
+                let ours = self.matches.get_raw(id).map(|values| values.collect::<Vec<_>>());
+                let theirs = other.matches.get_raw(id).map(|values| values.collect::<Vec<_>>());
+                ours != theirs
+            })
+            .filter_map(|arg| arg.get_long().map(str::to_string))
+            .collect()
+    }
+
+    /// Prints the effective configuration as TOML that can be used as a
+    /// config file, noting where each value came from. Secrets are hidden.
+    pub fn print(&self) {
+        match &self.file {
+            Some(path) => println!("# Effective configuration (config file: {})", path.display()),
+            None => println!("# Effective configuration (no config file)"),
+        }
+        for arg in Args::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            let Some(long) = arg.get_long() else {
+                continue;
+            };
+            if NOT_IN_FILE.contains(&id) {
+                continue;
+            }
+            let source = match self.matches.value_source(id) {
+                Some(ValueSource::DefaultValue) => "default".to_string(),
+                Some(ValueSource::EnvVariable) => format!(
+                    "env {}",
+                    arg.get_env().map(|env| env.to_string_lossy()).unwrap_or_default()
+                ),
+                Some(_) if self.from_file.contains(id) => "config file".to_string(),
+                Some(_) => "command line".to_string(),
+                None => {
+                    println!("# {} = (not set)", long);
+                    continue;
+                }
+            };
+            if arg.is_hide_env_values_set() {
+                println!("# {} = (set, hidden)  # {}", long, source);
+                continue;
+            }
+            let values: Vec<String> = self
+                .matches
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|raw| toml_value(&raw.to_string_lossy()))
+                .collect();
+            let value = if takes_many(arg) {
+                format!("[{}]", values.join(", "))
+            } else {
+                values.join(" ")
+            };
+            println!("{} = {}  # {}", long, value, source);
+        }
+    }
+}
+
+/// Re-reads the configuration on every SIGHUP, applies the live settings
+/// that changed and reports the ones that need a restart.
+#[cfg(unix)]
+pub async fn reload_on_hangup(
+    started: Config,
+    mut tuning: Arc<Tuning>,
+    commands: broadcast::Sender<Command>,
+    log_level: LogLevel,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Cannot listen for SIGHUP, configuration reloads are disabled: {}", e);
+            return;
+        }
+    };
+    let mut applied = started.clone();
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        // Reading the file and the GeoIP databases blocks
+        let reloaded = tokio::task::spawn_blocking(|| {
+            let config = reload()?;
+            config.args.validate()?;
+            let tuning = config.args.tuning()?;
+            Ok((config, tuning))
+        })
+        .await
+        .context("Configuration reload panicked")
+        .and_then(|reloaded| reloaded);
+        let (config, new_tuning) = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                warn!("Keeping the running configuration: {:#}", e);
+                continue;
+            }
+        };
+
+        let live: Vec<String> = applied
+            .changed_settings(&config)
+            .into_iter()
+            .filter(|name| LIVE_SETTINGS.contains(&name.as_str()))
+            .collect();
+        // Compared with what we started with, so they are reported until restarted
+        let restart: Vec<String> = started
+            .changed_settings(&config)
+            .into_iter()
+            .filter(|name| !LIVE_SETTINGS.contains(&name.as_str()))
+            .collect();
+
+        // The running tuning, not one rebuilt from the old settings, so a
+        // database replaced in place at the same path is picked up too
+        let retuned = *tuning != new_tuning;
+        if retuned {
+            tuning = Arc::new(new_tuning);
+            // Sending fails only when no rotation loop is left, nothing to update then
+            let _ = commands.send(Command::Tuning(tuning.clone()));
+        }
+        apply(&applied.args, &config.args, &commands, &log_level);
+        if !live.is_empty() {
+            info!("Applied {}", live.join(", "));
+        } else if retuned {
+            info!("Applied updated GeoIP databases");
+        } else {
+            info!("No live settings changed");
+        }
+        if !restart.is_empty() {
+            warn!("Restart to apply {}", restart.join(", "));
+        }
+        applied = config;
+    }
+}
+
+/// Hands what changed between `old` and `new` to the rotation loops and the
+/// log subscriber.
+#[cfg(unix)]
+fn apply(old: &Args, new: &Args, commands: &broadcast::Sender<Command>, log_level: &LogLevel) {
+    // Sending fails only when no rotation loop is left, nothing to update then
+    if old.policy_spec() != new.policy_spec() {
+        let _ = commands.send(Command::Policy(new.policy_spec()));
+    }
+    if let (Ok(old_exits), Ok(new_exits)) = (old.exit_selection(), new.exit_selection()) {
+        if old_exits != new_exits {
+            let _ = commands.send(Command::Exits(new_exits));
+        }
+    }
+    if old.log_level != new.log_level {
+        if let Err(e) = log_level.reload(new.log_level) {
+            warn!("Failed to change the log level: {}", e);
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/rusttator/config.toml`, falling back to `~/.config`.
+fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("rusttator").join("config.toml"))
+}
+
+fn read_file(path: &Path) -> Result<toml::Table> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    contents
+        .parse()
+        .map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))
+}
+
+/// The command line flags equivalent to `key = value`.
+fn to_flags(arg: &Arg, value: &toml::Value) -> Result<Vec<String>> {
+    let long = arg.get_long().unwrap_or_default();
+    if matches!(arg.get_action(), ArgAction::SetTrue) {
+        return match value {
+            toml::Value::Boolean(true) => Ok(vec![format!("--{}", long)]),
+            toml::Value::Boolean(false) => Ok(Vec::new()),
+            _ => Err(anyhow!("expected true or false")),
+        };
+    }
+    let values = match value {
+        toml::Value::Array(items) => items.iter().map(scalar).collect::<Result<Vec<_>>>()?,
+        other => vec![scalar(other)?],
+    };
+    if values.len() != 1 && !takes_many(arg) {
+        return Err(anyhow!("expected a single value, not a list"));
+    }
+    Ok(values.into_iter().map(|value| format!("--{}={}", long, value)).collect())
+}
+
+fn scalar(value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Datetime(d) => Ok(d.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => Err(anyhow!("nested lists and tables are not supported")),
+    }
+}
+
+/// Repeatable flags and comma-separated lists.
+fn takes_many(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append) || arg.get_value_delimiter().is_some()
+}
+
+/// A raw flag value written back as TOML: numbers and booleans bare,
+/// everything else as a string.
+fn toml_value(raw: &str) -> String {
+    if raw.parse::<i64>().is_ok() || raw == "true" || raw == "false" {
+        raw.to_string()
+    } else {
+        toml::Value::String(raw.to_string()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn ip_providers_keep_their_commas() {
+        let command = Args::command();
+        let arg = command.get_arguments().find(|arg| arg.get_id() == "ip_provider").unwrap();
+        let value = toml::Value::Array(vec![
+            "https://example.com/ip?fields=query,status json=query".into(),
+            "https://icanhazip.com".into(),
+        ]);
+        let mut argv = vec!["rusttator".to_string()];
+        argv.extend(to_flags(arg, &value).unwrap());
+        let args = Args::try_parse_from(argv).unwrap();
+        assert_eq!(
+            args.ip_provider,
+            ["https://example.com/ip?fields=query,status json=query", "https://icanhazip.com"]
+        );
+    }
+
+    #[test]
+    fn block_policy_needs_a_probe() {
+        let args = Args::try_parse_from(["rusttator", "--rotate", "interval:5m | block"]).unwrap();
+        assert!(args.validate().is_err());
+        let args =
+            Args::try_parse_from(["rusttator", "--rotate", "block", "--block-probe", "https://example.com/"]).unwrap();
+        args.validate().unwrap();
+    }
+
+    /// Reads the configuration for `flags` on top of a config file holding
+    /// `file`.
+    fn layered(file: &str, flags: &[&str]) -> Config {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, file).unwrap();
+        let argv: Vec<OsString> = ["rusttator".as_ref(), "--config".as_ref(), path.as_os_str()]
+            .into_iter()
+            .chain(flags.iter().map(|flag| flag.as_ref()))
+            .map(OsString::from)
+            .collect();
+        read(&argv).unwrap()
+    }
+
+    #[test]
+    fn command_line_beats_file_beats_defaults() {
+        let file = "max-rotation-attempts = 5\nip_provider = [\"https://file.example/ip\"]\nstagger = true\n";
+        let config = layered(file, &[]);
+        assert_eq!(config.args.max_rotation_attempts, 5);
+        assert_eq!(config.args.ip_provider, ["https://file.example/ip"]);
+        assert!(config.args.stagger);
+        assert_eq!(config.args.dns_leak_report_port, 5380);
+        let mut from_file: Vec<_> = config.from_file.iter().map(String::as_str).collect();
+        from_file.sort();
+        assert_eq!(from_file, ["ip_provider", "max_rotation_attempts", "stagger"]);
+
+        let config = layered(file, &["--max-rotation-attempts", "7", "--ip-provider", "https://cli.example/ip"]);
+        assert_eq!(config.args.max_rotation_attempts, 7);
+        // A list from the command line replaces the file's rather than adding to it
+        assert_eq!(config.args.ip_provider, ["https://cli.example/ip"]);
+        assert!(config.args.stagger);
+        assert_eq!(config.from_file, HashSet::from(["stagger".to_string()]));
+
+        // Subcommands still follow the file's settings
+        let config = layered(file, &["dns-leak"]);
+        assert!(matches!(config.args.command, Some(crate::CliCommand::DnsLeak)));
+        assert_eq!(config.args.max_rotation_attempts, 5);
+    }
+
+    #[test]
+    fn environment_sits_between_command_line_and_file() {
+        // The only test touching these variables
+        std::env::set_var("RUSTTATOR_CONTROL_PORT", "9300");
+        std::env::set_var("RUSTTATOR_EXIT_COUNTRIES", "nl");
+        let file = "control-port = 9100\nexit-countries = \"de\"\ncontrol-host = \"192.0.2.1\"\n";
+        let from_env = layered(file, &[]);
+        let from_cli = layered(file, &["--control-port", "9200"]);
+        std::env::remove_var("RUSTTATOR_CONTROL_PORT");
+        std::env::remove_var("RUSTTATOR_EXIT_COUNTRIES");
+        let from_file = layered(file, &[]);
+
+        // A file value must not override one from the environment
+        assert_eq!(from_env.args.control_port, 9300);
+        assert_eq!(from_env.args.exit_countries.as_deref(), Some("nl"));
+        assert_eq!(from_env.args.control_host, "192.0.2.1");
+        assert_eq!(from_env.from_file, HashSet::from(["control_host".to_string()]));
+        assert_eq!(from_env.matches.value_source("control_port"), Some(ValueSource::EnvVariable));
+
+        assert_eq!(from_cli.args.control_port, 9200);
+        assert_eq!(from_cli.matches.value_source("control_port"), Some(ValueSource::CommandLine));
+        assert_eq!(from_cli.args.exit_countries.as_deref(), Some("nl"));
+
+        assert_eq!(from_file.args.control_port, 9100);
+        assert_eq!(from_file.args.exit_countries.as_deref(), Some("de"));
+        assert!(from_file.from_file.contains("control_port"));
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_file_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        for file in ["no-such-setting = 1", "control-port = \"lots\"", "config = \"other.toml\"", "stagger = 1"] {
+            std::fs::write(&path, file).unwrap();
+            let argv = [OsString::from("rusttator"), OsString::from("--config"), path.clone().into_os_string()];
+            assert!(read(&argv).is_err(), "{}", file);
+        }
+    }
+}
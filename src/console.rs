@@ -0,0 +1,89 @@
+//! Runtime commands for the rotation loop, typed on stdin (or sent by the
+//! management API and configuration reloads).
+
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::config::Tuning;
+use crate::exit::{parse_countries, ExitSelection};
+use crate::policy::parse_policy;
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Rotate right away instead of waiting for the policy.
+    Rotate,
+    /// Restrict exits to these countries (empty: any).
+    ExitCountries(Vec<String>),
+    /// Never exit from these countries (empty: no exclusions).
+    ExcludeCountries(Vec<String>),
+    /// Switch to this (already validated) rotation policy spec.
+    Policy(String),
+    /// Replace both exit countries and exclusions.
+    Exits(ExitSelection),
+    /// Use these service URLs, timeouts and retries from now on.
+    Tuning(Arc<Tuning>),
+}
+
+const HELP: &str = "Commands: rotate | exit <cc,cc,...|any> | exclude <cc,cc,...|none> | \
+                    interval <duration> | policy <spec> | help";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(None);
+        };
+        let arg = words.next().unwrap_or("");
+        let rest = line.trim_start()[command.len()..].trim();
+
+        let countries = |arg: &str, empty: &str| {
+            if arg == empty {
+                Ok(Vec::new())
+            } else if arg.is_empty() {
+                Err(format!("Missing country list (or \"{}\")", empty))
+            } else {
+                parse_countries(arg).map_err(|e| e.to_string())
+            }
+        };
+
+        match command {
+            "rotate" => Ok(Some(Command::Rotate)),
+            "exit" => Ok(Some(Command::ExitCountries(countries(arg, "any")?))),
+            "exclude" => Ok(Some(Command::ExcludeCountries(countries(arg, "none")?))),
+            "interval" => Command::policy(&format!("interval:{}", arg)).map(Some),
+            "policy" => Command::policy(rest).map(Some),
+            other => Err(format!("Unknown command {:?}. {}", other, HELP)),
+        }
+    }
+
+    /// Validates a rotation policy spec before handing it to the loops.
+    pub fn policy(spec: &str) -> Result<Self, String> {
+        parse_policy(spec).map_err(|e| format!("{:#}", e))?;
+        Ok(Command::Policy(spec.to_string()))
+    }
+}
+
+/// Reads commands from stdin until it is closed, handing them to every
+/// instance's rotation loop. Blocks, so it gets a thread of its own: a
+/// pending read on tokio's stdin would keep the runtime from shutting down.
+pub fn read_stdin(commands: broadcast::Sender<Command>) {
+    for line in std::io::stdin().lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim() == "help" {
+            info!("{}", HELP);
+            continue;
+        }
+        match Command::parse(&line) {
+            Ok(Some(command)) => {
+                if commands.send(command).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(message) => warn!("{}", message),
+        }
+    }
+}
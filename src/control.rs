@@ -0,0 +1,811 @@
+use anyhow::{anyhow, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+use tokio::time;
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{debug, info, warn};
+
+use crate::bootstrap::BootstrapPhase;
+use crate::circuit::{Circuit, CircuitStatus};
+use crate::events::{StatusEvent, TorEvent};
+use crate::relay::RelayInfo;
+use crate::reply::{parse_args, quote, ControlError, Reply, ReplyParser};
+
+/// How long a single command may wait for its reply.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest line we accept from Tor; a longer one ends the connection
+/// rather than being buffered without bound.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+struct Request {
+    command: String,
+    respond_to: oneshot::Sender<Result<Reply>>,
+}
+
+/// Handle to a Tor control connection.
+///
+/// The socket itself is owned by a background task; handles are cheap to
+/// clone and can be used concurrently from any number of tasks. Tor answers
+/// commands strictly in order, so replies are matched to requests FIFO.
+#[derive(Clone)]
+pub struct TorControl {
+    requests: mpsc::Sender<Request>,
+    /// Receiving end of `requests`, locked by whichever task currently
+    /// carries this handle's commands to Tor.
+    inbox: Arc<Mutex<mpsc::Receiver<Request>>>,
+    events: broadcast::Sender<TorEvent>,
+    /// True while no connection carries our commands: once the original
+    /// is gone, until [`TorControl::reattach`] hands over a fresh one.
+    closed: Arc<watch::Sender<bool>>,
+    /// Counts the connections that replaced the original one.
+    reattached: Arc<watch::Sender<u64>>,
+    timeout: Duration,
+}
+
+/// Where Tor's control port lives: a TCP `ControlPort` or a `ControlSocket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlEndpoint {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ControlEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlEndpoint::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            ControlEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl TorControl {
+    pub async fn connect(endpoint: &ControlEndpoint) -> Result<Self> {
+        match endpoint {
+            ControlEndpoint::Tcp { host, port } => {
+                let stream = TcpStream::connect((host.as_str(), *port))
+                    .await
+                    .with_context(|| format!("Failed to connect to Tor control port {}", endpoint))?;
+                Ok(Self::from_stream(stream))
+            }
+            #[cfg(unix)]
+            ControlEndpoint::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path)
+                    .await
+                    .with_context(|| format!("Failed to connect to Tor control socket {}", path.display()))?;
+                Ok(Self::from_stream(stream))
+            }
+            #[cfg(not(unix))]
+            ControlEndpoint::Unix(_) => Err(anyhow!("Unix control sockets are not supported on this platform")),
+        }
+    }
+
+    fn from_stream<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (requests, rx) = mpsc::channel(32);
+        let (events, _) = broadcast::channel(256);
+        let closed = Arc::new(watch::Sender::new(false));
+        let inbox = Arc::new(Mutex::new(rx));
+        let framed = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+        let connection_inbox = inbox.clone();
+        let connection_events = events.clone();
+        let connection_closed = closed.clone();
+        tokio::spawn(async move {
+            let mut inbox = connection_inbox.lock_owned().await;
+            run_connection(framed, &mut inbox, connection_events).await;
+            // Still holding the inbox, so a reattach can't have begun yet
+            connection_closed.send_replace(true);
+        });
+
+        Self {
+            requests,
+            inbox,
+            events,
+            closed,
+            reattached: Arc::new(watch::Sender::new(0)),
+            timeout: COMMAND_TIMEOUT,
+        }
+    }
+
+    /// Carries this handle's (and its clones') commands and events over
+    /// `fresh` from now on, after the original connection was lost because
+    /// Tor restarted. `fresh` must already be authenticated; event
+    /// subscribers keep receiving events from it.
+    pub fn reattach(&self, fresh: TorControl) {
+        self.reattached.send_modify(|count| *count += 1);
+        let inbox = self.inbox.clone();
+        let events = self.events.clone();
+        let closed = self.closed.clone();
+        tokio::spawn(async move {
+            // Only available once the previous connection has finished
+            let mut inbox = inbox.lock_owned().await;
+            closed.send_replace(false);
+            let mut fresh_events = fresh.subscribe();
+            loop {
+                tokio::select! {
+                    request = inbox.recv() => {
+                        let Some(request) = request else { break };
+                        if fresh.requests.send(request).await.is_err() {
+                            break;
+                        }
+                    }
+                    event = fresh_events.recv() => match event {
+                        Ok(event) => {
+                            let _ = events.send(event);
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = fresh.closed() => break,
+                }
+            }
+            closed.send_replace(true);
+        });
+    }
+
+    /// Changes whenever a restarted Tor is reattached, which starts over
+    /// from its torrc and has lost any configuration we set.
+    pub fn reattached(&self) -> watch::Receiver<u64> {
+        self.reattached.subscribe()
+    }
+
+    /// Resolves once the connection to Tor is gone.
+    async fn closed(&self) {
+        let mut closed = self.closed.subscribe();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Returns a receiver for the async events Tor sends us. Only events
+    /// enabled through [`TorControl::set_events`] are delivered.
+    pub fn subscribe(&self) -> broadcast::Receiver<TorEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn set_events(&self, events: &[&str]) -> Result<()> {
+        self.command(&format!("SETEVENTS {}", events.join(" "))).await?;
+        Ok(())
+    }
+
+    /// Ends the session; Tor closes the connection after replying. A Tor we
+    /// took ownership of exits with it.
+    pub async fn quit(&self) -> Result<()> {
+        self.command("QUIT").await?;
+        self.closed().await;
+        Ok(())
+    }
+
+    /// Sends a command and returns its reply, mapping error codes to
+    /// [`crate::reply::ControlError`].
+    pub async fn command(&self, cmd: &str) -> Result<Reply> {
+        let (respond_to, response) = oneshot::channel();
+        self.requests
+            .send(Request { command: cmd.to_string(), respond_to })
+            .await
+            .map_err(|_| anyhow!("Tor control connection closed"))?;
+
+        let reply = time::timeout(self.timeout, response)
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for reply to {:?}", command_name(cmd)))?
+            .map_err(|_| anyhow!("Tor control connection closed"))??;
+        Ok(reply.into_result()?)
+    }
+
+    async fn get_protocol_info(&self) -> Result<Reply> {
+        self.command("PROTOCOLINFO 1").await
+    }
+
+    pub async fn authenticate(&self, password: Option<String>) -> Result<()> {
+        // First get protocol info
+        let proto_info = self.get_protocol_info().await?;
+        info!("Protocol info response: {:?}", proto_info.lines);
+
+        // Parse authentication methods from PROTOCOLINFO response
+        let mut methods = Vec::new();
+        let mut cookie_file = None;
+
+        if let Some(line) = proto_info.lines.iter().find(|l| l.text.starts_with("AUTH ")) {
+            let (_, args) = parse_args(&line.text);
+            if let Some(methods_str) = args.get("METHODS") {
+                methods = methods_str.split(',').map(str::to_string).collect();
+            }
+            cookie_file = args.get("COOKIEFILE").cloned();
+        }
+
+        info!("Supported auth methods: {:?}", methods);
+        if let Some(file) = &cookie_file {
+            info!("Cookie file: {}", file);
+        }
+
+        // An explicitly supplied password takes precedence: Tor closes the
+        // connection after a failed attempt, so we only get one shot.
+        if methods.iter().any(|m| m == "HASHEDPASSWORD") {
+            match &password {
+                Some(password) => {
+                    info!("Attempting HASHEDPASSWORD authentication");
+                    self.command(&format!("AUTHENTICATE {}", quote(password)))
+                        .await
+                        .context("HASHEDPASSWORD authentication failed")?;
+                    info!("Successfully authenticated with HASHEDPASSWORD");
+                    return Ok(());
+                }
+                None if !methods.iter().any(|m| matches!(m.as_str(), "COOKIE" | "SAFECOOKIE" | "NULL")) => {
+                    return Err(anyhow!(
+                        "Tor requires a control password (HASHEDPASSWORD) but none was given; \
+                         use --password, --password-file or RUSTTATOR_TOR_CONTROL_PASSWORD"
+                    ));
+                }
+                None => info!("No password supplied, skipping HASHEDPASSWORD"),
+            }
+        } else if password.is_some() {
+            warn!("A control password was supplied but Tor does not offer HASHEDPASSWORD");
+        }
+
+        // Try COOKIE authentication first
+        if let Some(cookie_path) = cookie_file.clone() {
+            if methods.iter().any(|m| m == "COOKIE") {
+                info!("Attempting COOKIE authentication");
+
+                // Read the cookie file
+                let cookie_data = match tokio::fs::read(&cookie_path).await {
+                    Ok(data) => {
+                        info!("Successfully read cookie file, length: {}", data.len());
+                        data
+                    }
+                    Err(e) => {
+                        warn!("Failed to read cookie file: {}", e);
+                        return Err(anyhow!("Failed to read cookie file: {}", e));
+                    }
+                };
+
+                // Send the authentication command with the cookie data
+                let auth_cmd = format!(
+                    "AUTHENTICATE {}",
+                    hex::encode(&cookie_data).to_uppercase()
+                );
+                info!("Sending {} command", command_name(&auth_cmd));
+                match self.command(&auth_cmd).await {
+                    Ok(_) => {
+                        info!("Successfully authenticated with COOKIE");
+                        return Ok(());
+                    }
+                    Err(e) => warn!("COOKIE authentication failed: {}", e),
+                }
+            }
+
+            // Try SAFECOOKIE authentication if COOKIE failed
+            if methods.iter().any(|m| m == "SAFECOOKIE") {
+                info!("Attempting SAFECOOKIE authentication");
+
+                // Read the cookie file
+                let cookie_data = match tokio::fs::read(&cookie_path).await {
+                    Ok(data) => {
+                        info!("Successfully read cookie file, length: {}", data.len());
+                        data
+                    }
+                    Err(e) => {
+                        warn!("Failed to read cookie file: {}", e);
+                        return Err(anyhow!("Failed to read cookie file: {}", e));
+                    }
+                };
+
+                // Generate client nonce
+                let mut client_nonce = vec![0u8; 32];
+                rand::thread_rng().fill(&mut client_nonce[..]);
+                let client_nonce_hex = hex::encode(&client_nonce).to_uppercase();
+                debug!("Generated client nonce (hex): {}", client_nonce_hex);
+
+                // Send AUTHCHALLENGE command with our nonce
+                let auth_cmd = format!("AUTHCHALLENGE SAFECOOKIE {}", client_nonce_hex);
+                debug!("Sending AUTHCHALLENGE command: {}", auth_cmd);
+                let response = self.command(&auth_cmd).await?;
+                debug!("AUTHCHALLENGE response: {:?}", response.lines);
+
+                // Parse the server hash and nonce from response
+                let (server_hash, server_nonce) = match response.lines.iter().find(|l| l.text.starts_with("AUTHCHALLENGE ")) {
+                    Some(line) => {
+                        debug!("Found AUTHCHALLENGE line: {}", line.text);
+                        let (_, args) = parse_args(&line.text);
+
+                        let server_hash = args.get("SERVERHASH")
+                            .ok_or_else(|| anyhow!("Missing SERVERHASH in response"))?;
+                        let server_nonce = args.get("SERVERNONCE")
+                            .ok_or_else(|| anyhow!("Missing SERVERNONCE in response"))?;
+
+                        debug!("Server hash: {}", server_hash);
+                        debug!("Server nonce: {}", server_nonce);
+
+                        match (hex::decode(server_nonce), hex::decode(server_hash)) {
+                            (Ok(nonce), Ok(hash)) => {
+                                info!("Decoded server nonce length: {}", nonce.len());
+                                info!("Decoded server hash length: {}", hash.len());
+                                (hash, nonce)
+                            }
+                            _ => {
+                                warn!("Failed to decode server nonce or hash");
+                                return Err(anyhow!("Failed to decode server nonce or hash"));
+                            }
+                        }
+                    }
+                    None => {
+                        warn!("Failed to get server nonce from AUTHCHALLENGE response");
+                        return Err(anyhow!("Failed to get server nonce from AUTHCHALLENGE response"));
+                    }
+                };
+
+                // Compute HMAC
+                let mut auth_input = Vec::new();
+                auth_input.extend_from_slice(&cookie_data);
+                auth_input.extend_from_slice(&client_nonce);
+                auth_input.extend_from_slice(&server_nonce);
+                info!("Auth input length: {}", auth_input.len());
+
+                let mut mac = match Hmac::<Sha256>::new_from_slice(b"Tor safe cookie authentication server-to-controller hash") {
+                    Ok(mac) => mac,
+                    Err(e) => {
+                        warn!("Failed to create HMAC: {}", e);
+                        return Err(anyhow!("Failed to create HMAC: {}", e));
+                    }
+                };
+                mac.update(&auth_input);
+                let computed_server_hash = mac.finalize().into_bytes();
+                debug!("Received server hash (hex): {}", hex::encode(&server_hash).to_uppercase());
+
+                // Verify server hash
+                if computed_server_hash.as_slice() != server_hash {
+                    warn!("Server hash verification failed");
+                    return Err(anyhow!("Server hash verification failed"));
+                }
+                info!("Server hash verified successfully");
+
+                // Compute client hash
+                let mut mac = match Hmac::<Sha256>::new_from_slice(b"Tor safe cookie authentication controller-to-server hash") {
+                    Ok(mac) => mac,
+                    Err(e) => {
+                        warn!("Failed to create HMAC: {}", e);
+                        return Err(anyhow!("Failed to create HMAC: {}", e));
+                    }
+                };
+                mac.update(&auth_input);
+                let client_hash = mac.finalize().into_bytes();
+
+                // Send the authentication command
+                let auth_cmd = format!(
+                    "AUTHENTICATE {}",
+                    hex::encode(client_hash).to_uppercase()
+                );
+                info!("Sending {} command", command_name(&auth_cmd));
+                match self.command(&auth_cmd).await {
+                    Ok(_) => {
+                        info!("Successfully authenticated with SAFECOOKIE");
+                        return Ok(());
+                    }
+                    Err(e) => warn!("SAFECOOKIE authentication failed: {}", e),
+                }
+            }
+        }
+
+        // Try null authentication as last resort
+        if methods.iter().any(|m| m == "NULL") || methods.is_empty() {
+            info!("Attempting null authentication");
+            match self.command("AUTHENTICATE").await {
+                Ok(_) => {
+                    info!("Successfully authenticated with null authentication");
+                    return Ok(());
+                }
+                Err(e) => warn!("Null authentication failed: {}", e),
+            }
+        }
+
+        Err(anyhow!("Failed to authenticate with Tor control port"))
+    }
+
+    pub async fn get_circuit_info(&self) -> Result<Vec<Circuit>> {
+        let response = self.command("GETINFO circuit-status").await?;
+
+        Ok(response.values("circuit-status")
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|line| Circuit::parse(line))
+            .collect())
+    }
+
+    /// Looks up a relay's router status entry in the current consensus.
+    pub async fn relay_info(&self, fingerprint: &str) -> Result<RelayInfo> {
+        let key = format!("ns/id/{}", fingerprint);
+        let response = self.command(&format!("GETINFO {}", key)).await?;
+        let lines = response.values(&key).unwrap_or_default();
+        RelayInfo::parse(fingerprint, &lines)
+    }
+
+    /// Router status entries of every relay in the current consensus
+    /// (`GETINFO ns/all`), line by line.
+    pub async fn network_status(&self) -> Result<Vec<String>> {
+        let response = self.command("GETINFO ns/all").await?;
+        Ok(response.values("ns/all").unwrap_or_default())
+    }
+
+    /// Country code for an address from Tor's GeoIP database, `None` if
+    /// Tor doesn't know it (`??`) or has no GeoIP data loaded.
+    pub async fn ip_to_country(&self, ip: IpAddr) -> Result<Option<String>> {
+        let key = format!("ip-to-country/{}", ip);
+        let response = match self.command(&format!("GETINFO {}", key)).await {
+            Ok(response) => response,
+            Err(e) if matches!(e.downcast_ref(), Some(ControlError::Internal(_))) => {
+                // 551 "GeoIP data not loaded"
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(response
+            .values(&key)
+            .and_then(|v| v.into_iter().next())
+            .filter(|cc| cc != "??" && !cc.is_empty()))
+    }
+
+    /// How long Tor would hold back a NEWNYM sent now
+    /// (`GETINFO signal/newnym`), `None` if this Tor doesn't report it.
+    pub async fn newnym_delay(&self) -> Result<Option<Duration>> {
+        let key = "signal/newnym";
+        let response = match self.command(&format!("GETINFO {}", key)).await {
+            Ok(response) => response,
+            Err(e) if matches!(e.downcast_ref(), Some(ControlError::UnrecognizedEntity(_))) => {
+                // 552 "Unrecognized key"
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(response
+            .values(key)
+            .and_then(|v| v.into_iter().next())
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs))
+    }
+
+    /// Tor's latest bootstrap status, `None` if it reported none yet.
+    pub async fn bootstrap_phase(&self) -> Result<Option<BootstrapPhase>> {
+        let key = "status/bootstrap-phase";
+        let response = self.command(&format!("GETINFO {}", key)).await?;
+        Ok(response
+            .values(key)
+            .and_then(|v| v.into_iter().next())
+            .and_then(|text| StatusEvent::parse(&text))
+            .and_then(|status| BootstrapPhase::from_status(&status)))
+    }
+
+    /// Returns once a general-purpose circuit is available, reacting to
+    /// CIRC events rather than polling.
+    pub async fn wait_for_circuits(&self, timeout: Duration) -> Result<()> {
+        let mut events = self.subscribe();
+        if self.get_circuit_info().await?.iter().any(Circuit::is_usable) {
+            return Ok(());
+        }
+        wait_for_built_circuit(&mut events, timeout).await
+    }
+}
+
+async fn wait_for_built_circuit(
+    events: &mut broadcast::Receiver<TorEvent>,
+    timeout: Duration,
+) -> Result<()> {
+    let wait = async {
+        loop {
+            match events.recv().await {
+                Ok(TorEvent::Circ(circuit)) if circuit.is_usable() => {
+                    info!("Circuit #{} built", circuit.id);
+                    return Ok(());
+                }
+                Ok(TorEvent::Circ(circuit)) if circuit.status == CircuitStatus::Failed => {
+                    warn!("Circuit #{} failed: {}", circuit.id, circuit.failure_reason());
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Missed {} Tor events while waiting for circuits", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(anyhow!("Tor control connection closed"));
+                }
+            }
+        }
+    };
+    time::timeout(timeout, wait)
+        .await
+        .map_err(|_| anyhow!("Timeout waiting for circuits to be built"))?
+}
+
+/// Keyword of a command, so secrets passed as arguments never hit the logs.
+fn command_name(cmd: &str) -> &str {
+    cmd.split_whitespace().next().unwrap_or(cmd)
+}
+
+/// Owns the socket: writes queued commands and routes each synchronous
+/// reply to the oldest outstanding request.
+async fn run_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut framed: Framed<S, LinesCodec>,
+    requests: &mut mpsc::Receiver<Request>,
+    events: broadcast::Sender<TorEvent>,
+) {
+    let mut pending: VecDeque<oneshot::Sender<Result<Reply>>> = VecDeque::new();
+    let mut parser = ReplyParser::new();
+
+    let reason = loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(Request { command, respond_to }) = request else {
+                    break "all handles dropped".to_string();
+                };
+                // LinesCodec appends '\n'; Tor expects CRLF line endings.
+                if let Err(e) = framed.send(format!("{}\r", command)).await {
+                    let _ = respond_to.send(Err(anyhow!("Failed to send command: {}", e)));
+                    break format!("write failed: {}", e);
+                }
+                pending.push_back(respond_to);
+            }
+            line = framed.next() => {
+                let line = match line {
+                    Some(Ok(line)) => line,
+                    Some(Err(e)) => break format!("read failed: {}", e),
+                    None => break "closed by Tor".to_string(),
+                };
+                debug!("Raw response line: {}", line);
+
+                match parser.feed(&line) {
+                    Ok(Some(reply)) if reply.is_async() => {
+                        if let Some(event) = TorEvent::parse(&reply) {
+                            // No subscribers is fine; nobody is listening yet.
+                            let _ = events.send(event);
+                        }
+                    }
+                    Ok(Some(reply)) => match pending.pop_front() {
+                        Some(respond_to) => {
+                            let _ = respond_to.send(Ok(reply));
+                        }
+                        None => warn!("Unsolicited reply from Tor: {:?}", reply),
+                    },
+                    Ok(None) => {}
+                    Err(e) => break e.to_string(),
+                }
+            }
+        }
+    };
+
+    info!("Tor control connection finished: {}", reason);
+    for respond_to in pending {
+        let _ = respond_to.send(Err(anyhow!("Tor control connection lost: {}", reason)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use std::sync::Mutex as StdMutex;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    const SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+    const CONTROLLER_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+    const SERVER_NONCE: [u8; 32] = [7; 32];
+
+    /// A handle talking to the returned end of an in-memory pipe.
+    fn pipe() -> (TorControl, DuplexStream) {
+        let (ours, tors) = tokio::io::duplex(64 * 1024);
+        (TorControl::from_stream(ours), tors)
+    }
+
+    fn is_closed(control: &TorControl) -> bool {
+        *control.closed.borrow()
+    }
+
+    #[tokio::test]
+    async fn drops_a_connection_sending_an_endless_line() {
+        let (mut control, mut tor) = pipe();
+        control.timeout = Duration::from_millis(200);
+        let chunk = vec![b'x'; 64 * 1024];
+        tokio::spawn(async move {
+            // Stops once the handle stops reading and drops its end
+            while tor.write_all(&chunk).await.is_ok() {}
+        });
+        time::timeout(Duration::from_secs(5), control.closed()).await.unwrap();
+        assert!(control.command("GETINFO version").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reattaching_reopens_the_connection() {
+        let (mut control, tor) = pipe();
+        // Commands sent while nothing carries them only time out
+        control.timeout = Duration::from_millis(200);
+        drop(tor);
+        time::timeout(Duration::from_secs(1), control.closed()).await.unwrap();
+        assert!(is_closed(&control));
+
+        let (fresh, tor) = pipe();
+        let (hang_up, hung_up) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(tor);
+            let mut lines = BufReader::new(read).lines();
+            tokio::select! {
+                _ = async {
+                    while let Ok(Some(_)) = lines.next_line().await {
+                        let _ = write.write_all(b"250 OK\r\n").await;
+                    }
+                } => {}
+                _ = hung_up => {}
+            }
+        });
+        let reattached = control.reattached();
+        control.reattach(fresh);
+        assert!(reattached.has_changed().unwrap());
+
+        control.command("GETINFO version").await.unwrap();
+        assert!(!is_closed(&control));
+
+        drop(hang_up);
+        time::timeout(Duration::from_secs(1), control.closed()).await.unwrap();
+        assert!(control.command("GETINFO version").await.is_err());
+    }
+
+    fn safecookie_hash(key: &[u8], cookie: &[u8], client_nonce: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(cookie);
+        mac.update(client_nonce);
+        mac.update(&SERVER_NONCE);
+        hex::encode_upper(mac.finalize().into_bytes())
+    }
+
+    /// A control port offering `methods` that records the commands it
+    /// gets and answers the ones after PROTOCOLINFO with `respond`.
+    async fn auth_port<F>(
+        methods: &str,
+        cookie_file: Option<&std::path::Path>,
+        respond: F,
+    ) -> (TorControl, Arc<StdMutex<Vec<String>>>)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        let protocol_info = format!(
+            "250-PROTOCOLINFO 1\n250-AUTH METHODS={}{}\n250-VERSION Tor=\"0.4.8.9\"\n250 OK",
+            methods,
+            cookie_file.map(|path| format!(" COOKIEFILE={}", quote(&path.to_string_lossy()))).unwrap_or_default()
+        );
+        let commands = Arc::new(StdMutex::new(Vec::new()));
+        let seen = commands.clone();
+        let control = testutil::fake_control(move |command| {
+            seen.lock().unwrap().push(command.to_string());
+            if command.starts_with("PROTOCOLINFO") {
+                protocol_info.clone()
+            } else {
+                respond(command)
+            }
+        })
+        .await;
+        (control, commands)
+    }
+
+    /// Answers the SAFECOOKIE exchange for `cookie`, claiming `server_key`
+    /// as the key of its own hash.
+    fn safecookie_port(cookie: Vec<u8>, server_key: &'static [u8]) -> impl Fn(&str) -> String + Send + Sync {
+        let client_nonce = StdMutex::new(Vec::new());
+        move |command: &str| {
+            if let Some(nonce) = command.strip_prefix("AUTHCHALLENGE SAFECOOKIE ") {
+                let nonce = hex::decode(nonce).unwrap();
+                let reply = format!(
+                    "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}",
+                    safecookie_hash(server_key, &cookie, &nonce),
+                    hex::encode_upper(SERVER_NONCE)
+                );
+                *client_nonce.lock().unwrap() = nonce;
+                reply
+            } else if let Some(hash) = command.strip_prefix("AUTHENTICATE ") {
+                let expected = safecookie_hash(CONTROLLER_KEY, &cookie, &client_nonce.lock().unwrap());
+                if hash == expected {
+                    "250 OK".to_string()
+                } else {
+                    "515 Authentication failed: Safe cookie response did not match expected value.".to_string()
+                }
+            } else {
+                "510 Unrecognized command".to_string()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_a_quoted_password() {
+        let password = "pa ss\"w\\rd\n";
+        let expected = r#"AUTHENTICATE "pa ss\"w\\rd\n""#;
+        let (control, commands) = auth_port("HASHEDPASSWORD", None, move |command| {
+            if command == expected {
+                "250 OK".to_string()
+            } else {
+                "515 Authentication failed: Password did not match HashedControlPassword value from configuration"
+                    .to_string()
+            }
+        })
+        .await;
+        control.authenticate(Some(password.to_string())).await.unwrap();
+        assert_eq!(commands.lock().unwrap().last().unwrap(), expected);
+
+        let (control, _) = auth_port("HASHEDPASSWORD", None, |_| "515 Authentication failed".to_string()).await;
+        let error = control.authenticate(Some("wrong".to_string())).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("HASHEDPASSWORD authentication failed"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn asks_for_a_password_tor_requires() {
+        let (control, commands) = auth_port("HASHEDPASSWORD", None, |_| "250 OK".to_string()).await;
+        let error = control.authenticate(None).await.unwrap_err();
+        assert!(error.to_string().contains("--password"), "{}", error);
+        assert!(!commands.lock().unwrap().iter().any(|command| command.starts_with("AUTHENTICATE")));
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_safecookie() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_file = dir.path().join("control_auth_cookie");
+        let cookie = vec![0x42; 32];
+        std::fs::write(&cookie_file, &cookie).unwrap();
+
+        let (control, commands) =
+            auth_port("SAFECOOKIE", Some(&cookie_file), safecookie_port(cookie, SERVER_KEY)).await;
+        control.authenticate(None).await.unwrap();
+        let commands = commands.lock().unwrap();
+        assert!(commands[1].starts_with("AUTHCHALLENGE SAFECOOKIE "), "{:?}", commands);
+        assert!(commands[2].starts_with("AUTHENTICATE "), "{:?}", commands);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_server_that_does_not_know_the_cookie() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie_file = dir.path().join("control_auth_cookie");
+        let cookie = vec![0x42; 32];
+        std::fs::write(&cookie_file, &cookie).unwrap();
+
+        // Right cookie, wrong key: the server hash can't be verified
+        let (control, commands) =
+            auth_port("SAFECOOKIE", Some(&cookie_file), safecookie_port(cookie, CONTROLLER_KEY)).await;
+        let error = control.authenticate(None).await.unwrap_err();
+        assert!(error.to_string().contains("Server hash verification failed"), "{}", error);
+        // Our hash proves we know the cookie; it must not go to an impostor
+        assert!(!commands.lock().unwrap().iter().any(|command| command.starts_with("AUTHENTICATE")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connects_through_a_control_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = tokio::io::split(stream);
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(command)) = lines.next_line().await {
+                let reply = match command.trim_end() {
+                    "GETINFO version" => "250-version=0.4.8.9\r\n250 OK\r\n",
+                    _ => "510 Unrecognized command\r\n",
+                };
+                let _ = write.write_all(reply.as_bytes()).await;
+            }
+        });
+
+        let control = TorControl::connect(&ControlEndpoint::Unix(path.clone())).await.unwrap();
+        let reply = control.command("GETINFO version").await.unwrap();
+        assert_eq!(reply.values("version"), Some(vec!["0.4.8.9".to_string()]));
+        assert!(control.command("BOGUS").await.is_err());
+
+        let missing = ControlEndpoint::Unix(dir.path().join("missing"));
+        let error = TorControl::connect(&missing).await.err().unwrap();
+        assert!(error.to_string().contains("Failed to connect to Tor control socket"), "{}", error);
+    }
+}
@@ -0,0 +1,676 @@
+//! DNS leak check: unique random names under a zone we are authoritative
+//! for are looked up through Tor (SOCKS CONNECT with the host name, Tor's
+//! `RESOLVE` extension and its DNSPort) and, for comparison, with the
+//! local resolver. The authoritative server records which resolver asked
+//! for each name and reports it back, so a Tor lookup that reached our own
+//! resolver shows up as a leak.
+//!
+//! The server side is [`LeakServer`]: it answers every name under the zone
+//! with its own address, and on its report port takes a name per
+//! connection and returns the addresses that queried it, one per line.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::socks::{self, TargetAddr};
+
+/// Names looked up per method.
+const ROUNDS: usize = 3;
+
+/// Names whose queriers the server remembers.
+const REMEMBERED_NAMES: usize = 10_000;
+
+/// Clients must ask for their report within this time.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_FORMAT_ERROR: u16 = 1;
+const RCODE_REFUSED: u16 = 5;
+
+/// How a name was looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// SOCKS CONNECT to the host name; Tor resolves it at the exit.
+    SocksConnect,
+    /// Tor's SOCKS `RESOLVE` extension.
+    SocksResolve,
+    /// A query to Tor's DNSPort.
+    DnsPort,
+    /// Our own resolver, to know what a leak looks like.
+    Local,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Method::SocksConnect => write!(f, "SOCKS CONNECT"),
+            Method::SocksResolve => write!(f, "SOCKS RESOLVE"),
+            Method::DnsPort => write!(f, "DNSPort"),
+            Method::Local => write!(f, "local resolver"),
+        }
+    }
+}
+
+/// One name looked up, and the resolvers the server saw asking for it.
+pub struct Probe {
+    pub method: Method,
+    pub name: String,
+    pub resolvers: Result<Vec<IpAddr>>,
+}
+
+pub struct DnsLeakReport {
+    pub probes: Vec<Probe>,
+}
+
+impl DnsLeakReport {
+    /// Resolvers seen for the names looked up with `method`.
+    pub fn resolvers(&self, method: Method) -> Vec<IpAddr> {
+        let mut resolvers = Vec::new();
+        for probe in self.probes.iter().filter(|probe| probe.method == method) {
+            for resolver in probe.resolvers.iter().flatten() {
+                if !resolvers.contains(resolver) {
+                    resolvers.push(*resolver);
+                }
+            }
+        }
+        resolvers
+    }
+
+    /// Lookups through Tor that reached one of our local resolvers.
+    pub fn leaks(&self) -> Vec<(&Probe, IpAddr)> {
+        let local = self.resolvers(Method::Local);
+        self.probes
+            .iter()
+            .filter(|probe| probe.method != Method::Local)
+            .flat_map(|probe| probe.resolvers.iter().flatten().map(move |resolver| (probe, *resolver)))
+            .filter(|(_, resolver)| local.contains(resolver))
+            .collect()
+    }
+
+    /// Whether any lookup through Tor got an answer at all.
+    pub fn is_conclusive(&self) -> bool {
+        self.probes
+            .iter()
+            .any(|probe| probe.method != Method::Local && matches!(&probe.resolvers, Ok(r) if !r.is_empty()))
+    }
+}
+
+pub struct DnsLeakCheck {
+    /// Zone served by a [`LeakServer`].
+    pub zone: String,
+    /// The server's report port.
+    pub report_port: u16,
+    /// Tor's SocksPort.
+    pub socks: SocketAddr,
+    /// Tor's DNSPort, if it has one.
+    pub dns_port: Option<SocketAddr>,
+    /// How long one lookup and its report may take.
+    pub timeout: Duration,
+}
+
+impl DnsLeakCheck {
+    pub async fn run(&self) -> DnsLeakReport {
+        let mut methods = vec![Method::Local, Method::SocksConnect, Method::SocksResolve];
+        if self.dns_port.is_some() {
+            methods.push(Method::DnsPort);
+        }
+        let mut probes = Vec::new();
+        for method in methods {
+            for _ in 0..ROUNDS {
+                let name = format!("{:016x}.{}", rand::random::<u64>(), self.zone);
+                let resolvers = time::timeout(self.timeout, self.look_up(method, &name))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("no answer within {}s", self.timeout.as_secs())));
+                match &resolvers {
+                    Ok(resolvers) => debug!("{} lookup of {} came from {:?}", method, name, resolvers),
+                    Err(e) => debug!("{} lookup of {} failed: {:#}", method, name, e),
+                }
+                probes.push(Probe {
+                    method,
+                    name,
+                    resolvers,
+                });
+            }
+        }
+        DnsLeakReport { probes }
+    }
+
+    /// Looks `name` up with `method` and asks the server, through Tor, who
+    /// queried it.
+    async fn look_up(&self, method: Method, name: &str) -> Result<Vec<IpAddr>> {
+        let server = match method {
+            Method::SocksConnect => {
+                // The exit resolves the name and connects us to the server
+                let target = TargetAddr::Domain(name.to_string(), self.report_port);
+                let mut stream = socks::connect(self.socks, &target, None).await?;
+                return read_report(&mut stream, name).await;
+            }
+            Method::SocksResolve => socks::resolve(self.socks, name, None).await?,
+            Method::DnsPort => {
+                let dns_port = self.dns_port.ok_or_else(|| anyhow!("No DNSPort configured"))?;
+                query(dns_port, name).await?
+            }
+            Method::Local => tokio::net::lookup_host((name, self.report_port))
+                .await
+                .context("Local lookup failed")?
+                .next()
+                .map(|addr| addr.ip())
+                .ok_or_else(|| anyhow!("Local lookup returned no address"))?,
+        };
+        let target = TargetAddr::Ip(SocketAddr::new(server, self.report_port));
+        let mut stream = socks::connect(self.socks, &target, None)
+            .await
+            .with_context(|| format!("Failed to reach the report port at {}", target))?;
+        read_report(&mut stream, name).await
+    }
+}
+
+/// Asks a [`LeakServer`] which addresses queried `name`.
+async fn read_report<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, name: &str) -> Result<Vec<IpAddr>> {
+    stream.write_all(format!("{}\n", name).as_bytes()).await?;
+    let mut resolvers = Vec::new();
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        let resolver = line
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Malformed report line {:?}", line))?;
+        resolvers.push(resolver);
+    }
+    Ok(resolvers)
+}
+
+/// Sends an A query for `name` to the DNS server at `server`.
+async fn query(server: SocketAddr, name: &str) -> Result<IpAddr> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    let id = rand::random::<u16>();
+    socket.send(&encode_query(id, name)?).await?;
+    let mut packet = [0u8; 512];
+    loop {
+        let len = socket.recv(&mut packet).await?;
+        // Ignore stray datagrams that don't answer our query
+        if len >= 2 && u16::from_be_bytes([packet[0], packet[1]]) == id {
+            return parse_answer(&packet[..len])?
+                .ok_or_else(|| anyhow!("{} returned no address for {}", server, name));
+        }
+    }
+}
+
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(64);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|&len| len > 0 && len < 64)
+            .ok_or_else(|| anyhow!("Invalid DNS name {:?}", name))?;
+        packet.push(len);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_A.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+/// Reads a possibly compressed name at `offset`, returning it and the
+/// offset just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let malformed = || anyhow!("Malformed DNS name");
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Every pointer has to go backwards, which rules out loops
+    let mut limit = offset;
+    loop {
+        let len = *packet.get(offset).ok_or_else(malformed)? as usize;
+        match len {
+            0 => {
+                offset += 1;
+                break;
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let low = *packet.get(offset + 1).ok_or_else(malformed)? as usize;
+                let target = ((len & 0x3f) << 8) | low;
+                if target >= limit {
+                    return Err(malformed());
+                }
+                end.get_or_insert(offset + 2);
+                limit = target;
+                offset = target;
+            }
+            len if len < 64 => {
+                let label = packet.get(offset + 1..offset + 1 + len).ok_or_else(malformed)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(offset)))
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16> {
+    packet
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| anyhow!("Truncated DNS message"))
+}
+
+/// The first A record in a response, `None` if it has none.
+fn parse_answer(packet: &[u8]) -> Result<Option<IpAddr>> {
+    let flags = read_u16(packet, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(anyhow!("DNS message is not a response"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        rcode => return Err(anyhow!("DNS error code {}", rcode)),
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+    for _ in 0..answers {
+        offset = read_name(packet, offset)?.1;
+        let (kind, class, len) = (read_u16(packet, offset)?, read_u16(packet, offset + 2)?, read_u16(packet, offset + 8)?);
+        let data = packet
+            .get(offset + 10..offset + 10 + len as usize)
+            .ok_or_else(|| anyhow!("Truncated DNS message"))?;
+        if kind == TYPE_A && class == CLASS_IN {
+            if let Ok(octets) = <[u8; 4]>::try_from(data) {
+                return Ok(Some(Ipv4Addr::from(octets).into()));
+            }
+        }
+        offset += 10 + len as usize;
+    }
+    Ok(None)
+}
+
+#[derive(Default)]
+struct Queries {
+    by_name: HashMap<String, Vec<IpAddr>>,
+    /// Oldest first, for forgetting.
+    order: VecDeque<String>,
+}
+
+/// Authoritative server for the check's zone: answers every A query under
+/// it with `answer`, its own address, and remembers who asked.
+pub struct LeakServer {
+    zone: String,
+    answer: Ipv4Addr,
+    queries: Mutex<Queries>,
+}
+
+impl LeakServer {
+    pub fn new(zone: &str, answer: Ipv4Addr) -> Self {
+        Self {
+            zone: zone.trim_end_matches('.').to_ascii_lowercase(),
+            answer,
+            queries: Mutex::default(),
+        }
+    }
+
+    /// Answers DNS queries on `socket` forever.
+    pub async fn serve_dns(self: Arc<Self>, socket: UdpSocket) {
+        if let Ok(addr) = socket.local_addr() {
+            info!("Serving {} on {}/udp, answering {}", self.zone, addr, self.answer);
+        }
+        let mut packet = [0u8; 512];
+        loop {
+            let (len, from) = match socket.recv_from(&mut packet).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Failed to receive DNS query: {}", e);
+                    time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let Some(response) = self.respond(&packet[..len], from.ip()) else {
+                continue;
+            };
+            if let Err(e) = socket.send_to(&response, from).await {
+                debug!("Failed to answer {}: {}", from, e);
+            }
+        }
+    }
+
+    /// Tells clients on `listener` who queried the name they send.
+    pub async fn serve_reports(self: Arc<Self>, listener: TcpListener) {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Serving DNS leak reports on {}", addr);
+        }
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept report connection: {}", e);
+                    time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut name = String::new();
+                let read = time::timeout(REPORT_TIMEOUT, stream.read_line(&mut name)).await;
+                if !matches!(read, Ok(Ok(len)) if len > 0 && len <= 256) {
+                    debug!("Report request from {} timed out or was malformed", peer);
+                    return;
+                }
+                let name = name.trim().trim_end_matches('.').to_ascii_lowercase();
+                let report: String = server.queriers(&name).iter().map(|ip| format!("{}\n", ip)).collect();
+                if let Err(e) = stream.get_mut().write_all(report.as_bytes()).await {
+                    debug!("Failed to send report to {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    fn queriers(&self, name: &str) -> Vec<IpAddr> {
+        self.queries.lock().unwrap().by_name.get(name).cloned().unwrap_or_default()
+    }
+
+    fn record(&self, name: String, from: IpAddr) {
+        let mut queries = self.queries.lock().unwrap();
+        if !queries.by_name.contains_key(&name) {
+            if queries.order.len() == REMEMBERED_NAMES {
+                if let Some(oldest) = queries.order.pop_front() {
+                    queries.by_name.remove(&oldest);
+                }
+            }
+            queries.order.push_back(name.clone());
+        }
+        let queriers = queries.by_name.entry(name).or_default();
+        if !queriers.contains(&from) {
+            queriers.push(from);
+        }
+    }
+
+    /// The response to a query, `None` for anything that isn't one.
+    fn respond(&self, packet: &[u8], from: IpAddr) -> Option<Vec<u8>> {
+        let flags = read_u16(packet, 2).ok()?;
+        if flags & FLAG_RESPONSE != 0 {
+            return None;
+        }
+        let mut response = packet.get(..12)?.to_vec();
+        let question = match (read_u16(packet, 4), read_name(packet, 12)) {
+            (Ok(1), Ok((name, end))) if packet.len() >= end + 4 => Some((name, end + 4)),
+            _ => None,
+        };
+        let Some((name, end)) = question else {
+            return Some(error_response(response, flags, RCODE_FORMAT_ERROR));
+        };
+        // Resolvers randomise the case of names; answer in theirs, record in ours
+        let name = name.to_ascii_lowercase();
+        if name != self.zone && !name.ends_with(&format!(".{}", self.zone)) {
+            return Some(error_response(response, flags, RCODE_REFUSED));
+        }
+        self.record(name, from);
+
+        let kind = read_u16(packet, end - 4).ok()?;
+        let answers: u16 = if kind == TYPE_A { 1 } else { 0 };
+        let flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE | (flags & FLAG_RECURSION_DESIRED);
+        response[2..4].copy_from_slice(&flags.to_be_bytes());
+        response[4..12].copy_from_slice(&[0, 1, 0, answers as u8, 0, 0, 0, 0]);
+        response.extend_from_slice(&packet[12..end]);
+        if answers > 0 {
+            // Name pointer to the question, TTL 0 so nobody caches it
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(&TYPE_A.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&[0, 0, 0, 0, 0, 4]);
+            response.extend_from_slice(&self.answer.octets());
+        }
+        Some(response)
+    }
+}
+
+fn error_response(mut response: Vec<u8>, flags: u16, rcode: u16) -> Vec<u8> {
+    let flags = FLAG_RESPONSE | (flags & FLAG_RECURSION_DESIRED) | rcode;
+    response[2..4].copy_from_slice(&flags.to_be_bytes());
+    response[4..12].fill(0);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    const ZONE: &str = "leak.test";
+
+    /// Where the exit's resolver queries from, as far as the server sees.
+    const EXIT_RESOLVER: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+    fn query_packet(name: &str) -> Vec<u8> {
+        encode_query(0x1234, name).unwrap()
+    }
+
+    /// Asks the server at `dns` for `name` the way the exit's resolver would.
+    async fn exit_lookup(dns: SocketAddr, name: &str) -> Option<IpAddr> {
+        let socket = UdpSocket::bind((EXIT_RESOLVER, 0)).await.ok()?;
+        socket.send_to(&query_packet(name), dns).await.ok()?;
+        let mut packet = [0u8; 512];
+        let len = socket.recv(&mut packet).await.ok()?;
+        parse_answer(&packet[..len]).ok()?
+    }
+
+    /// Tor's DNSPort, passing queries on to the exit's resolver.
+    async fn dns_port(dns: SocketAddr) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut packet = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut packet).await {
+                let exit = UdpSocket::bind((EXIT_RESOLVER, 0)).await.unwrap();
+                exit.send_to(&packet[..len], dns).await.unwrap();
+                let mut response = [0u8; 512];
+                let len = exit.recv(&mut response).await.unwrap();
+                let _ = socket.send_to(&response[..len], from).await;
+            }
+        });
+        addr
+    }
+
+    /// A leak server on loopback answering with the report port's address.
+    async fn leak_server() -> (SocketAddr, u16) {
+        let server = Arc::new(LeakServer::new(ZONE, Ipv4Addr::LOCALHOST));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dns = socket.local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let report_port = listener.local_addr().unwrap().port();
+        tokio::spawn(server.clone().serve_dns(socket));
+        tokio::spawn(server.serve_reports(listener));
+        (dns, report_port)
+    }
+
+    #[tokio::test]
+    async fn tor_lookups_come_from_the_exit() {
+        let (dns, report_port) = leak_server().await;
+        let socks = testutil::socks_port(move |name| async move { exit_lookup(dns, &name).await }).await;
+        let check = DnsLeakCheck {
+            zone: ZONE.to_string(),
+            report_port,
+            socks,
+            dns_port: Some(dns_port(dns).await),
+            timeout: Duration::from_secs(2),
+        };
+        let report = check.run().await;
+
+        for method in [Method::SocksConnect, Method::SocksResolve, Method::DnsPort] {
+            let probes: Vec<&Probe> = report.probes.iter().filter(|probe| probe.method == method).collect();
+            assert_eq!(probes.len(), ROUNDS, "{}", method);
+            for probe in probes {
+                assert!(probe.name.ends_with(".leak.test"), "{}", probe.name);
+                assert_eq!(probe.resolvers.as_ref().unwrap(), &[IpAddr::from(EXIT_RESOLVER)], "{}", method);
+            }
+        }
+        // .test is reserved, so local lookups never reach the server
+        assert!(report.resolvers(Method::Local).is_empty());
+        assert!(report.leaks().is_empty());
+        assert!(report.is_conclusive());
+    }
+
+    #[test]
+    fn reports_tor_lookups_by_a_local_resolver() {
+        let local: IpAddr = "192.0.2.53".parse().unwrap();
+        let exit: IpAddr = "198.51.100.53".parse().unwrap();
+        let probe = |method, resolvers: Vec<IpAddr>| Probe {
+            method,
+            name: format!("{}.{}", method, ZONE),
+            resolvers: Ok(resolvers),
+        };
+        let report = DnsLeakReport {
+            probes: vec![
+                probe(Method::Local, vec![local]),
+                probe(Method::SocksConnect, vec![exit]),
+                probe(Method::DnsPort, vec![exit, local]),
+                Probe { method: Method::SocksResolve, name: ZONE.to_string(), resolvers: Err(anyhow!("no answer")) },
+            ],
+        };
+        let leaks: Vec<(Method, IpAddr)> = report.leaks().iter().map(|(probe, ip)| (probe.method, *ip)).collect();
+        assert_eq!(leaks, [(Method::DnsPort, local)]);
+        assert_eq!(report.resolvers(Method::DnsPort), [exit, local]);
+        assert!(report.is_conclusive());
+
+        let report =
+            DnsLeakReport { probes: vec![probe(Method::Local, vec![local]), probe(Method::SocksConnect, Vec::new())] };
+        assert!(!report.is_conclusive());
+    }
+
+    #[test]
+    fn reads_names() {
+        let mut packet = vec![0u8; 12];
+        // 12: leak.test
+        packet.extend_from_slice(b"\x04leak\x04test\x00");
+        // 23: abc + pointer to 12
+        packet.extend_from_slice(b"\x03abc\xc0\x0c");
+        // 29: pointer to 23
+        packet.extend_from_slice(b"\xc0\x17");
+
+        assert_eq!(read_name(&packet, 12).unwrap(), ("leak.test".to_string(), 23));
+        assert_eq!(read_name(&packet, 23).unwrap(), ("abc.leak.test".to_string(), 29));
+        assert_eq!(read_name(&packet, 29).unwrap(), ("abc.leak.test".to_string(), 31));
+        assert_eq!(read_name(b"\x00", 0).unwrap(), (String::new(), 1));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        let cases: &[&[u8]] = &[
+            // Pointer to itself
+            b"\xc0\x00",
+            // Forward pointer
+            b"\xc0\x02\x00",
+            // Truncated pointer, label and name
+            b"\xc0",
+            b"\x05ab",
+            b"\x01a",
+            // Reserved label type
+            b"\x40",
+        ];
+        for packet in cases {
+            assert!(read_name(packet, 0).is_err(), "{:02x?}", packet);
+        }
+        // Back to the start of the name, then forward into it again
+        assert!(read_name(b"\x01a\xc0\x04\x01b\xc0\x00", 4).is_err());
+    }
+
+    #[test]
+    fn answers_names_in_the_zone() {
+        let server = LeakServer::new("Leak.Test.", Ipv4Addr::new(192, 0, 2, 1));
+        let from: IpAddr = "198.51.100.53".parse().unwrap();
+        let query = query_packet("AbC.lEaK.tEsT");
+        let response = server.respond(&query, from).unwrap();
+
+        assert_eq!(response[..2], query[..2]);
+        let flags = read_u16(&response, 2).unwrap();
+        assert_eq!(flags, FLAG_RESPONSE | FLAG_AUTHORITATIVE | FLAG_RECURSION_DESIRED);
+        // The question comes back in the resolver's case
+        assert_eq!(read_name(&response, 12).unwrap().0, "AbC.lEaK.tEsT");
+        assert_eq!(parse_answer(&response).unwrap(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(server.queriers("abc.leak.test"), [from]);
+
+        // Asking again doesn't list the resolver twice
+        server.respond(&query, from).unwrap();
+        assert_eq!(server.queriers("abc.leak.test"), [from]);
+
+        // Other types are recorded but get no answer
+        let mut aaaa = query_packet("v6.leak.test");
+        let len = aaaa.len();
+        aaaa[len - 4..len - 2].copy_from_slice(&28u16.to_be_bytes());
+        let response = server.respond(&aaaa, from).unwrap();
+        assert_eq!(parse_answer(&response).unwrap(), None);
+        assert_eq!(server.queriers("v6.leak.test"), [from]);
+    }
+
+    #[test]
+    fn refuses_what_it_should_not_answer() {
+        let server = LeakServer::new(ZONE, Ipv4Addr::LOCALHOST);
+        let from: IpAddr = "198.51.100.53".parse().unwrap();
+        let rcode = |response: Vec<u8>| read_u16(&response, 2).unwrap() & 0x000f;
+
+        let outside = server.respond(&query_packet("example.com"), from).unwrap();
+        assert_eq!(rcode(outside), RCODE_REFUSED);
+        let suffix = server.respond(&query_packet("notleak.test"), from).unwrap();
+        assert_eq!(rcode(suffix), RCODE_REFUSED);
+        assert!(server.queriers("example.com").is_empty());
+
+        let mut truncated = query_packet("a.leak.test");
+        truncated.truncate(truncated.len() - 2);
+        assert_eq!(rcode(server.respond(&truncated, from).unwrap()), RCODE_FORMAT_ERROR);
+        let mut two_questions = query_packet("a.leak.test");
+        two_questions[5] = 2;
+        assert_eq!(rcode(server.respond(&two_questions, from).unwrap()), RCODE_FORMAT_ERROR);
+
+        // Responses and runts are dropped
+        let response = server.respond(&query_packet("a.leak.test"), from).unwrap();
+        assert!(server.respond(&response, from).is_none());
+        assert!(server.respond(&[0x12], from).is_none());
+    }
+
+    #[test]
+    fn parses_answers() {
+        let server = LeakServer::new(ZONE, Ipv4Addr::new(192, 0, 2, 1));
+        let from: IpAddr = "198.51.100.53".parse().unwrap();
+        let response = server.respond(&query_packet("a.leak.test"), from).unwrap();
+        assert_eq!(parse_answer(&response).unwrap(), Some("192.0.2.1".parse().unwrap()));
+
+        // A CNAME before the A record is skipped
+        let mut with_cname = response[..12].to_vec();
+        with_cname[7] = 2;
+        let question_end = read_name(&response, 12).unwrap().1 + 4;
+        with_cname.extend_from_slice(&response[12..question_end]);
+        with_cname.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x00\x00\x02\xc0\x0c");
+        with_cname.extend_from_slice(&response[question_end..]);
+        assert_eq!(parse_answer(&with_cname).unwrap(), Some("192.0.2.1".parse().unwrap()));
+
+        assert!(parse_answer(&query_packet("a.leak.test")).is_err(), "a query is not an answer");
+        let refused = server.respond(&query_packet("example.com"), from).unwrap();
+        assert!(parse_answer(&refused).unwrap_err().to_string().contains("code 5"));
+        let mut truncated = response.clone();
+        truncated.truncate(truncated.len() - 2);
+        assert!(parse_answer(&truncated).is_err());
+        assert!(parse_answer(&response[..3]).is_err());
+    }
+}
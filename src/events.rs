@@ -0,0 +1,284 @@
+//! Typed asynchronous events (control-spec §4.1).
+
+use std::collections::HashMap;
+
+use crate::circuit::Circuit;
+use crate::reply::{parse_args, Reply};
+
+/// Events we ask Tor for right after authenticating.
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "CIRC",
+    "STREAM",
+    "ORCONN",
+    "BW",
+    "STATUS_CLIENT",
+    "STATUS_GENERAL",
+    "NEWDESC",
+    "GUARD",
+    // Tor reports NEWNYM rate limiting only as a log message
+    "NOTICE",
+];
+
+#[derive(Debug, Clone)]
+pub enum TorEvent {
+    /// `650 CIRC` — same layout as a `circuit-status` entry.
+    Circ(Box<Circuit>),
+    Stream(StreamEvent),
+    OrConn(OrConnEvent),
+    /// Bytes read/written in the last second.
+    Bandwidth { read: u64, written: u64 },
+    StatusClient(StatusEvent),
+    StatusGeneral(StatusEvent),
+    /// Fingerprints (`$ID~nick`) of relays with new descriptors.
+    NewDesc(Vec<String>),
+    Guard(GuardEvent),
+    /// Log message (`DEBUG`, `INFO`, `NOTICE`, `WARN` or `ERR`).
+    Log { severity: String, message: String },
+    /// Anything we subscribed to but don't model (or failed to parse).
+    Other { keyword: String, text: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamEvent {
+    pub id: String,
+    pub status: String,
+    pub circuit_id: String,
+    pub target: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OrConnEvent {
+    pub target: String,
+    pub status: String,
+    pub reason: Option<String>,
+}
+
+/// `STATUS_CLIENT`/`STATUS_GENERAL` payload, e.g.
+/// `NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY="Done"`.
+#[derive(Debug, Clone)]
+pub struct StatusEvent {
+    pub severity: String,
+    pub action: String,
+    pub args: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GuardEvent {
+    pub kind: String,
+    pub name: String,
+    pub status: String,
+}
+
+impl TorEvent {
+    pub fn parse(reply: &Reply) -> Option<Self> {
+        let text = &reply.lines.first()?.text;
+        let (keyword, rest) = text.split_once(' ').unwrap_or((text.as_str(), ""));
+
+        let event = match keyword {
+            "CIRC" => Circuit::parse(rest).map(|c| TorEvent::Circ(Box::new(c))),
+            "STREAM" => {
+                let (words, args) = parse_args(rest);
+                match words.as_slice() {
+                    [id, status, circuit_id, target, ..] => Some(TorEvent::Stream(StreamEvent {
+                        id: id.clone(),
+                        status: status.clone(),
+                        circuit_id: circuit_id.clone(),
+                        target: target.clone(),
+                        reason: args.get("REASON").cloned(),
+                    })),
+                    _ => None,
+                }
+            }
+            "ORCONN" => {
+                let (words, args) = parse_args(rest);
+                match words.as_slice() {
+                    [target, status, ..] => Some(TorEvent::OrConn(OrConnEvent {
+                        target: target.clone(),
+                        status: status.clone(),
+                        reason: args.get("REASON").cloned(),
+                    })),
+                    _ => None,
+                }
+            }
+            "BW" => {
+                let mut parts = rest.split_whitespace().map(str::parse::<u64>);
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(read)), Some(Ok(written))) => {
+                        Some(TorEvent::Bandwidth { read, written })
+                    }
+                    _ => None,
+                }
+            }
+            "STATUS_CLIENT" => StatusEvent::parse(rest).map(TorEvent::StatusClient),
+            "STATUS_GENERAL" => StatusEvent::parse(rest).map(TorEvent::StatusGeneral),
+            "NEWDESC" => Some(TorEvent::NewDesc(
+                rest.split_whitespace().map(str::to_string).collect(),
+            )),
+            "GUARD" => {
+                let mut parts = rest.split_whitespace();
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(kind), Some(name), Some(status)) => Some(TorEvent::Guard(GuardEvent {
+                        kind: kind.to_string(),
+                        name: name.to_string(),
+                        status: status.to_string(),
+                    })),
+                    _ => None,
+                }
+            }
+            "DEBUG" | "INFO" | "NOTICE" | "WARN" | "ERR" => {
+                let first = reply.lines.first()?;
+                let message = match &first.data {
+                    Some(data) => data.join("\n"),
+                    None => rest.to_string(),
+                };
+                Some(TorEvent::Log { severity: keyword.to_string(), message })
+            }
+            _ => None,
+        };
+
+        Some(event.unwrap_or_else(|| TorEvent::Other {
+            keyword: keyword.to_string(),
+            text: rest.to_string(),
+        }))
+    }
+}
+
+impl StatusEvent {
+    pub fn parse(text: &str) -> Option<Self> {
+        let (words, args) = parse_args(text);
+        let mut words = words.into_iter();
+        Some(Self {
+            severity: words.next()?,
+            action: words.next()?,
+            args,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::CircuitStatus;
+    use crate::reply::ReplyParser;
+
+    const FP: &str = "$0123456789ABCDEF0123456789ABCDEF01234567~relay";
+
+    /// The event carried by the reply that `lines` complete.
+    fn parse(lines: &[&str]) -> TorEvent {
+        let mut parser = ReplyParser::new();
+        let reply =
+            lines.iter().find_map(|line| parser.feed(line).expect("valid reply line")).expect("a complete reply");
+        TorEvent::parse(&reply).expect("an event")
+    }
+
+    fn other(event: TorEvent) -> (String, String) {
+        match event {
+            TorEvent::Other { keyword, text } => (keyword, text),
+            event => panic!("expected an unmodelled event, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn parses_circuit_events() {
+        let line = format!("650 CIRC 12 BUILT {} BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL", FP);
+        let TorEvent::Circ(circuit) = parse(&[&line]) else {
+            panic!("expected a CIRC event");
+        };
+        assert_eq!(circuit.id, "12");
+        assert_eq!(circuit.status, CircuitStatus::Built);
+        assert_eq!(circuit.path.len(), 1);
+        assert!(circuit.is_usable());
+
+        assert_eq!(other(parse(&["650 CIRC"])), ("CIRC".to_string(), String::new()));
+    }
+
+    #[test]
+    fn parses_stream_and_connection_events() {
+        let TorEvent::Stream(stream) = parse(&["650 STREAM 45 FAILED 12 example.com:443 REASON=TIMEOUT"]) else {
+            panic!("expected a STREAM event");
+        };
+        assert_eq!(
+            (stream.id.as_str(), stream.status.as_str(), stream.circuit_id.as_str(), stream.target.as_str()),
+            ("45", "FAILED", "12", "example.com:443")
+        );
+        assert_eq!(stream.reason.as_deref(), Some("TIMEOUT"));
+        let TorEvent::Stream(stream) = parse(&["650 STREAM 46 NEW 0 192.0.2.1:80 SOURCE_ADDR=127.0.0.1:5000"]) else {
+            panic!("expected a STREAM event");
+        };
+        assert_eq!(stream.reason, None);
+        assert_eq!(other(parse(&["650 STREAM 47 NEW"])).0, "STREAM");
+
+        let TorEvent::OrConn(conn) = parse(&[&format!("650 ORCONN {} CLOSED REASON=DONE", FP)]) else {
+            panic!("expected an ORCONN event");
+        };
+        assert_eq!((conn.status.as_str(), conn.reason.as_deref()), ("CLOSED", Some("DONE")));
+        assert_eq!(other(parse(&["650 ORCONN"])).0, "ORCONN");
+    }
+
+    #[test]
+    fn parses_bandwidth_events() {
+        assert!(matches!(parse(&["650 BW 1024 2048"]), TorEvent::Bandwidth { read: 1024, written: 2048 }));
+        for line in ["650 BW", "650 BW 1024", "650 BW 1024 lots", "650 BW -1 2"] {
+            assert_eq!(other(parse(&[line])).0, "BW", "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_status_events() {
+        let TorEvent::StatusClient(status) =
+            parse(&[r#"650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY="Done""#])
+        else {
+            panic!("expected a STATUS_CLIENT event");
+        };
+        assert_eq!((status.severity.as_str(), status.action.as_str()), ("NOTICE", "BOOTSTRAP"));
+        assert_eq!(status.args["PROGRESS"], "100");
+        assert_eq!(status.args["SUMMARY"], "Done");
+
+        assert!(matches!(
+            parse(&["650 STATUS_GENERAL WARN CLOCK_SKEW SKEW=-120"]),
+            TorEvent::StatusGeneral(status) if status.action == "CLOCK_SKEW"
+        ));
+        assert_eq!(other(parse(&["650 STATUS_CLIENT NOTICE"])).0, "STATUS_CLIENT");
+    }
+
+    #[test]
+    fn parses_descriptor_and_guard_events() {
+        let TorEvent::NewDesc(relays) = parse(&[&format!("650 NEWDESC {} $ABCD~other", FP)]) else {
+            panic!("expected a NEWDESC event");
+        };
+        assert_eq!(relays, [FP, "$ABCD~other"]);
+
+        let TorEvent::Guard(guard) = parse(&[&format!("650 GUARD ENTRY {} GOOD_L", FP)]) else {
+            panic!("expected a GUARD event");
+        };
+        assert_eq!((guard.kind.as_str(), guard.name.as_str(), guard.status.as_str()), ("ENTRY", FP, "GOOD_L"));
+        assert_eq!(other(parse(&["650 GUARD ENTRY"])).0, "GUARD");
+    }
+
+    #[test]
+    fn parses_log_events() {
+        let TorEvent::Log { severity, message } =
+            parse(&["650 NOTICE Rate limiting NEWNYM request: delaying by 8 second(s)"])
+        else {
+            panic!("expected a log event");
+        };
+        assert_eq!(severity, "NOTICE");
+        assert_eq!(message, "Rate limiting NEWNYM request: delaying by 8 second(s)");
+
+        let TorEvent::Log { severity, message } = parse(&["650+WARN", "first line", "..dotted", ".", "650 OK"]) else {
+            panic!("expected a log event");
+        };
+        assert_eq!(severity, "WARN");
+        assert_eq!(message, "first line\n.dotted");
+    }
+
+    #[test]
+    fn keeps_unknown_events() {
+        assert_eq!(
+            other(parse(&["650 ADDRMAP example.com 192.0.2.1 NEVER"])),
+            ("ADDRMAP".to_string(), "example.com 192.0.2.1 NEVER".to_string())
+        );
+        assert_eq!(other(parse(&["650 SIGNAL"])), ("SIGNAL".to_string(), String::new()));
+    }
+}
@@ -0,0 +1,163 @@
+//! Exit country selection through Tor's `ExitNodes`/`ExcludeExitNodes`.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use tracing::info;
+
+use crate::control::TorControl;
+use crate::reply::quote;
+
+/// Options we touch; their original values are restored on shutdown.
+const MANAGED_OPTIONS: &[&str] = &["ExitNodes", "ExcludeExitNodes", "StrictNodes"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExitSelection {
+    /// Lower-case ISO country codes exits must be in (empty: any).
+    pub countries: Vec<String>,
+    /// Lower-case ISO country codes exits must not be in.
+    pub excluded: Vec<String>,
+}
+
+impl ExitSelection {
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty() && self.excluded.is_empty()
+    }
+
+    /// Whether an exit in `country` satisfies this selection.
+    pub fn allows(&self, country: &str) -> bool {
+        let country = country.to_ascii_lowercase();
+        (self.countries.is_empty() || self.countries.contains(&country))
+            && !self.excluded.contains(&country)
+    }
+}
+
+impl fmt::Display for ExitSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.countries.is_empty(), self.excluded.is_empty()) {
+            (true, true) => write!(f, "any country"),
+            (false, true) => write!(f, "{}", self.countries.join(",")),
+            (true, false) => write!(f, "any except {}", self.excluded.join(",")),
+            (false, false) => write!(
+                f,
+                "{} except {}",
+                self.countries.join(","),
+                self.excluded.join(",")
+            ),
+        }
+    }
+}
+
+/// Parses a comma separated list of two-letter country codes.
+pub fn parse_countries(s: &str) -> Result<Vec<String>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|cc| !cc.is_empty())
+        .map(|cc| {
+            if cc.len() == 2 && cc.chars().all(|c| c.is_ascii_alphabetic()) {
+                Ok(cc.to_ascii_lowercase())
+            } else {
+                Err(anyhow!("Invalid country code {:?} (expected e.g. de,nl)", cc))
+            }
+        })
+        .collect()
+}
+
+fn node_set(countries: &[String]) -> String {
+    countries
+        .iter()
+        .map(|cc| format!("{{{}}}", cc))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Applies exit selections and remembers Tor's original settings.
+pub struct ExitManager {
+    control: TorControl,
+    /// `None` values were unset (default) before we changed them.
+    saved: Option<Vec<(String, Option<String>)>>,
+}
+
+impl ExitManager {
+    pub fn new(control: TorControl) -> Self {
+        Self { control, saved: None }
+    }
+
+    pub async fn apply(&mut self, selection: &ExitSelection) -> Result<()> {
+        if self.saved.is_none() {
+            self.saved = Some(self.current_values().await?);
+        }
+        let saved = self.saved.as_deref().unwrap_or_default();
+        let original = |key: &str| {
+            saved
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(k, v)| setting(k, v.as_deref()))
+                .unwrap_or_else(|| key.to_string())
+        };
+
+        let mut settings = Vec::new();
+        if selection.countries.is_empty() {
+            settings.push(original("ExitNodes"));
+            settings.push(original("StrictNodes"));
+        } else {
+            settings.push(setting("ExitNodes", Some(&node_set(&selection.countries))));
+            settings.push("StrictNodes=1".to_string());
+        }
+        if selection.excluded.is_empty() {
+            settings.push(original("ExcludeExitNodes"));
+        } else {
+            settings.push(setting("ExcludeExitNodes", Some(&node_set(&selection.excluded))));
+        }
+
+        self.control
+            .command(&format!("SETCONF {}", settings.join(" ")))
+            .await?;
+        info!("Exit selection set to {}", selection);
+        Ok(())
+    }
+
+    /// Puts back whatever Tor had configured before our first change.
+    pub async fn restore(&mut self) -> Result<()> {
+        let Some(saved) = self.saved.take() else {
+            return Ok(());
+        };
+        let settings: Vec<_> = saved
+            .iter()
+            .map(|(key, value)| setting(key, value.as_deref()))
+            .collect();
+        self.control
+            .command(&format!("SETCONF {}", settings.join(" ")))
+            .await?;
+        info!("Restored Tor exit configuration");
+        Ok(())
+    }
+
+    async fn current_values(&self) -> Result<Vec<(String, Option<String>)>> {
+        let reply = self
+            .control
+            .command(&format!("GETCONF {}", MANAGED_OPTIONS.join(" ")))
+            .await?;
+        Ok(reply
+            .lines
+            .iter()
+            .filter_map(|line| {
+                let (key, value) = match line.text.split_once('=') {
+                    Some((key, value)) => (key, Some(value.to_string())),
+                    None => (line.text.as_str(), None),
+                };
+                MANAGED_OPTIONS
+                    .iter()
+                    .find(|o| o.eq_ignore_ascii_case(key))
+                    .map(|o| (o.to_string(), value))
+            })
+            .collect())
+    }
+}
+
+/// `Key=value` for SETCONF, or the bare key to reset it to Tor's default.
+fn setting(key: &str, value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("{}={}", key, quote(value)),
+        None => key.to_string(),
+    }
+}
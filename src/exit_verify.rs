@@ -0,0 +1,293 @@
+//! Checking locally that an exit IP belongs to Tor, instead of asking
+//! check.torproject.org: it should be the address of the exit relay of one
+//! of our built circuits, or at least of a relay the consensus lists with
+//! the Exit flag.
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::config::Tuning;
+use crate::control::TorControl;
+use crate::metrics::Metrics;
+use crate::relay::RelayInfo;
+
+/// A consensus is valid for an hour; fetch a fresh one after that.
+const CONSENSUS_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitVerdict {
+    /// The exit relay of one of our built circuits has this address.
+    CircuitExit { nickname: String },
+    /// A consensus relay with the Exit flag has this address, though none
+    /// of our built circuits ends there (they may have changed since).
+    ConsensusExit { nickname: String },
+    /// No exit relay has this address. An exit using a different outbound
+    /// address than the one it advertises looks like this, and so does
+    /// traffic that doesn't go through Tor at all.
+    Mismatch {
+        observed: IpAddr,
+        /// Where our built circuits should have left Tor.
+        circuit_exits: Vec<(String, IpAddr)>,
+        /// A relay without the Exit flag that has the address.
+        relay: Option<String>,
+    },
+}
+
+impl ExitVerdict {
+    /// The address is known to be a Tor exit.
+    pub fn is_verified(&self) -> bool {
+        !matches!(self, ExitVerdict::Mismatch { .. })
+    }
+}
+
+impl fmt::Display for ExitVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitVerdict::CircuitExit { nickname } => write!(f, "exit relay {} of a built circuit", nickname),
+            ExitVerdict::ConsensusExit { nickname } => {
+                write!(f, "consensus exit {}, not in a built circuit", nickname)
+            }
+            ExitVerdict::Mismatch {
+                observed,
+                circuit_exits,
+                relay,
+            } => {
+                write!(f, "{} is not the address of any consensus exit", observed)?;
+                if let Some(relay) = relay {
+                    write!(f, " (relay {} has it, without the Exit flag)", relay)?;
+                }
+                if circuit_exits.is_empty() {
+                    write!(f, "; no built circuits")
+                } else {
+                    let exits: Vec<String> = circuit_exits
+                        .iter()
+                        .map(|(nickname, address)| format!("{} at {}", nickname, address))
+                        .collect();
+                    write!(f, "; our circuits exit through {}", exits.join(", "))
+                }
+            }
+        }
+    }
+}
+
+/// Consensus relays by address: nickname and whether it has the Exit flag.
+type Relays = HashMap<IpAddr, (String, bool)>;
+
+/// Verifies exit IPs against one Tor instance's circuits and consensus.
+pub struct ExitVerifier {
+    control: TorControl,
+    consensus: Mutex<Option<(Instant, Arc<Relays>)>>,
+}
+
+impl ExitVerifier {
+    pub fn new(control: TorControl) -> Self {
+        Self {
+            control,
+            consensus: Mutex::new(None),
+        }
+    }
+
+    /// Whether we can have been seen as `ip` through this Tor.
+    pub async fn verify(&self, ip: IpAddr) -> Result<ExitVerdict> {
+        let mut circuit_exits = Vec::new();
+        for circuit in self.control.get_circuit_info().await? {
+            let Some(exit) = circuit.path.last().filter(|_| circuit.is_usable()) else {
+                continue;
+            };
+            match self.control.relay_info(&exit.fingerprint).await {
+                Ok(relay) if relay.address == ip => {
+                    return Ok(ExitVerdict::CircuitExit {
+                        nickname: relay.nickname,
+                    })
+                }
+                Ok(relay) => {
+                    let exit = (relay.nickname, relay.address);
+                    if !circuit_exits.contains(&exit) {
+                        circuit_exits.push(exit);
+                    }
+                }
+                Err(e) => debug!("Failed to look up exit {}: {}", exit.fingerprint, e),
+            }
+        }
+
+        let relays = self.relays().await?;
+        Ok(match relays.get(&ip) {
+            Some((nickname, true)) => ExitVerdict::ConsensusExit {
+                nickname: nickname.clone(),
+            },
+            relay => ExitVerdict::Mismatch {
+                observed: ip,
+                circuit_exits,
+                relay: relay.map(|(nickname, _)| nickname.clone()),
+            },
+        })
+    }
+
+    /// The cached consensus, fetched again once it's an hour old.
+    async fn relays(&self) -> Result<Arc<Relays>> {
+        let mut cached = self.consensus.lock().await;
+        if let Some((fetched, relays)) = cached.as_ref() {
+            if fetched.elapsed() < CONSENSUS_TTL {
+                return Ok(relays.clone());
+            }
+        }
+        let relays = Arc::new(parse_consensus(&self.control.network_status().await?));
+        debug!("Loaded {} relay addresses from the consensus", relays.len());
+        *cached = Some((Instant::now(), relays.clone()));
+        Ok(relays)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TorCheckResponse {
+    #[serde(rename = "IsTor")]
+    is_tor: bool,
+}
+
+/// Checks whether `ip` is a Tor exit: against the circuits and consensus of
+/// `verifier`'s Tor, then with the Tor check service if one is configured
+/// and the consensus can't vouch for the address. Errors only when neither
+/// could tell.
+pub async fn is_tor_exit(
+    ip: IpAddr,
+    client: &reqwest::Client,
+    verifier: Option<&ExitVerifier>,
+    tuning: &Tuning,
+    metrics: &Metrics,
+) -> Result<bool> {
+    let mut local_error = None;
+    if let Some(verifier) = verifier {
+        match verifier.verify(ip).await {
+            Ok(verdict) if verdict.is_verified() => {
+                debug!("Verified exit {}: {}", ip, verdict);
+                return Ok(true);
+            }
+            Ok(verdict) => warn!("Exit IP mismatch: {}", verdict),
+            Err(e) => {
+                warn!("Failed to verify exit IP against the consensus: {:#}", e);
+                local_error = Some(e);
+            }
+        }
+    }
+
+    let Some(tor_check_url) = &tuning.tor_check_url else {
+        return match local_error {
+            Some(e) => Err(e.context("Failed to verify exit IP against the consensus")),
+            None => Ok(false),
+        };
+    };
+    let started = Instant::now();
+    let tor_check = client
+        .get(tor_check_url)
+        .timeout(tuning.check_timeout)
+        .send()
+        .await;
+    metrics.record_ip_check(tor_check_url, started.elapsed(), tor_check.is_ok());
+    let tor_check = tor_check.map_err(|e| anyhow!("Failed to connect to Tor check service: {}", e))?;
+
+    match tor_check.json::<TorCheckResponse>().await {
+        Ok(response) => {
+            debug!("Tor check service says {} is{} Tor", ip, if response.is_tor { "" } else { " not" });
+            Ok(response.is_tor)
+        }
+        Err(e) => {
+            warn!("Failed to parse Tor check response: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+/// Indexes router status entries by their IPv4 address and any IPv6
+/// addresses from their `a` lines. Where relays share an address, an exit
+/// wins.
+fn parse_consensus(lines: &[String]) -> Relays {
+    let mut relays = Relays::new();
+    let starts: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.starts_with("r "))
+        .map(|(index, _)| index)
+        .collect();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(lines.len());
+        let entry = &lines[start..end];
+        let relay = match RelayInfo::parse("consensus entry", entry) {
+            Ok(relay) => relay,
+            Err(e) => {
+                debug!("Skipping consensus entry: {}", e);
+                continue;
+            }
+        };
+        let is_exit = relay.flags.iter().any(|flag| flag == "Exit");
+        let ipv6 = entry
+            .iter()
+            .filter_map(|line| line.strip_prefix("a "))
+            .filter_map(|address| address.parse::<SocketAddr>().ok())
+            .map(|address| address.ip());
+        for address in std::iter::once(relay.address).chain(ipv6) {
+            match relays.get(&address) {
+                Some((_, true)) => {}
+                Some(_) if !is_exit => {}
+                _ => {
+                    relays.insert(address, (relay.nickname.clone(), is_exit));
+                }
+            }
+        }
+    }
+    relays
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder().no_proxy().build().unwrap()
+    }
+
+    /// A Tor without circuits or consensus, so every address is a mismatch.
+    async fn clueless_verifier() -> ExitVerifier {
+        ExitVerifier::new(testutil::fake_control(|_| "250 OK".to_string()).await)
+    }
+
+    #[tokio::test]
+    async fn asks_the_tor_check_service_on_a_mismatch() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let verifier = clueless_verifier().await;
+        let metrics = Metrics::default();
+        let (ip_provider, _) = testutil::http_server("200 OK", "192.0.2.1").await;
+
+        let tuning = testutil::tuning(&ip_provider, None);
+        assert!(!is_tor_exit(ip, &client(), Some(&verifier), &tuning, &metrics).await.unwrap());
+
+        for (body, expected) in [(r#"{"IsTor": true}"#, true), (r#"{"IsTor": false}"#, false), ("<html>", false)] {
+            let (tor_check, hits) = testutil::http_server("200 OK", body).await;
+            let tuning = testutil::tuning(&ip_provider, Some(tor_check));
+            let is_tor = is_tor_exit(ip, &client(), Some(&verifier), &tuning, &metrics).await.unwrap();
+            assert_eq!(is_tor, expected, "{}", body);
+            assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_when_nothing_can_tell() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let (ip_provider, _) = testutil::http_server("200 OK", "192.0.2.1").await;
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", listener.local_addr().unwrap())
+        };
+        let tuning = testutil::tuning(&ip_provider, Some(unreachable));
+        let verifier = clueless_verifier().await;
+        let result = is_tor_exit(ip, &client(), Some(&verifier), &tuning, &Metrics::default()).await;
+        assert!(result.is_err());
+    }
+}
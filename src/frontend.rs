@@ -0,0 +1,98 @@
+//! Accept loop shared by the proxy frontends.
+
+use anyhow::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::killswitch::KillSwitch;
+use crate::shutdown::{self, Shutdown};
+
+/// Accepts connections on `listener` and runs `handle` on each until
+/// shutdown, then waits for the open ones to finish. While the kill switch
+/// is engaged nothing is accepted.
+pub async fn serve<H, F>(
+    frontend: &'static str,
+    mut listener: TcpListener,
+    kill_switch: &KillSwitch,
+    shutdown: Shutdown,
+    handle: H,
+) where
+    H: Fn(TcpStream, SocketAddr) -> F,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            // An engaged kill switch wins over a pending connection
+            biased;
+            _ = kill_switch.engaged() => {
+                match kill_switch.hold(frontend, listener, &mut connections, &shutdown).await {
+                    Some(reopened) => listener = reopened,
+                    None => return,
+                }
+                continue;
+            }
+            _ = shutdown.requested() => break,
+            accepted = listener.accept() => accepted,
+            // Reap finished connections as we go
+            Some(_) = connections.join_next() => continue,
+        };
+        let (client, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept {} connection: {}", frontend, e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let connection = handle(client, peer);
+        connections.spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("{} connection from {} ended: {}", frontend, peer, e);
+            }
+        });
+    }
+    drop(listener);
+    info!("{} frontend stopped accepting connections", frontend);
+    shutdown::drain(frontend, connections).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn accepts_nothing_while_the_kill_switch_is_engaged() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            // Queued before the loop starts, so accept and the kill switch
+            // are both ready on its first turn
+            let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let kill_switch = KillSwitch::new(["tor0".to_string()], None);
+            let shutdown = Shutdown::default();
+            let handled = handled.clone();
+            let frontend = tokio::spawn({
+                let shutdown = shutdown.clone();
+                async move {
+                    serve("test", listener, &kill_switch, shutdown, |_, _| {
+                        handled.fetch_add(1, Ordering::SeqCst);
+                        async { Ok(()) }
+                    })
+                    .await
+                }
+            });
+            time::sleep(Duration::from_millis(10)).await;
+            shutdown.request();
+            frontend.await.unwrap();
+        }
+        assert_eq!(handled.load(Ordering::SeqCst), 0);
+    }
+}
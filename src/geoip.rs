@@ -0,0 +1,591 @@
+//! Locating IP addresses offline, from Tor's `geoip`/`geoip6` range files
+//! and MaxMind `.mmdb` databases, so checking the exit doesn't tell a third
+//! party every address we rotate through. A remote lookup service is only
+//! asked when it is selected explicitly, and only for addresses the offline
+//! databases don't know.
+
+use anyhow::{anyhow, Context, Result};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+
+/// Where the tor package installs its GeoIP files.
+pub const TOR_GEOIP: &str = "/usr/share/tor/geoip";
+pub const TOR_GEOIP6: &str = "/usr/share/tor/geoip6";
+
+/// Where to look an address up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GeoSource {
+    /// The MaxMind databases given with --geoip-mmdb.
+    Maxmind,
+    /// Tor's geoip and geoip6 files.
+    Tor,
+    /// The --geo-url service, for addresses the others don't know.
+    Remote,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct GeoInfo {
+    pub country_name: Option<String>,
+    /// Two-letter country code, upper case.
+    pub country_code: Option<String>,
+    pub city: Option<String>,
+    pub region: Option<String>,
+    /// Autonomous system, e.g. `AS24940`.
+    pub asn: Option<String>,
+    /// Organisation owning the autonomous system.
+    pub org: Option<String>,
+}
+
+impl GeoInfo {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Takes the fields we don't know yet from `other`.
+    fn fill(&mut self, other: GeoInfo) {
+        let fill = |ours: &mut Option<String>, theirs: Option<String>| {
+            if ours.is_none() {
+                *ours = theirs;
+            }
+        };
+        fill(&mut self.country_name, other.country_name);
+        fill(&mut self.country_code, other.country_code);
+        fill(&mut self.city, other.city);
+        fill(&mut self.region, other.region);
+        fill(&mut self.asn, other.asn);
+        fill(&mut self.org, other.org);
+    }
+}
+
+/// An offline database mapping addresses to locations.
+pub trait GeoDatabase: fmt::Display + Send + Sync {
+    /// What the database knows about `ip`, `None` if nothing.
+    fn locate(&self, ip: IpAddr) -> Option<GeoInfo>;
+
+    /// When the file was last modified, so a reload can tell it changed.
+    fn modified(&self) -> Option<SystemTime>;
+}
+
+/// Tor's GeoIP files: `INTIPLOW,INTIPHIGH,CC` lines for IPv4 and
+/// `IPV6LOW,IPV6HIGH,CC` lines for IPv6, sorted by range.
+pub struct TorGeoIp {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    v4: Vec<(u32, u32, [u8; 2])>,
+    v6: Vec<(u128, u128, [u8; 2])>,
+}
+
+impl TorGeoIp {
+    /// Reads either file or both.
+    pub fn load(geoip: Option<&Path>, geoip6: Option<&Path>) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        if let Some(path) = geoip {
+            v4 = parse_ranges(path, |s| {
+                s.parse::<u32>().ok().or_else(|| s.parse::<Ipv4Addr>().ok().map(u32::from))
+            })?;
+            files.push((path.to_path_buf(), modified(path)));
+        }
+        if let Some(path) = geoip6 {
+            v6 = parse_ranges(path, |s| s.parse::<Ipv6Addr>().ok().map(u128::from))?;
+            files.push((path.to_path_buf(), modified(path)));
+        }
+        debug!("Loaded {} IPv4 and {} IPv6 ranges from Tor's GeoIP files", v4.len(), v6.len());
+        Ok(Self { files, v4, v6 })
+    }
+}
+
+impl GeoDatabase for TorGeoIp {
+    fn locate(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let country = match ip {
+            IpAddr::V4(ip) => find_range(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => find_range(&self.v4, u32::from(ip)),
+                None => find_range(&self.v6, u128::from(ip)),
+            },
+        }?;
+        Some(GeoInfo {
+            country_code: Some(String::from_utf8_lossy(&country).into_owned()),
+            ..GeoInfo::default()
+        })
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.files.iter().filter_map(|(_, modified)| *modified).max()
+    }
+}
+
+impl fmt::Display for TorGeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paths: Vec<String> = self.files.iter().map(|(path, _)| path.display().to_string()).collect();
+        write!(f, "tor:{}", paths.join(","))
+    }
+}
+
+/// Reads a Tor GeoIP file into ranges sorted by their start, skipping
+/// comments and addresses Tor doesn't know the country of (`??`).
+fn parse_ranges<T: Ord + Copy>(path: &Path, parse: impl Fn(&str) -> Option<T>) -> Result<Vec<(T, T, [u8; 2])>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read GeoIP file {}", path.display()))?;
+    let mut ranges = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || anyhow!("Invalid line {} in {}: {:?}", number + 1, path.display(), line);
+        let mut fields = line.split(',').map(|field| field.trim().trim_matches('"'));
+        let (Some(low), Some(high), Some(country), None) = (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let (Some(low), Some(high)) = (parse(low), parse(high)) else {
+            return Err(invalid());
+        };
+        let country: [u8; 2] = match country.as_bytes() {
+            b"??" => continue,
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                [a.to_ascii_uppercase(), b.to_ascii_uppercase()]
+            }
+            _ => return Err(invalid()),
+        };
+        if low > high {
+            return Err(invalid());
+        }
+        ranges.push((low, high, country));
+    }
+    ranges.sort_unstable_by_key(|&(low, _, _)| low);
+    Ok(ranges)
+}
+
+fn find_range<T: Ord + Copy>(ranges: &[(T, T, [u8; 2])], ip: T) -> Option<[u8; 2]> {
+    let after = ranges.partition_point(|&(low, _, _)| low <= ip);
+    let &(_, high, country) = ranges[..after].last()?;
+    (ip <= high).then_some(country)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// A MaxMind GeoIP2/GeoLite2 database: country, city or ASN.
+pub struct MaxMindDb {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    reader: Reader<Vec<u8>>,
+    /// An ASN database, which has no locations.
+    asn: bool,
+}
+
+impl MaxMindDb {
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| anyhow!("Failed to open MaxMind database {}: {}", path.display(), e))?;
+        let asn = reader.metadata.database_type.contains("ASN");
+        debug!("Opened {} database {}", reader.metadata.database_type, path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            reader,
+            asn,
+        })
+    }
+
+    fn lookup<'a, T: Deserialize<'a>>(&'a self, ip: IpAddr) -> Option<T> {
+        match self.reader.lookup(ip) {
+            Ok(record) => Some(record),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+            Err(e) => {
+                debug!("Failed to look up {} in {}: {}", ip, self.path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+impl GeoDatabase for MaxMindDb {
+    fn locate(&self, ip: IpAddr) -> Option<GeoInfo> {
+        if self.asn {
+            let record: geoip2::Asn = self.lookup(ip)?;
+            return Some(GeoInfo {
+                asn: record.autonomous_system_number.map(|number| format!("AS{}", number)),
+                org: record.autonomous_system_organization.map(str::to_string),
+                ..GeoInfo::default()
+            });
+        }
+
+        // Country databases have the same layout without the city fields
+        let record: geoip2::City = self.lookup(ip)?;
+        let english = |names: Option<&BTreeMap<&str, &str>>| {
+            names.and_then(|names| names.get("en")).map(|name| name.to_string())
+        };
+        let country = record.country.as_ref();
+        Some(GeoInfo {
+            country_name: english(country.and_then(|c| c.names.as_ref())),
+            country_code: country.and_then(|c| c.iso_code).map(str::to_uppercase),
+            city: english(record.city.as_ref().and_then(|c| c.names.as_ref())),
+            region: english(
+                record
+                    .subdivisions
+                    .as_ref()
+                    .and_then(|s| s.first())
+                    .and_then(|s| s.names.as_ref()),
+            ),
+            ..GeoInfo::default()
+        })
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+}
+
+impl fmt::Display for MaxMindDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "maxmind:{}", self.path.display())
+    }
+}
+
+/// The selected databases asked in order, each filling in what the ones
+/// before didn't know, plus the remote service if it was selected.
+pub struct Geolocator {
+    databases: Vec<Box<dyn GeoDatabase>>,
+    /// Lookup URL with `{ip}` where the address goes.
+    remote: Option<String>,
+    timeout: Duration,
+}
+
+impl Geolocator {
+    pub fn new(databases: Vec<Box<dyn GeoDatabase>>, remote: Option<String>, timeout: Duration) -> Self {
+        Self {
+            databases,
+            remote,
+            timeout,
+        }
+    }
+
+    /// Nothing to look addresses up in.
+    pub fn is_empty(&self) -> bool {
+        self.databases.is_empty() && self.remote.is_none()
+    }
+
+    /// Where `ip` is. The remote service is asked through `client`, and
+    /// only if no database knows the country.
+    pub async fn locate(&self, ip: IpAddr, client: &reqwest::Client, metrics: &Metrics) -> Option<GeoInfo> {
+        let mut info = GeoInfo::default();
+        for database in &self.databases {
+            if let Some(found) = database.locate(ip) {
+                info.fill(found);
+            }
+        }
+        if info.country_code.is_none() {
+            if let Some(url) = &self.remote {
+                if let Some(found) = self.locate_remotely(url, ip, client, metrics).await {
+                    info.fill(found);
+                }
+            }
+        }
+        Some(info).filter(|info| !info.is_empty())
+    }
+
+    async fn locate_remotely(
+        &self,
+        url: &str,
+        ip: IpAddr,
+        client: &reqwest::Client,
+        metrics: &Metrics,
+    ) -> Option<GeoInfo> {
+        let started = Instant::now();
+        let response = client
+            .get(url.replace("{ip}", &ip.to_string()))
+            .timeout(self.timeout)
+            .send()
+            .await;
+        metrics.record_geo_lookup(url, started.elapsed(), response.is_ok());
+        match response {
+            Ok(response) => match response.json::<GeoInfo>().await {
+                Ok(info) => Some(info),
+                Err(e) => {
+                    warn!("Failed to parse location info: {}", e);
+                    None
+                }
+            },
+            Err(e) => {
+                warn!("Failed to fetch location info: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Geolocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let databases: Vec<String> = self.databases.iter().map(ToString::to_string).collect();
+        f.debug_struct("Geolocator")
+            .field("databases", &databases)
+            .field("remote", &self.remote)
+            .finish()
+    }
+}
+
+/// Geolocators are equal when they use the same files, unchanged since
+/// they were read, and the same remote service.
+impl PartialEq for Geolocator {
+    fn eq(&self, other: &Self) -> bool {
+        self.remote == other.remote
+            && self.timeout == other.timeout
+            && self.databases.len() == other.databases.len()
+            && self
+                .databases
+                .iter()
+                .zip(&other.databases)
+                .all(|(ours, theirs)| ours.to_string() == theirs.to_string() && ours.modified() == theirs.modified())
+    }
+}
+
+impl Eq for Geolocator {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn parse_v4(path: &Path) -> Result<Vec<(u32, u32, [u8; 2])>> {
+        parse_ranges(path, |s| s.parse::<u32>().ok().or_else(|| s.parse::<Ipv4Addr>().ok().map(u32::from)))
+    }
+
+    #[test]
+    fn parse_ranges_sorts_and_skips_unknown_countries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(
+            dir.path(),
+            "geoip",
+            "# Last updated based on ...\n\n300,399,\"fr\"\n100,199,DE\n200,299,??\n 0.0.1.144 , 0.0.1.200 ,US\n",
+        );
+        let ranges = parse_v4(&path).unwrap();
+        assert_eq!(ranges, vec![(100, 199, *b"DE"), (300, 399, *b"FR"), (400, 456, *b"US")]);
+    }
+
+    #[test]
+    fn parse_ranges_rejects_malformed_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        for line in ["1,2", "1,2,DE,x", "x,2,DE", "1,,DE", "2,1,DE", "1,2,D1", "1,2,DEU", "1,2,"] {
+            let path = write(dir.path(), "geoip", &format!("# header\n{}\n", line));
+            let err = parse_v4(&path).unwrap_err().to_string();
+            assert!(err.contains("Invalid line 2"), "{:?}: {}", line, err);
+        }
+        assert!(parse_v4(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn find_range_checks_both_ends() {
+        let ranges = [(10u32, 20u32, *b"AA"), (30, 40, *b"BB"), (41, 41, *b"CC")];
+        assert_eq!(find_range(&ranges, 9), None);
+        assert_eq!(find_range(&ranges, 10), Some(*b"AA"));
+        assert_eq!(find_range(&ranges, 15), Some(*b"AA"));
+        assert_eq!(find_range(&ranges, 20), Some(*b"AA"));
+        assert_eq!(find_range(&ranges, 21), None);
+        assert_eq!(find_range(&ranges, 29), None);
+        assert_eq!(find_range(&ranges, 30), Some(*b"BB"));
+        assert_eq!(find_range(&ranges, 40), Some(*b"BB"));
+        assert_eq!(find_range(&ranges, 41), Some(*b"CC"));
+        assert_eq!(find_range(&ranges, 42), None);
+        assert_eq!(find_range(&ranges, u32::MAX), None);
+        assert_eq!(find_range::<u32>(&[], 10), None);
+    }
+
+    #[test]
+    fn tor_geoip_locates_v4_v6_and_mapped_addresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let geoip = write(dir.path(), "geoip", "16909056,16909311,DE\n");
+        let geoip6 = write(dir.path(), "geoip6", "2001:db8::,2001:db8::ffff,NL\n");
+        let db = TorGeoIp::load(Some(&geoip), Some(&geoip6)).unwrap();
+
+        let country = |ip: &str| db.locate(ip.parse().unwrap()).and_then(|info| info.country_code);
+        assert_eq!(country("1.2.3.4").as_deref(), Some("DE"));
+        assert_eq!(country("::ffff:1.2.3.4").as_deref(), Some("DE"));
+        assert_eq!(country("2001:db8::1").as_deref(), Some("NL"));
+        assert_eq!(country("1.2.4.0"), None);
+        assert_eq!(country("::ffff:1.2.4.0"), None);
+        assert_eq!(country("2001:db8::1:0"), None);
+        assert!(db.modified().is_some());
+        assert_eq!(db.to_string(), format!("tor:{},{}", geoip.display(), geoip6.display()));
+    }
+
+    // Just enough of the MaxMind DB format to build a database in a test:
+    // https://maxmind.github.io/MaxMind-DB/
+    fn field(kind: u8, size: usize) -> Vec<u8> {
+        // Sizes from 29 on take an extra byte
+        let (size, extra) = match size {
+            0..=28 => (size as u8, None),
+            29..=284 => (29, Some((size - 29) as u8)),
+            _ => panic!("{} bytes is too long for a test record", size),
+        };
+        let mut out = match kind {
+            ..=7 => vec![kind << 5 | size],
+            _ => vec![size, kind - 7],
+        };
+        out.extend(extra);
+        out
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        [field(2, s.len()), s.as_bytes().to_vec()].concat()
+    }
+
+    fn uint(kind: u8, value: u64) -> Vec<u8> {
+        let bytes: Vec<u8> = value.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect();
+        [field(kind, bytes.len()), bytes].concat()
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = field(7, entries.len());
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend(value);
+        }
+        out
+    }
+
+    fn array(items: &[Vec<u8>]) -> Vec<u8> {
+        [field(11, items.len()), items.concat()].concat()
+    }
+
+    /// An IPv4 database with 24-bit records mapping each network to its
+    /// encoded data.
+    fn mmdb(database_type: &str, networks: &[(&str, u32, Vec<u8>)]) -> Vec<u8> {
+        #[derive(Clone, Copy)]
+        enum Record {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+        let mut nodes = vec![[Record::Empty; 2]];
+        let mut data: Vec<u8> = Vec::new();
+        for (network, prefix, record) in networks {
+            let bits = u32::from(network.parse::<Ipv4Addr>().unwrap());
+            let mut node = 0;
+            for i in 0..*prefix {
+                let bit = (bits >> (31 - i) & 1) as usize;
+                if i + 1 == *prefix {
+                    nodes[node][bit] = Record::Data(data.len());
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Record::Node(next) => next,
+                    _ => {
+                        nodes.push([Record::Empty; 2]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+            data.extend(record);
+        }
+
+        let node_count = nodes.len();
+        let mut out = Vec::new();
+        for record in nodes.iter().flatten() {
+            let value = match *record {
+                Record::Empty => node_count,
+                Record::Node(next) => next,
+                Record::Data(offset) => node_count + 16 + offset,
+            };
+            out.extend(&(value as u32).to_be_bytes()[1..]);
+        }
+        out.extend([0; 16]);
+        out.extend(data);
+        out.extend(b"\xab\xcd\xefMaxMind.com");
+        out.extend(map(&[
+            ("node_count", uint(6, node_count as u64)),
+            ("record_size", uint(5, 24)),
+            ("ip_version", uint(5, 4)),
+            ("database_type", string(database_type)),
+            ("languages", array(&[string("en")])),
+            ("binary_format_major_version", uint(5, 2)),
+            ("binary_format_minor_version", uint(5, 0)),
+            ("build_epoch", uint(9, 1_700_000_000)),
+            ("description", map(&[("en", string("test"))])),
+        ]));
+        out
+    }
+
+    fn names(name: &str) -> Vec<u8> {
+        map(&[("names", map(&[("de", string("x")), ("en", string(name))]))])
+    }
+
+    #[test]
+    fn maxmind_city_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let berlin = map(&[
+            ("city", names("Berlin")),
+            ("country", map(&[("iso_code", string("de")), ("names", map(&[("en", string("Germany"))]))])),
+            ("subdivisions", array(&[names("Land Berlin")])),
+        ]);
+        let country_only = map(&[("country", map(&[("iso_code", string("NL"))]))]);
+        let path = dir.path().join("city.mmdb");
+        std::fs::write(
+            &path,
+            mmdb("GeoLite2-City", &[("192.0.2.0", 24, berlin), ("198.51.100.128", 25, country_only)]),
+        )
+        .unwrap();
+        let db = MaxMindDb::open(&path).unwrap();
+
+        assert_eq!(
+            db.locate("192.0.2.1".parse().unwrap()),
+            Some(GeoInfo {
+                country_name: Some("Germany".into()),
+                country_code: Some("DE".into()),
+                city: Some("Berlin".into()),
+                region: Some("Land Berlin".into()),
+                ..GeoInfo::default()
+            })
+        );
+        assert_eq!(
+            db.locate("198.51.100.255".parse().unwrap()),
+            Some(GeoInfo { country_code: Some("NL".into()), ..GeoInfo::default() })
+        );
+        assert_eq!(db.locate("198.51.100.127".parse().unwrap()), None);
+        assert_eq!(db.locate("192.0.3.1".parse().unwrap()), None);
+        assert_eq!(db.to_string(), format!("maxmind:{}", path.display()));
+    }
+
+    #[test]
+    fn maxmind_asn_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = map(&[
+            ("autonomous_system_number", uint(6, 3320)),
+            ("autonomous_system_organization", string("Deutsche Telekom AG")),
+        ]);
+        let path = dir.path().join("asn.mmdb");
+        std::fs::write(&path, mmdb("GeoLite2-ASN", &[("192.0.2.0", 24, record)])).unwrap();
+        let db = MaxMindDb::open(&path).unwrap();
+
+        assert_eq!(
+            db.locate("192.0.2.1".parse().unwrap()),
+            Some(GeoInfo { asn: Some("AS3320".into()), org: Some("Deutsche Telekom AG".into()), ..GeoInfo::default() })
+        );
+        assert_eq!(db.locate("203.0.113.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn maxmind_open_rejects_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write(dir.path(), "geoip", "16909056,16909311,DE\n");
+        let err = MaxMindDb::open(&path).err().unwrap().to_string();
+        assert!(err.contains("Failed to open MaxMind database"), "{}", err);
+    }
+}
@@ -0,0 +1,446 @@
+//! HTTP forward proxy frontend: plain `http://` requests are forwarded and
+//! `CONNECT` is tunnelled, both through Tor's SocksPort.
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, info};
+
+use crate::frontend;
+use crate::killswitch::KillSwitch;
+use crate::pool::TorPool;
+use crate::shutdown::Shutdown;
+use crate::socks::{self, Isolator, SocksReplyError, TargetAddr};
+
+/// Upper bound on a request line plus headers.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Clients must send their request head within this time.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that would identify the client or only concern the hop to us.
+/// Anything listed in `Connection` is dropped as well.
+const STRIPPED_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+    "via",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
+
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    pub fn parse(head: &str) -> Result<Self> {
+        let mut lines = head.split("\r\n").map(|l| l.trim_end_matches('\n'));
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(anyhow!("Malformed request line {:?}", request_line));
+        };
+
+        let headers = lines
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                l.split_once(':')
+                    .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                    .ok_or_else(|| anyhow!("Malformed header {:?}", l))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Headers to pass on, minus hop-by-hop and identifying ones.
+    fn forwarded_headers(&self) -> Vec<&(String, String)> {
+        let listed: Vec<String> = self
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, v)| v.split(',').map(|h| h.trim().to_ascii_lowercase()))
+            .collect();
+        self.headers
+            .iter()
+            .filter(|(k, _)| {
+                let k = k.to_ascii_lowercase();
+                !STRIPPED_HEADERS.contains(&k.as_str()) && !listed.contains(&k)
+            })
+            .collect()
+    }
+}
+
+pub struct HttpProxy {
+    pool: Arc<TorPool>,
+    isolator: Arc<Isolator>,
+    kill_switch: KillSwitch,
+}
+
+impl HttpProxy {
+    pub fn new(pool: Arc<TorPool>, isolator: Arc<Isolator>, kill_switch: KillSwitch) -> Self {
+        Self {
+            pool,
+            isolator,
+            kill_switch,
+        }
+    }
+
+    /// Accepts connections until shutdown, then waits for the open ones to
+    /// finish. While the kill switch is engaged nothing is accepted.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, shutdown: Shutdown) {
+        if let Ok(addr) = listener.local_addr() {
+            info!("🌍 HTTP proxy frontend listening on {}", addr);
+        }
+        let kill_switch = self.kill_switch.clone();
+        frontend::serve("HTTP proxy", listener, &kill_switch, shutdown, |client, peer| {
+            let proxy = self.clone();
+            async move { proxy.handle(client, peer).await }
+        })
+        .await;
+    }
+
+    /// Handles a single request; the connection is closed afterwards so
+    /// every request is accounted for (and can be isolated) separately.
+    async fn handle(&self, client: TcpStream, peer: SocketAddr) -> Result<()> {
+        let mut client = BufReader::new(client);
+        let head = match time::timeout(HEAD_TIMEOUT, read_head(&mut client)).await {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(e)) => {
+                respond(client.get_mut(), 400, "Bad Request").await?;
+                return Err(e);
+            }
+            Err(_) => {
+                respond(client.get_mut(), 408, "Request Timeout").await?;
+                return Err(anyhow!("request head timed out"));
+            }
+        };
+        let request = match RequestHead::parse(&head) {
+            Ok(request) => request,
+            Err(e) => {
+                respond(client.get_mut(), 400, "Bad Request").await?;
+                return Err(e);
+            }
+        };
+
+        let tunnel = request.method.eq_ignore_ascii_case("CONNECT");
+        let (target, path) = if tunnel {
+            (TargetAddr::parse(&request.target, 443), None)
+        } else {
+            match origin_target(&request.target) {
+                Ok((target, path)) => (Ok(target), Some(path)),
+                Err(e) => (Err(e), None),
+            }
+        };
+        let target = match target {
+            Ok(target) => target,
+            Err(e) => {
+                respond(client.get_mut(), 400, "Bad Request").await?;
+                return Err(e);
+            }
+        };
+
+        let lease = self.pool.lease();
+        lease.instance.traffic.record_request();
+        debug!("HTTP {} {} from {} via {}", request.method, target, peer, lease.instance);
+        let (user, pass) = self.isolator.credentials(peer, None);
+        let mut tor = match socks::connect(lease.instance.socks, &target, Some((&user, &pass))).await {
+            Ok(tor) => tor,
+            Err(e) => {
+                let (status, reason) = match e.downcast_ref::<SocksReplyError>() {
+                    Some(SocksReplyError(0x06)) => (504, "Gateway Timeout"),
+                    _ => (502, "Bad Gateway"),
+                };
+                respond(client.get_mut(), status, reason).await?;
+                return Err(e.context(format!("connecting to {}", target)));
+            }
+        };
+
+        match path {
+            None => {
+                client
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await?;
+            }
+            Some(path) => {
+                let mut head = format!("{} {} {}\r\n", request.method, path, request.version);
+                let mut has_host = false;
+                for (key, value) in request.forwarded_headers() {
+                    has_host |= key.eq_ignore_ascii_case("host");
+                    head.push_str(&format!("{}: {}\r\n", key, value));
+                }
+                if !has_host {
+                    head.push_str(&format!("Host: {}\r\n", target));
+                }
+                head.push_str("Connection: close\r\n\r\n");
+                tor.write_all(head.as_bytes()).await?;
+            }
+        }
+
+        // Whatever the client sent past the head (request body, TLS hello)
+        let buffered = client.buffer().len() as u64;
+        tor.write_all(client.buffer()).await?;
+        let mut client = client.into_inner();
+        let (sent, received) = tokio::io::copy_bidirectional(&mut client, &mut tor).await?;
+        let sent = sent + buffered;
+        lease.instance.traffic.record_relayed(sent, received);
+        debug!("HTTP {} {} closed ({} B sent, {} B received)", request.method, target, sent, received);
+        Ok(())
+    }
+}
+
+/// Reads up to and including the blank line ending the head. `None` if
+/// the client closed the connection without sending anything.
+pub async fn read_head<R: AsyncBufRead + Unpin>(client: &mut R) -> Result<Option<String>> {
+    let mut head = String::new();
+    loop {
+        // Never buffer more than one byte past the limit, even of a line
+        // that doesn't end
+        let budget = (MAX_HEAD_SIZE + 1 - head.len()) as u64;
+        let read = (&mut *client).take(budget).read_line(&mut head).await?;
+        if read == 0 {
+            return if head.is_empty() {
+                Ok(None)
+            } else {
+                Err(anyhow!("connection closed mid-request"))
+            };
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(anyhow!("request head too large"));
+        }
+        if head.ends_with("\n\r\n") || head.ends_with("\n\n") || head == "\r\n" {
+            return Ok(Some(head));
+        }
+    }
+}
+
+/// Splits an absolute `http://` URI into where to connect and the
+/// origin-form path to request there.
+fn origin_target(uri: &str) -> Result<(TargetAddr, String)> {
+    let url = Url::parse(uri).map_err(|e| anyhow!("Invalid request URI {:?}: {}", uri, e))?;
+    if url.scheme() != "http" {
+        return Err(anyhow!("Unsupported scheme {:?} (use CONNECT for https)", url.scheme()));
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Request URI {:?} has no host", uri))?;
+    let target = TargetAddr::parse(host, port)?;
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    Ok((target, path))
+}
+
+async fn respond(client: &mut TcpStream, status: u16, reason: &str) -> Result<()> {
+    let body = format!("{} {}\n", status, reason);
+    write_response(client, status, reason, "text/plain", &body).await
+}
+
+/// Writes a complete response and announces the connection will close.
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    client: &mut W,
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    );
+    client.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::{Balance, TorInstance};
+    use crate::testutil;
+    use std::net::IpAddr;
+    use tokio::sync::mpsc;
+
+    /// A web server answering `ok` to anything, reporting each request head
+    /// it receives.
+    async fn origin() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (seen, heads) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let Ok(Some(head)) = read_head(&mut stream).await else {
+                        return;
+                    };
+                    let _ = seen.send(head);
+                    let _ = write_response(stream.get_mut(), 200, "OK", "text/plain", "ok").await;
+                });
+            }
+        });
+        (addr, heads)
+    }
+
+    /// An HTTP proxy frontend whose Tor resolves `origin.test` to loopback.
+    async fn proxy() -> (SocketAddr, Arc<TorInstance>) {
+        let socks =
+            testutil::socks_port(|host| async move { (host == "origin.test").then_some(IpAddr::from([127, 0, 0, 1])) })
+                .await;
+        let instance = testutil::instance("tor0", socks).await;
+        let pool = Arc::new(TorPool::new(vec![instance.clone()], Balance::RoundRobin));
+        let isolator = Arc::new(Isolator::new(socks::Isolation::None));
+        let proxy = Arc::new(HttpProxy::new(pool, isolator, KillSwitch::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(proxy.serve(listener, Shutdown::default()));
+        (addr, instance)
+    }
+
+    /// Sends `request` through the proxy and returns the whole response.
+    async fn exchange(proxy: SocketAddr, request: &str) -> String {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn forwards_absolute_uris_in_origin_form_without_hop_by_hop_headers() {
+        let (origin, mut heads) = origin().await;
+        let (proxy, instance) = proxy().await;
+        let port = origin.port();
+        let response = exchange(
+            proxy,
+            &format!(
+                "GET http://origin.test:{port}/path?q=1 HTTP/1.1\r\n\
+                 Host: origin.test:{port}\r\n\
+                 User-Agent: test\r\n\
+                 Proxy-Authorization: Basic dXNlcjpwdw==\r\n\
+                 Proxy-Connection: keep-alive\r\n\
+                 Connection: keep-alive, X-Client-Secret\r\n\
+                 X-Client-Secret: 42\r\n\
+                 Via: 1.1 upstream\r\n\
+                 X-Forwarded-For: 10.0.0.1\r\n\
+                 Forwarded: for=10.0.0.1\r\n\
+                 Accept: */*\r\n\r\n"
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("ok"), "{}", response);
+        assert_eq!(
+            heads.recv().await.unwrap(),
+            format!(
+                "GET /path?q=1 HTTP/1.1\r\nHost: origin.test:{port}\r\nUser-Agent: test\r\nAccept: */*\r\n\
+                 Connection: close\r\n\r\n"
+            )
+        );
+
+        // Host is added when the client left it out
+        exchange(proxy, &format!("GET http://127.0.0.1:{port} HTTP/1.0\r\n\r\n")).await;
+        assert_eq!(
+            heads.recv().await.unwrap(),
+            format!("GET / HTTP/1.0\r\nHost: 127.0.0.1:{port}\r\nConnection: close\r\n\r\n")
+        );
+        assert_eq!(instance.traffic.snapshot().requests, 2);
+    }
+
+    #[tokio::test]
+    async fn tunnels_connect_untouched() {
+        let (origin, mut heads) = origin().await;
+        let (proxy, instance) = proxy().await;
+        // Headers inside the tunnel belong to the client and its origin
+        let inner = "GET /inside HTTP/1.1\r\nProxy-Authorization: Basic dXNlcjpwdw==\r\n\r\n";
+        let response = exchange(
+            proxy,
+            &format!(
+                "CONNECT origin.test:{port} HTTP/1.1\r\nHost: origin.test:{port}\r\n\r\n{inner}",
+                port = origin.port()
+            ),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 Connection established\r\n\r\nHTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(heads.recv().await.unwrap(), inner);
+
+        // Counted once both ends are closed
+        time::timeout(Duration::from_secs(5), async {
+            while instance.traffic.snapshot().relayed == 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let established = "HTTP/1.1 200 Connection established\r\n\r\n".len();
+        assert_eq!(instance.traffic.snapshot().relayed, (inner.len() + response.len() - established) as u64);
+    }
+
+    #[tokio::test]
+    async fn rejects_what_it_cannot_forward() {
+        let (proxy, _) = proxy().await;
+        for request in [
+            "GET https://origin.test/ HTTP/1.1\r\n\r\n",
+            "GET /relative HTTP/1.1\r\n\r\n",
+            "CONNECT origin.test:https HTTP/1.1\r\n\r\n",
+            "GET\r\n\r\n",
+        ] {
+            let response = exchange(proxy, request).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", request, response);
+        }
+        let response = exchange(proxy, "GET http://unknown.test/ HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn bounds_the_request_head() {
+        let mut endless_line = BufReader::new(tokio::io::repeat(b'a'));
+        assert!(read_head(&mut endless_line).await.is_err());
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-Padding: a\r\n".repeat(MAX_HEAD_SIZE / 10));
+        assert!(read_head(&mut many_headers.as_bytes()).await.is_err());
+
+        let mut request = "GET / HTTP/1.1\r\nHost: example.com\r\n\r\nbody".as_bytes();
+        assert_eq!(read_head(&mut request).await.unwrap().unwrap(), "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(request, b"body");
+        assert!(read_head(&mut "".as_bytes()).await.unwrap().is_none());
+        assert!(read_head(&mut "GET / HTTP/1.1\r\n".as_bytes()).await.is_err());
+    }
+}
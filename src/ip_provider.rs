@@ -0,0 +1,457 @@
+//! Finding out the exit IP from IP echo services, with fallback between
+//! them and optionally several having to agree.
+//!
+//! Providers are given as a URL and how to read the answer:
+//!
+//! ```text
+//! https://icanhazip.com                          plain text body
+//! https://api.ipify.org?format=json json=ip      {"ip": "..."}
+//! https://example.com/whoami json=data.0.addr timeout=5
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::future::BoxFuture;
+use reqwest::Url;
+use serde_json::Value;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::metrics::Metrics;
+
+/// Consecutive failures after which a provider is tried last.
+const UNHEALTHY_AFTER: u32 = 3;
+
+/// How long an unhealthy provider stays at the back of the line.
+const UNHEALTHY_FOR: Duration = Duration::from_secs(300);
+
+/// A service that tells us which address our requests come from.
+pub trait IpProvider: fmt::Display + Send + Sync {
+    /// Short label for logs and metrics, e.g. the host.
+    fn name(&self) -> &str;
+
+    /// Asks for our address through `client`, giving up after the
+    /// provider's own timeout.
+    fn lookup<'a>(&'a self, client: &'a reqwest::Client) -> BoxFuture<'a, Result<IpAddr>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseFormat {
+    /// The body is the address.
+    Text,
+    /// The address is at this dot-separated path in a JSON body; numbers
+    /// index into arrays.
+    Json(String),
+}
+
+/// An HTTP(S) echo service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpProvider {
+    name: String,
+    url: Url,
+    format: ResponseFormat,
+    timeout: Duration,
+}
+
+impl HttpProvider {
+    /// Parses `URL [json=PATH] [timeout=SECS]`; the timeout defaults to
+    /// `default_timeout`.
+    pub fn parse(spec: &str, default_timeout: Duration) -> Result<Self> {
+        let mut words = spec.split_whitespace();
+        let url = words.next().ok_or_else(|| anyhow!("empty IP provider"))?;
+        let url = Url::parse(url).with_context(|| format!("Invalid IP provider URL {:?}", url))?;
+        let host = url.host_str().ok_or_else(|| anyhow!("IP provider URL {} has no host", url))?;
+        let name = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        let mut format = ResponseFormat::Text;
+        let mut timeout = default_timeout;
+        for option in words {
+            match option.split_once('=') {
+                Some(("json", path)) if !path.is_empty() => format = ResponseFormat::Json(path.to_string()),
+                Some(("timeout", secs)) => {
+                    timeout = match secs.parse() {
+                        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                        _ => return Err(anyhow!("Invalid IP provider timeout {:?} (expected seconds)", secs)),
+                    }
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown IP provider option {:?} (expected json=PATH or timeout=SECS)",
+                        option
+                    ))
+                }
+            }
+        }
+        Ok(Self { name, url, format, timeout })
+    }
+
+    async fn fetch(&self, client: &reqwest::Client) -> Result<IpAddr> {
+        let body = client
+            .get(self.url.clone())
+            .timeout(self.timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let address = match &self.format {
+            ResponseFormat::Text => body.trim().to_string(),
+            ResponseFormat::Json(path) => {
+                let json: Value = serde_json::from_str(&body).context("Response is not JSON")?;
+                match json_path(&json, path) {
+                    Some(Value::String(address)) => address.trim().to_string(),
+                    Some(other) => return Err(anyhow!("{} is not a string: {}", path, other)),
+                    None => return Err(anyhow!("Response has no {}", path)),
+                }
+            }
+        };
+        address
+            .parse()
+            .map_err(|_| anyhow!("Response is not an IP address: {:?}", truncate(&address, 64)))
+    }
+}
+
+impl IpProvider for HttpProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn lookup<'a>(&'a self, client: &'a reqwest::Client) -> BoxFuture<'a, Result<IpAddr>> {
+        Box::pin(self.fetch(client))
+    }
+}
+
+impl fmt::Display for HttpProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)?;
+        if let ResponseFormat::Json(path) = &self.format {
+            write!(f, " json={}", path)?;
+        }
+        write!(f, " timeout={}", self.timeout.as_secs())
+    }
+}
+
+fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| match (value, key.parse::<usize>()) {
+        (Value::Array(items), Ok(index)) => items.get(index),
+        _ => value.get(key),
+    })
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    match s.char_indices().nth(max) {
+        Some((end, _)) => &s[..end],
+        None => s,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    unhealthy_until: Option<Instant>,
+}
+
+impl Health {
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.unhealthy_until.is_none_or(|until| now >= until)
+    }
+}
+
+/// Providers tried in order until `quorum` of them report the same address.
+/// Ones that keep failing are tried last for a while.
+pub struct ProviderChain {
+    providers: Vec<Box<dyn IpProvider>>,
+    quorum: usize,
+    health: Mutex<Vec<Health>>,
+}
+
+impl ProviderChain {
+    pub fn new(providers: Vec<Box<dyn IpProvider>>, quorum: usize) -> Result<Self> {
+        if providers.is_empty() {
+            return Err(anyhow!("At least one IP provider is needed"));
+        }
+        if quorum == 0 || quorum > providers.len() {
+            return Err(anyhow!(
+                "ip-quorum must be between 1 and the number of IP providers ({}), not {}",
+                providers.len(),
+                quorum
+            ));
+        }
+        let health = providers.iter().map(|_| Health::default()).collect();
+        Ok(Self {
+            providers,
+            quorum,
+            health: Mutex::new(health),
+        })
+    }
+
+    /// Our address as seen through `client`.
+    pub async fn lookup(&self, client: &reqwest::Client, metrics: &Metrics) -> Result<IpAddr> {
+        let mut votes: Vec<(IpAddr, Vec<&str>)> = Vec::new();
+        let mut failures = Vec::new();
+        for index in self.order() {
+            let provider = &self.providers[index];
+            let started = Instant::now();
+            let result = provider.lookup(client).await;
+            metrics.record_ip_check(provider.name(), started.elapsed(), result.is_ok());
+            let address = match result {
+                Ok(address) => {
+                    self.record(index, true);
+                    address
+                }
+                Err(e) => {
+                    debug!("IP provider {} failed: {:#}", provider.name(), e);
+                    self.record(index, false);
+                    failures.push(format!("{}: {:#}", provider.name(), e));
+                    continue;
+                }
+            };
+            let agreeing = match votes.iter_mut().find(|(voted, _)| *voted == address) {
+                Some((_, names)) => {
+                    names.push(provider.name());
+                    names.len()
+                }
+                None => {
+                    votes.push((address, vec![provider.name()]));
+                    1
+                }
+            };
+            if agreeing >= self.quorum {
+                if votes.len() > 1 {
+                    warn!("IP providers disagree: {}", describe_votes(&votes));
+                }
+                return Ok(address);
+            }
+        }
+
+        if votes.is_empty() {
+            Err(anyhow!("All IP providers failed ({})", failures.join("; ")))
+        } else {
+            Err(anyhow!(
+                "No {} IP providers agreed: {}",
+                self.quorum,
+                describe_votes(&votes)
+            ))
+        }
+    }
+
+    /// Healthy providers first, each group in the configured order.
+    fn order(&self) -> Vec<usize> {
+        let health = self.health.lock().unwrap();
+        let now = Instant::now();
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) =
+            (0..self.providers.len()).partition(|&index| health[index].is_healthy(now));
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    fn record(&self, index: usize, succeeded: bool) {
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[index];
+        let name = self.providers[index].name();
+        if succeeded {
+            if health.unhealthy_until.take().is_some() {
+                info!("IP provider {} is answering again", name);
+            }
+            health.consecutive_failures = 0;
+            return;
+        }
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= UNHEALTHY_AFTER {
+            if health.unhealthy_until.is_none() {
+                warn!(
+                    "IP provider {} failed {} times in a row, trying it last for {}s",
+                    name,
+                    health.consecutive_failures,
+                    UNHEALTHY_FOR.as_secs()
+                );
+            }
+            health.unhealthy_until = Some(Instant::now() + UNHEALTHY_FOR);
+        }
+    }
+}
+
+fn describe_votes(votes: &[(IpAddr, Vec<&str>)]) -> String {
+    votes
+        .iter()
+        .map(|(address, names)| format!("{} from {}", address, names.join(", ")))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl fmt::Debug for ProviderChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let providers: Vec<String> = self.providers.iter().map(ToString::to_string).collect();
+        f.debug_struct("ProviderChain")
+            .field("providers", &providers)
+            .field("quorum", &self.quorum)
+            .finish()
+    }
+}
+
+/// Chains are equal when they ask the same providers for the same quorum;
+/// health is not compared.
+impl PartialEq for ProviderChain {
+    fn eq(&self, other: &Self) -> bool {
+        self.quorum == other.quorum
+            && self.providers.len() == other.providers.len()
+            && self
+                .providers
+                .iter()
+                .zip(&other.providers)
+                .all(|(ours, theirs)| ours.to_string() == theirs.to_string())
+    }
+}
+
+impl Eq for ProviderChain {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::http_server as echo;
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    fn chain(specs: &[String], quorum: usize) -> ProviderChain {
+        let providers = specs
+            .iter()
+            .map(|spec| Box::new(HttpProvider::parse(spec, Duration::from_secs(5)).unwrap()) as Box<dyn IpProvider>)
+            .collect();
+        ProviderChain::new(providers, quorum).unwrap()
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[test]
+    fn parses_provider_specs() {
+        let timeout = Duration::from_secs(10);
+        let provider =
+            HttpProvider::parse("https://example.com:8443/ip?a=1,2 json=data.0.ip timeout=3", timeout).unwrap();
+        assert_eq!(provider.name(), "example.com:8443");
+        assert_eq!(provider.format, ResponseFormat::Json("data.0.ip".to_string()));
+        assert_eq!(provider.timeout, Duration::from_secs(3));
+        assert_eq!(
+            provider.to_string(),
+            "https://example.com:8443/ip?a=1,2 json=data.0.ip timeout=3"
+        );
+
+        let provider = HttpProvider::parse("https://icanhazip.com", timeout).unwrap();
+        assert_eq!(provider.format, ResponseFormat::Text);
+        assert_eq!(provider.timeout, timeout);
+
+        for spec in [
+            "",
+            "not a url",
+            "https://example.com json=",
+            "https://example.com timeout=0",
+            "https://example.com retries=2",
+        ] {
+            assert!(HttpProvider::parse(spec, timeout).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn follows_json_paths() {
+        let value = json!({"ip": "192.0.2.1", "data": [{"addr": "2001:db8::1"}, {"addr": 7}]});
+        assert_eq!(json_path(&value, "ip"), Some(&json!("192.0.2.1")));
+        assert_eq!(json_path(&value, "data.0.addr"), Some(&json!("2001:db8::1")));
+        assert_eq!(json_path(&value, "data.1.addr"), Some(&json!(7)));
+        assert_eq!(json_path(&value, "data.2.addr"), None);
+        assert_eq!(json_path(&value, "data.first"), None);
+        assert_eq!(json_path(&value, "ip.0"), None);
+        assert_eq!(json_path(&value, "missing"), None);
+    }
+
+    #[tokio::test]
+    async fn falls_back_in_order() {
+        let (down, down_hits) = echo("503 Service Unavailable", "").await;
+        let (garbled, garbled_hits) = echo("200 OK", "<html>").await;
+        let (json, json_hits) = echo("200 OK", r#"{"data": [{"ip": "192.0.2.7"}]}"#).await;
+        let (unused, unused_hits) = echo("200 OK", "192.0.2.8").await;
+        let chain = chain(&[down, garbled, format!("{} json=data.0.ip", json), unused], 1);
+
+        let address = chain.lookup(&client(), &Metrics::default()).await.unwrap();
+        assert_eq!(address, "192.0.2.7".parse::<IpAddr>().unwrap());
+        let hits = [&down_hits, &garbled_hits, &json_hits, &unused_hits].map(|hits| hits.load(Ordering::SeqCst));
+        assert_eq!(hits, [1, 1, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn reports_every_failure() {
+        let (down, _) = echo("500 Internal Server Error", "").await;
+        let (garbled, _) = echo("200 OK", "not an address").await;
+        let chain = chain(&[down, garbled], 1);
+
+        let error = chain
+            .lookup(&client(), &Metrics::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("All IP providers failed"), "{}", error);
+        assert!(error.contains("500"), "{}", error);
+        assert!(error.contains("not an IP address"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_quorum() {
+        let (first, _) = echo("200 OK", "192.0.2.1\n").await;
+        let (liar, _) = echo("200 OK", "198.51.100.1").await;
+        let (second, _) = echo("200 OK", "192.0.2.1").await;
+        let chain = chain(&[first.clone(), liar.clone(), second], 2);
+        let address = chain.lookup(&client(), &Metrics::default()).await.unwrap();
+        assert_eq!(address, "192.0.2.1".parse::<IpAddr>().unwrap());
+
+        let (down, _) = echo("503 Service Unavailable", "").await;
+        let chain = self::chain(&[first, liar, down], 2);
+        let error = chain
+            .lookup(&client(), &Metrics::default())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("No 2 IP providers agreed"), "{}", error);
+        assert!(error.contains("192.0.2.1 from 127.0.0.1"), "{}", error);
+        assert!(error.contains("198.51.100.1 from 127.0.0.1"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn tries_failing_providers_last() {
+        let (down, down_hits) = echo("503 Service Unavailable", "").await;
+        let (up, _) = echo("200 OK", "192.0.2.1").await;
+        let chain = chain(&[down, up], 1);
+        let client = client();
+        let metrics = Metrics::default();
+
+        for failures in 1..=UNHEALTHY_AFTER {
+            assert_eq!(chain.order(), [0, 1], "after {} failures", failures - 1);
+            chain.lookup(&client, &metrics).await.unwrap();
+        }
+        assert_eq!(chain.order(), [1, 0]);
+        chain.lookup(&client, &metrics).await.unwrap();
+        assert_eq!(down_hits.load(Ordering::SeqCst), UNHEALTHY_AFTER as usize);
+
+        // One answer is enough to get back in line
+        chain.record(0, true);
+        assert_eq!(chain.order(), [0, 1]);
+        let health = chain.health.lock().unwrap();
+        assert_eq!(health[0].consecutive_failures, 0);
+        assert!(health[0].unhealthy_until.is_none());
+    }
+
+    #[test]
+    fn unhealthy_providers_come_back_after_a_while() {
+        let chain = chain(&["http://192.0.2.1/".to_string(), "http://192.0.2.2/".to_string()], 1);
+        for _ in 0..UNHEALTHY_AFTER {
+            chain.record(0, false);
+        }
+        assert_eq!(chain.order(), [1, 0]);
+        chain.health.lock().unwrap()[0].unhealthy_until = Some(Instant::now());
+        assert_eq!(chain.order(), [0, 1]);
+    }
+}
@@ -0,0 +1,365 @@
+//! Kill switch: with our real address recorded at startup, any sign that
+//! traffic could leave without Tor (the real IP seen through Tor, a SOCKS
+//! port that is down, an exit that isn't Tor's) closes the proxy frontends
+//! and cuts their connections until Tor is verified again.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use crate::config::Tuning;
+use crate::console;
+use crate::exit_verify;
+use crate::metrics::Metrics;
+use crate::pool::TorInstance;
+use crate::rotation;
+use crate::shutdown::Shutdown;
+
+/// How long to wait before trying to reopen a frontend's port again.
+const REBIND_DELAY: Duration = Duration::from_secs(1);
+
+/// Why traffic is held back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Leak {
+    /// Tor hasn't been verified since startup; the kill switch starts
+    /// engaged.
+    Unverified,
+    /// An IP check through Tor saw our real address.
+    DirectIp(IpAddr),
+    /// Tor's SOCKS port can't be reached.
+    SocksDown(String),
+    /// The exit IP isn't one of Tor's.
+    NotTor(IpAddr),
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Leak::Unverified => write!(f, "Tor not verified yet"),
+            Leak::DirectIp(ip) => write!(f, "our real IP {} was seen through Tor", ip),
+            Leak::SocksDown(e) => write!(f, "Tor's SOCKS port is unreachable: {}", e),
+            Leak::NotTor(ip) => write!(f, "exit {} is not verified as Tor", ip),
+        }
+    }
+}
+
+/// Shared between the leak checks, which trip and release it, and the
+/// frontends, which close while it is engaged. The default one is off and
+/// never trips.
+#[derive(Clone, Default)]
+pub struct KillSwitch {
+    armed: bool,
+    /// What holds traffic back, per instance; empty when it may flow.
+    leaks: Arc<watch::Sender<BTreeMap<String, Leak>>>,
+    real_ip: Arc<Mutex<Option<IpAddr>>>,
+    /// Shell command run whenever a leak is detected.
+    hook: Option<Arc<str>>,
+}
+
+impl KillSwitch {
+    /// Engaged until each of `instances` has been verified.
+    pub fn new(instances: impl IntoIterator<Item = String>, hook: Option<String>) -> Self {
+        let leaks = instances.into_iter().map(|name| (name, Leak::Unverified)).collect();
+        Self {
+            armed: true,
+            leaks: Arc::new(watch::Sender::new(leaks)),
+            real_ip: Arc::default(),
+            hook: hook.map(Arc::from),
+        }
+    }
+
+    /// Our address without Tor, which must never be seen through it.
+    pub fn set_real_ip(&self, ip: IpAddr) {
+        *self.real_ip.lock().unwrap() = Some(ip);
+    }
+
+    fn real_ip(&self) -> Option<IpAddr> {
+        *self.real_ip.lock().unwrap()
+    }
+
+    /// Trips if `ip`, seen through `instance`, is our real address.
+    pub fn observe(&self, instance: &str, ip: IpAddr) {
+        if self.real_ip() == Some(ip) {
+            self.trip(instance, Leak::DirectIp(ip));
+        }
+    }
+
+    pub fn trip(&self, instance: &str, leak: Leak) {
+        if !self.armed {
+            return;
+        }
+        let changed = self.leaks.send_if_modified(|leaks| {
+            leaks.insert(instance.to_string(), leak.clone()).as_ref() != Some(&leak)
+        });
+        if changed {
+            error!("🛑 Kill switch engaged by {}: {}; proxy frontends stopped", instance, leak);
+            self.run_hook(instance, &leak);
+        }
+    }
+
+    /// Tor was verified on `instance`.
+    pub fn release(&self, instance: &str) {
+        let mut previous = None;
+        let changed = self.leaks.send_if_modified(|leaks| {
+            previous = leaks.remove(instance);
+            previous.is_some()
+        });
+        if !changed {
+            return;
+        }
+        match previous {
+            Some(Leak::Unverified) => info!("✓ {} verified as Tor", instance),
+            _ => info!("✓ {} verified as Tor again", instance),
+        }
+        let leaks = self.leaks.borrow();
+        if leaks.is_empty() {
+            info!("Kill switch released; proxy frontends accepting connections");
+        } else {
+            let held: Vec<&str> = leaks.keys().map(String::as_str).collect();
+            info!("Kill switch still held by {}", held.join(", "));
+        }
+    }
+
+    /// Resolves once traffic has to stop; never if it doesn't.
+    pub async fn engaged(&self) {
+        let mut leaks = self.leaks.subscribe();
+        let _ = leaks.wait_for(|leaks| !leaks.is_empty()).await;
+    }
+
+    async fn released(&self) {
+        let mut leaks = self.leaks.subscribe();
+        let _ = leaks.wait_for(|leaks| leaks.is_empty()).await;
+    }
+
+    /// Closes a frontend's listener and cuts its connections until the
+    /// kill switch is released, then listens on the same address again.
+    /// Returns `None` if shutdown is requested in the meantime.
+    pub async fn hold(
+        &self,
+        frontend: &str,
+        listener: TcpListener,
+        connections: &mut JoinSet<()>,
+        shutdown: &Shutdown,
+    ) -> Option<TcpListener> {
+        let addr = listener.local_addr();
+        drop(listener);
+        let cut = connections.len();
+        connections.shutdown().await;
+        let addr = match addr {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("{} frontend closed for good, cannot tell where it listened: {}", frontend, e);
+                return None;
+            }
+        };
+        warn!(
+            "{} frontend on {} closed until Tor is verified ({} connection(s) cut)",
+            frontend, addr, cut
+        );
+
+        tokio::select! {
+            _ = self.released() => {}
+            _ = shutdown.requested() => return None,
+        }
+        loop {
+            match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    info!("{} frontend listening on {} again", frontend, addr);
+                    return Some(listener);
+                }
+                Err(e) => warn!("Failed to reopen {} frontend on {}: {}", frontend, addr, e),
+            }
+            tokio::select! {
+                _ = time::sleep(REBIND_DELAY) => {}
+                _ = shutdown.requested() => return None,
+            }
+        }
+    }
+
+    fn run_hook(&self, instance: &str, leak: &Leak) {
+        let Some(hook) = &self.hook else {
+            return;
+        };
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(hook.as_ref())
+            .env("RUSTTATOR_LEAK_INSTANCE", instance)
+            .env("RUSTTATOR_LEAK", leak.to_string())
+            .env(
+                "RUSTTATOR_REAL_IP",
+                self.real_ip().map(|ip| ip.to_string()).unwrap_or_default(),
+            );
+        tokio::spawn(async move {
+            match command.status().await {
+                Ok(status) if status.success() => debug!("Leak hook finished"),
+                Ok(status) => warn!("Leak hook failed: {}", status),
+                Err(e) => warn!("Failed to run leak hook: {}", e),
+            }
+        });
+    }
+}
+
+/// Checks `instance` for leaks every `interval` until shutdown, tripping
+/// the kill switch on one and releasing it once Tor checks out again.
+/// Reloaded tuning arrives through `commands`.
+pub async fn monitor(
+    kill_switch: KillSwitch,
+    instance: Arc<TorInstance>,
+    mut tuning: Arc<Tuning>,
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    mut commands: broadcast::Receiver<console::Command>,
+    shutdown: Shutdown,
+) {
+    loop {
+        match check(&instance, kill_switch.real_ip(), &tuning, &metrics).await {
+            Ok(Some(leak)) => kill_switch.trip(&instance.name, leak),
+            Ok(None) => kill_switch.release(&instance.name),
+            // Without an answer nothing changes; an engaged switch stays so
+            Err(e) => debug!("Leak check on {} inconclusive: {:#}", instance.name, e),
+        }
+        let next_check = time::sleep(interval);
+        tokio::pin!(next_check);
+        loop {
+            tokio::select! {
+                _ = &mut next_check => break,
+                Ok(command) = commands.recv() => {
+                    if let console::Command::Tuning(retuned) = command {
+                        tuning = retuned;
+                    }
+                }
+                _ = shutdown.requested() => return,
+            }
+        }
+    }
+}
+
+/// The leak `instance` has, `None` if its traffic verifiably leaves through
+/// Tor, or an error if that couldn't be determined.
+async fn check(
+    instance: &TorInstance,
+    real_ip: Option<IpAddr>,
+    tuning: &Tuning,
+    metrics: &Metrics,
+) -> Result<Option<Leak>> {
+    let reachable = match time::timeout(tuning.check_timeout, TcpStream::connect(instance.socks)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", tuning.check_timeout.as_secs())),
+    };
+    if let Err(e) = reachable {
+        return Ok(Some(Leak::SocksDown(e)));
+    }
+
+    let client = rotation::tor_client(instance.socks.port(), tuning.request_timeout)?;
+    let ip = tuning.ip_providers.lookup(&client, metrics).await?;
+    if real_ip == Some(ip) {
+        return Ok(Some(Leak::DirectIp(ip)));
+    }
+    let is_tor = exit_verify::is_tor_exit(ip, &client, Some(&instance.exit_verifier), tuning, metrics).await?;
+    Ok((!is_tor).then_some(Leak::NotTor(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    const EXIT: &str = "192.0.2.1";
+
+    /// An instance whose SOCKS port reaches loopback and whose Tor knows no
+    /// exits, so only the Tor check service can vouch for one.
+    async fn instance() -> Arc<TorInstance> {
+        let socks = testutil::socks_port(|_| async { None }).await;
+        testutil::instance("tor0", socks).await
+    }
+
+    #[tokio::test]
+    async fn reports_a_socks_port_that_is_down() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let instance = testutil::instance("tor0", closed).await;
+        let tuning = testutil::tuning("http://192.0.2.1/", None);
+        let leak = check(&instance, None, &tuning, &Metrics::default()).await.unwrap();
+        assert!(matches!(leak, Some(Leak::SocksDown(_))), "{:?}", leak);
+    }
+
+    #[tokio::test]
+    async fn reports_our_real_ip() {
+        let instance = instance().await;
+        let (ip_provider, _) = testutil::http_server("200 OK", EXIT).await;
+        let tuning = testutil::tuning(&ip_provider, None);
+        let real_ip = EXIT.parse().unwrap();
+        let leak = check(&instance, Some(real_ip), &tuning, &Metrics::default()).await.unwrap();
+        assert_eq!(leak, Some(Leak::DirectIp(real_ip)));
+    }
+
+    #[tokio::test]
+    async fn asks_the_tor_check_service_like_the_rotation_loop() {
+        let instance = instance().await;
+        let (ip_provider, _) = testutil::http_server("200 OK", EXIT).await;
+        let metrics = Metrics::default();
+
+        let tuning = testutil::tuning(&ip_provider, None);
+        let leak = check(&instance, None, &tuning, &metrics).await.unwrap();
+        assert_eq!(leak, Some(Leak::NotTor(EXIT.parse().unwrap())));
+
+        let (tor_check, _) = testutil::http_server("200 OK", r#"{"IsTor": true}"#).await;
+        let tuning = testutil::tuning(&ip_provider, Some(tor_check));
+        assert_eq!(check(&instance, None, &tuning, &metrics).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn engages_until_every_instance_is_verified() {
+        let kill_switch = KillSwitch::new(["tor0".to_string(), "tor1".to_string()], None);
+        time::timeout(Duration::from_secs(1), kill_switch.engaged()).await.unwrap();
+        kill_switch.release("tor0");
+        kill_switch.release("tor1");
+        assert!(time::timeout(Duration::from_millis(50), kill_switch.engaged()).await.is_err());
+
+        kill_switch.trip("tor1", Leak::NotTor(EXIT.parse().unwrap()));
+        time::timeout(Duration::from_secs(1), kill_switch.engaged()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_default_switch_never_trips() {
+        let kill_switch = KillSwitch::default();
+        kill_switch.trip("tor0", Leak::NotTor(EXIT.parse().unwrap()));
+        assert!(time::timeout(Duration::from_millis(50), kill_switch.engaged()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn runs_the_hook_with_the_leak_in_its_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("hook");
+        let hook = format!(
+            "echo \"$RUSTTATOR_LEAK_INSTANCE|$RUSTTATOR_LEAK|$RUSTTATOR_REAL_IP\" > {}.tmp && mv {0}.tmp {0}",
+            out.display()
+        );
+        let kill_switch = KillSwitch::new(["tor0".to_string()], Some(hook));
+        kill_switch.set_real_ip("198.51.100.7".parse().unwrap());
+        kill_switch.observe("tor0", "198.51.100.7".parse().unwrap());
+
+        let seen = time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(seen) = std::fs::read_to_string(&out) {
+                    return seen;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let leak = Leak::DirectIp("198.51.100.7".parse().unwrap());
+        assert_eq!(seen.trim(), format!("tor0|{}|198.51.100.7", leak));
+    }
+}
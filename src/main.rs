@@ -1,509 +1,526 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use reqwest::Proxy;
-use serde::Deserialize;
-use std::{time::Duration, net::TcpStream};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
 use tokio::time;
-use tracing::{info, warn, error};
-use std::io::{Write, BufRead, BufReader};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, info, info_span, warn, error, Instrument, Span};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::reload;
 use anyhow::{anyhow};
-use std::fs;
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
-use rand::Rng;
-use hex;
 
-#[derive(Parser, Debug)]
+mod api;
+mod bootstrap;
+mod circuit;
+mod config;
+mod console;
+mod control;
+mod dns_leak;
+mod events;
+mod exit;
+mod exit_verify;
+mod frontend;
+mod geoip;
+mod http_proxy;
+mod ip_provider;
+mod killswitch;
+mod metrics;
+mod policy;
+mod pool;
+mod process;
+mod relay;
+mod reply;
+mod rotation;
+mod shutdown;
+mod socks;
+mod status;
+#[cfg(test)]
+mod testutil;
+
+use api::{ApiEndpoint, ApiServer};
+use circuit::{Circuit, CircuitStatus};
+use config::Tuning;
+use console::Command;
+use control::{ControlEndpoint, TorControl};
+use dns_leak::{DnsLeakCheck, LeakServer, Method};
+use events::TorEvent;
+use exit::{parse_countries, ExitManager, ExitSelection};
+use exit_verify::{is_tor_exit, ExitVerifier};
+use geoip::{GeoDatabase, GeoInfo, GeoSource, Geolocator, MaxMindDb, TorGeoIp};
+use http_proxy::HttpProxy;
+use ip_provider::{HttpProvider, IpProvider, ProviderChain};
+use killswitch::{KillSwitch, Leak};
+use metrics::Metrics;
+use policy::RotationPolicy;
+use pool::{Balance, TorInstance, TorPool};
+use process::ManagedTor;
+use relay::{RelayCache, RelayInfo};
+use rotation::{RotationOutcome, Rotator};
+use shutdown::Shutdown;
+use socks::{Isolation, Isolator, SocksServer};
+use status::{ExitStatus, Status};
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Interval in seconds between IP switches
-    #[arg(short, long, default_value_t = 60)]
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    /// TOML config file; keys are the long flag names [default:
+    /// $XDG_CONFIG_HOME/rusttator/config.toml, if present]
+    #[arg(long, value_name = "PATH", env = "RUSTTATOR_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Log level: error, warn, info, debug, trace or off
+    #[arg(long, value_name = "LEVEL", default_value = "info", env = "RUSTTATOR_LOG_LEVEL")]
+    log_level: LevelFilter,
+
+    /// Interval in seconds between IP switches (ignored when --rotate is given)
+    #[arg(short, long, default_value_t = 60, env = "RUSTTATOR_INTERVAL")]
     interval: u64,
 
+    /// Rotation policy, e.g. "interval:5m | requests:100", "random:2m-10m",
+    /// "cron:*/15 * * * *", "bytes:50MB" or "block" (overrides --interval)
+    #[arg(long, value_name = "POLICY", env = "RUSTTATOR_ROTATE")]
+    rotate: Option<String>,
+
+    /// URL fetched through Tor to detect blocks for the "block" policy
+    #[arg(long, value_name = "URL", env = "RUSTTATOR_BLOCK_PROBE")]
+    block_probe: Option<String>,
+
+    /// HTTP status codes from the block probe that count as blocked
+    #[arg(long, value_delimiter = ',', default_value = "403,429", env = "RUSTTATOR_BLOCK_STATUS")]
+    block_status: Vec<u16>,
+
     /// Tor SOCKS port
-    #[arg(short = 's', long, default_value_t = 9052)]
+    #[arg(short = 's', long, default_value_t = 9052, env = "RUSTTATOR_PORT")]
     port: u16,
 
+    /// Run our own SOCKS5 proxy on this address (e.g. 127.0.0.1:1080), forwarding to Tor
+    #[arg(long, value_name = "ADDR", env = "RUSTTATOR_SOCKS_LISTEN")]
+    socks_listen: Option<SocketAddr>,
+
+    /// Run an HTTP/HTTPS proxy on this address (e.g. 127.0.0.1:8118), forwarding through Tor
+    #[arg(long, value_name = "ADDR", env = "RUSTTATOR_HTTP_LISTEN")]
+    http_listen: Option<SocketAddr>,
+
+    /// Serve Prometheus metrics on http://ADDR/metrics (e.g. 127.0.0.1:9100)
+    #[arg(long, value_name = "ADDR", env = "RUSTTATOR_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Serve the management API on this address or Unix socket
+    /// (e.g. 127.0.0.1:8080 or unix:/run/rusttator.sock)
+    #[arg(long, value_name = "ADDR", env = "RUSTTATOR_API_LISTEN")]
+    api_listen: Option<ApiEndpoint>,
+
+    /// Bearer token required by the management API (random if not set)
+    #[arg(long, env = "RUSTTATOR_API_TOKEN", hide_env_values = true)]
+    api_token: Option<String>,
+
+    /// Seconds to wait for Tor to finish bootstrapping before giving up
+    #[arg(long, value_name = "SECS", default_value_t = 300, env = "RUSTTATOR_BOOTSTRAP_TIMEOUT")]
+    bootstrap_timeout: u64,
+
+    /// Launch and supervise this many Tor processes of our own instead of
+    /// using the Tor at --port/--control-port
+    #[arg(long, value_name = "COUNT", default_value_t = 0, env = "RUSTTATOR_LAUNCH_TOR")]
+    launch_tor: usize,
+
+    /// Tor executable used by --launch-tor
+    #[arg(long, value_name = "PATH", default_value = "tor", env = "RUSTTATOR_TOR_BINARY")]
+    tor_binary: PathBuf,
+
+    /// Additional Tor instance for the pool, as SOCKS_PORT:CONTROL_PORT (repeatable)
+    #[arg(long, value_name = "SOCKS:CONTROL", env = "RUSTTATOR_INSTANCE", value_delimiter = ',')]
+    instance: Vec<String>,
+
+    /// How proxy frontend connections are spread over the Tor instances
+    #[arg(long, value_enum, default_value_t = Balance::RoundRobin, env = "RUSTTATOR_BALANCE")]
+    balance: Balance,
+
+    /// Spread the instances' rotations evenly over the rotation interval
+    #[arg(long, env = "RUSTTATOR_STAGGER")]
+    stagger: bool,
+
+    /// Which proxy frontend connections may share Tor circuits
+    #[arg(long, value_enum, default_value_t = Isolation::Client, env = "RUSTTATOR_ISOLATION")]
+    isolation: Isolation,
+
+    /// Only use exits in these countries, e.g. "de,nl"
+    #[arg(long, value_name = "CC,CC", env = "RUSTTATOR_EXIT_COUNTRIES")]
+    exit_countries: Option<String>,
+
+    /// Never use exits in these countries, e.g. "us,gb"
+    #[arg(long, value_name = "CC,CC", env = "RUSTTATOR_EXCLUDE_COUNTRIES")]
+    exclude_countries: Option<String>,
+
+    /// Stop the proxy frontends whenever traffic might not go through Tor:
+    /// our real IP seen through Tor, a SOCKS port down or a non-Tor exit
+    #[arg(long, env = "RUSTTATOR_KILL_SWITCH")]
+    kill_switch: bool,
+
+    /// Shell command run when the kill switch trips; gets
+    /// RUSTTATOR_LEAK_INSTANCE, RUSTTATOR_LEAK and RUSTTATOR_REAL_IP in its
+    /// environment
+    #[arg(long, value_name = "COMMAND", env = "RUSTTATOR_LEAK_HOOK")]
+    leak_hook: Option<String>,
+
+    /// Tor DNSPort on 127.0.0.1, also tested by dns-leak
+    #[arg(long, value_name = "PORT", env = "RUSTTATOR_DNS_PORT")]
+    dns_port: Option<u16>,
+
+    /// Zone served by `rusttator dns-leak-server`, under which dns-leak
+    /// looks up its test names
+    #[arg(long, value_name = "ZONE", env = "RUSTTATOR_DNS_LEAK_ZONE")]
+    dns_leak_zone: Option<String>,
+
+    /// Report port of that server
+    #[arg(long, value_name = "PORT", default_value_t = 5380, env = "RUSTTATOR_DNS_LEAK_REPORT_PORT")]
+    dns_leak_report_port: u16,
+
+    /// Seconds between the kill switch's leak checks
+    #[arg(long, value_name = "SECS", default_value_t = 30, env = "RUSTTATOR_LEAK_CHECK_INTERVAL")]
+    leak_check_interval: u64,
+
+    /// How many NEWNYM attempts to make per rotation if the exit IP doesn't change
+    #[arg(long, default_value_t = 3, env = "RUSTTATOR_MAX_ROTATION_ATTEMPTS")]
+    max_rotation_attempts: u32,
+
     /// Tor control port
-    #[arg(short = 'c', long, default_value_t = 9063)]
+    #[arg(short = 'c', long, default_value_t = 9063, env = "RUSTTATOR_CONTROL_PORT")]
     control_port: u16,
 
-    /// Tor control password (hashed)
-    #[arg(short = 'p', long)]
+    /// Host the Tor control port listens on
+    #[arg(long, default_value = "127.0.0.1", env = "RUSTTATOR_CONTROL_HOST")]
+    control_host: String,
+
+    /// Tor ControlSocket path; used instead of the TCP control port when set
+    #[arg(long, env = "RUSTTATOR_CONTROL_SOCKET")]
+    control_socket: Option<PathBuf>,
+
+    /// Tor control password (the plain password, not the HashedControlPassword value)
+    #[arg(short = 'p', long, env = "RUSTTATOR_TOR_CONTROL_PASSWORD", hide_env_values = true)]
     password: Option<String>,
-}
 
-#[derive(Debug, Deserialize)]
-struct IpInfo {
-    ip: String,
-}
+    /// Read the Tor control password from a file (takes precedence over --password)
+    #[arg(long, env = "RUSTTATOR_PASSWORD_FILE")]
+    password_file: Option<PathBuf>,
+
+    /// IP echo service as "URL [json=PATH] [timeout=SECS]", tried in the
+    /// order given (repeat the flag for more; URLs may contain commas);
+    /// plain text answers unless json=PATH is set
+    #[arg(
+        long,
+        value_name = "SPEC",
+        default_values = [
+            "https://api.ipify.org?format=json json=ip",
+            "https://icanhazip.com",
+            "https://check.torproject.org/api/ip json=IP",
+        ],
+        env = "RUSTTATOR_IP_PROVIDER"
+    )]
+    ip_provider: Vec<String>,
+
+    /// How many IP providers must report the same address
+    #[arg(long, value_name = "COUNT", default_value_t = 1, env = "RUSTTATOR_IP_QUORUM")]
+    ip_quorum: usize,
+
+    /// Service returning {"IsTor": true} when reached through Tor, asked
+    /// only when the consensus can't confirm the exit IP
+    /// (e.g. https://check.torproject.org/api/ip)
+    #[arg(long, value_name = "URL", env = "RUSTTATOR_TOR_CHECK_URL")]
+    tor_check_url: Option<String>,
+
+    /// Where exit IPs are located, in order: maxmind, tor, and remote
+    /// (--geo-url, only asked for addresses the others don't know)
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "maxmind,tor", env = "RUSTTATOR_GEO_SOURCE")]
+    geo_source: Vec<GeoSource>,
+
+    /// Tor's IPv4 GeoIP file [default: /usr/share/tor/geoip, if present]
+    #[arg(long, value_name = "PATH", env = "RUSTTATOR_GEOIP_FILE")]
+    geoip_file: Option<PathBuf>,
+
+    /// Tor's IPv6 GeoIP file [default: /usr/share/tor/geoip6, if present]
+    #[arg(long, value_name = "PATH", env = "RUSTTATOR_GEOIP6_FILE")]
+    geoip6_file: Option<PathBuf>,
+
+    /// MaxMind country, city or ASN database (.mmdb) (repeatable)
+    #[arg(long, value_name = "PATH", value_delimiter = ',', env = "RUSTTATOR_GEOIP_MMDB")]
+    geoip_mmdb: Vec<PathBuf>,
+
+    /// Geolocation service for --geo-source remote; {ip} is replaced by the
+    /// address to locate
+    #[arg(long, value_name = "URL", default_value = "https://ipapi.co/{ip}/json/", env = "RUSTTATOR_GEO_URL")]
+    geo_url: String,
+
+    /// Seconds before an IP or Tor check request gives up
+    #[arg(long, value_name = "SECS", default_value_t = 10, env = "RUSTTATOR_CHECK_TIMEOUT")]
+    check_timeout: u64,
+
+    /// Seconds before any other request through Tor gives up
+    #[arg(long, value_name = "SECS", default_value_t = 30, env = "RUSTTATOR_REQUEST_TIMEOUT")]
+    request_timeout: u64,
 
-#[derive(Debug, Deserialize)]
-struct GeoInfo {
-    country_name: Option<String>,
-    country_code: Option<String>,
-    city: Option<String>,
-    #[allow(dead_code)]
-    region: Option<String>,
+    /// Seconds to wait for Tor to report a built circuit
+    #[arg(long, value_name = "SECS", default_value_t = 30, env = "RUSTTATOR_CIRCUIT_TIMEOUT")]
+    circuit_timeout: u64,
+
+    /// How many times to try verifying the Tor connection when creating a client
+    #[arg(long, value_name = "COUNT", default_value_t = 3, env = "RUSTTATOR_CLIENT_RETRIES")]
+    client_retries: u32,
+
+    /// Seconds between those attempts
+    #[arg(long, value_name = "SECS", default_value_t = 10, env = "RUSTTATOR_RETRY_DELAY")]
+    retry_delay: u64,
 }
 
-#[derive(Debug)]
-struct Circuit {
-    id: String,
-    status: String,
-    path: Vec<String>,
-    purpose: String,
+#[derive(Subcommand, Debug, Clone)]
+enum CliCommand {
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Check that lookups through Tor's SOCKS port (and --dns-port) never
+    /// reach our own resolver; needs --dns-leak-zone
+    DnsLeak,
+    /// Serve --dns-leak-zone for dns-leak: answer every name under it with
+    /// ANSWER and report which resolvers asked
+    DnsLeakServer {
+        /// Public IPv4 address of this server, given as the answer
+        answer: Ipv4Addr,
+        /// Where to serve DNS
+        #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:53")]
+        listen: SocketAddr,
+        /// Where to serve reports; dns-leak expects --dns-leak-report-port
+        #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:5380")]
+        report_listen: SocketAddr,
+    },
 }
 
-struct TorControl {
-    stream: TcpStream,
-    reader: BufReader<TcpStream>,
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigAction {
+    /// Print the effective configuration merged from the command line,
+    /// environment, config file and defaults
+    Print,
 }
 
-impl TorControl {
-    fn new(control_port: u16) -> Result<Self> {
-        let stream = TcpStream::connect(format!("127.0.0.1:{}", control_port))
-            .context("Failed to connect to Tor control port")?;
-        let reader = BufReader::new(stream.try_clone()?);
-        Ok(Self { stream, reader })
-    }
-
-    fn get_protocol_info(&mut self) -> Result<Vec<String>> {
-        self.send_command("PROTOCOLINFO")?;
-        self.read_response()
-    }
-
-    fn authenticate(&mut self, _password: Option<String>) -> Result<()> {
-        // First get protocol info
-        let proto_info = self.get_protocol_info()?;
-        info!("Protocol info response: {:?}", proto_info);
-
-        // Parse authentication methods from PROTOCOLINFO response
-        let mut methods = Vec::new();
-        let mut cookie_file = None;
-        
-        for line in &proto_info {
-            if line.contains("AUTH METHODS=") {
-                if let Some(methods_str) = line.split("METHODS=").nth(1) {
-                    methods = methods_str
-                        .split(',')
-                        .map(|s| s.trim().trim_matches(|c| c == '"' || c == ' '))
-                        .collect();
-                }
-                if line.contains("COOKIEFILE=") {
-                    if let Some(file) = line.split("COOKIEFILE=\"").nth(1) {
-                        cookie_file = Some(file.trim_end_matches('"').to_string());
-                    }
-                }
+impl Args {
+    /// Checks everything that can be checked before connecting to Tor,
+    /// except the tuning, which [`Args::tuning`] checks as it builds it.
+    fn validate(&self) -> Result<()> {
+        if self.rotation_policy()?.needs_block_probe() && self.block_probe.is_none() {
+            return Err(anyhow!("the block rotation policy needs block-probe to detect blocks"));
+        }
+        self.exit_selection()?;
+        self.instance_endpoints()?;
+        for (name, url) in [
+            ("tor-check-url", self.tor_check_url.as_ref()),
+            ("geo-url", Some(&self.geo_url)),
+        ] {
+            let Some(url) = url else {
+                continue;
+            };
+            reqwest::Url::parse(url).map_err(|e| anyhow!("Invalid {} {:?}: {}", name, url, e))?;
+        }
+        if !self.geo_url.contains("{ip}") {
+            return Err(anyhow!("geo-url {:?} must contain {{ip}} where the address goes", self.geo_url));
+        }
+        for (name, secs) in [
+            ("check-timeout", self.check_timeout),
+            ("request-timeout", self.request_timeout),
+            ("circuit-timeout", self.circuit_timeout),
+            ("bootstrap-timeout", self.bootstrap_timeout),
+            ("leak-check-interval", self.leak_check_interval),
+        ] {
+            if secs == 0 {
+                return Err(anyhow!("{} must be at least 1 second", name));
             }
         }
-
-        info!("Supported auth methods: {:?}", methods);
-        if let Some(file) = &cookie_file {
-            info!("Cookie file: {}", file);
+        if self.client_retries == 0 {
+            return Err(anyhow!("client-retries must be at least 1"));
         }
+        if matches!(self.command, Some(CliCommand::DnsLeak | CliCommand::DnsLeakServer { .. }))
+            && self.dns_leak_zone.is_none()
+        {
+            return Err(anyhow!("dns-leak-zone is required to check for DNS leaks"));
+        }
+        if self.leak_hook.is_some() && !self.kill_switch {
+            return Err(anyhow!("leak-hook is only run by the kill switch; add kill-switch"));
+        }
+        Ok(())
+    }
 
-        // Try COOKIE authentication first
-        if let Some(cookie_path) = cookie_file.clone() {
-            if methods.contains(&"COOKIE") {
-                info!("Attempting COOKIE authentication");
-                
-                // Read the cookie file
-                let cookie_data = match fs::read(&cookie_path) {
-                    Ok(data) => {
-                        info!("Successfully read cookie file, length: {}", data.len());
-                        info!("Cookie data (hex): {}", hex::encode(&data));
-                        data
-                    }
-                    Err(e) => {
-                        warn!("Failed to read cookie file: {}", e);
-                        return Err(anyhow!("Failed to read cookie file: {}", e));
-                    }
-                };
-
-                // Send the authentication command with the cookie data
-                let auth_cmd = format!(
-                    "AUTHENTICATE {}",
-                    hex::encode(&cookie_data).to_uppercase()
-                );
-                info!("Sending authentication command: {}", auth_cmd);
-                self.send_command(&auth_cmd)?;
-                let response = self.read_response()?;
-                info!("Authentication response: {:?}", response);
-                
-                if response.iter().any(|line| line == "OK") {
-                    info!("Successfully authenticated with COOKIE");
-                    return Ok(());
-                }
-                warn!("COOKIE authentication failed, response: {:?}", response);
-            }
+    fn tuning(&self) -> Result<Tuning> {
+        let check_timeout = Duration::from_secs(self.check_timeout);
+        let providers = self
+            .ip_provider
+            .iter()
+            .map(|spec| {
+                HttpProvider::parse(spec, check_timeout)
+                    .map(|provider| Box::new(provider) as Box<dyn IpProvider>)
+                    .with_context(|| format!("Invalid --ip-provider {:?}", spec))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Tuning {
+            ip_providers: Arc::new(ProviderChain::new(providers, self.ip_quorum)?),
+            tor_check_url: self.tor_check_url.clone(),
+            geo: Arc::new(self.geolocator(check_timeout)?),
+            check_timeout,
+            request_timeout: Duration::from_secs(self.request_timeout),
+            circuit_timeout: Duration::from_secs(self.circuit_timeout),
+            client_retries: self.client_retries,
+            retry_delay: Duration::from_secs(self.retry_delay),
+        })
+    }
 
-            // Try SAFECOOKIE authentication if COOKIE failed
-            if methods.contains(&"SAFECOOKIE") {
-                info!("Attempting SAFECOOKIE authentication");
-                
-                // Read the cookie file
-                let cookie_data = match fs::read(&cookie_path) {
-                    Ok(data) => {
-                        info!("Successfully read cookie file, length: {}", data.len());
-                        info!("Cookie data (hex): {}", hex::encode(&data));
-                        data
-                    }
-                    Err(e) => {
-                        warn!("Failed to read cookie file: {}", e);
-                        return Err(anyhow!("Failed to read cookie file: {}", e));
+    /// Opens the databases of the selected geolocation sources.
+    fn geolocator(&self, timeout: Duration) -> Result<Geolocator> {
+        let mut databases: Vec<Box<dyn GeoDatabase>> = Vec::new();
+        let mut remote = None;
+        for source in &self.geo_source {
+            match source {
+                GeoSource::Maxmind => {
+                    for path in &self.geoip_mmdb {
+                        databases.push(Box::new(MaxMindDb::open(path)?));
                     }
-                };
-
-                // Generate client nonce
-                let mut client_nonce = vec![0u8; 32];
-                rand::thread_rng().fill(&mut client_nonce[..]);
-                let client_nonce_hex = hex::encode(&client_nonce).to_uppercase();
-                info!("Generated client nonce (hex): {}", client_nonce_hex);
-
-                // Send AUTHCHALLENGE command with our nonce
-                let auth_cmd = format!("AUTHCHALLENGE SAFECOOKIE {}", client_nonce_hex);
-                info!("Sending AUTHCHALLENGE command: {}", auth_cmd);
-                self.send_command(&auth_cmd)?;
-                let response = self.read_response()?;
-                info!("AUTHCHALLENGE response: {:?}", response);
-                
-                // Parse the server hash and nonce from response
-                let (server_hash, server_nonce) = match response.iter().find(|line| line.contains("SERVERHASH=")) {
-                    Some(line) => {
-                        info!("Found AUTHCHALLENGE line: {}", line);
-                        let parts: Vec<&str> = line.split(' ').collect();
-                        info!("Split parts: {:?}", parts);
-                        
-                        let server_hash = parts.iter()
-                            .find(|p| p.starts_with("SERVERHASH="))
-                            .and_then(|p| Some(&p[11..]))
-                            .ok_or_else(|| anyhow!("Missing SERVERHASH in response"))?;
-                            
-                        let server_nonce = parts.iter()
-                            .find(|p| p.starts_with("SERVERNONCE="))
-                            .and_then(|p| Some(&p[12..]))
-                            .ok_or_else(|| anyhow!("Missing SERVERNONCE in response"))?;
-                            
-                        info!("Server hash: {}", server_hash);
-                        info!("Server nonce: {}", server_nonce);
-                        
-                        match (hex::decode(server_nonce), hex::decode(server_hash)) {
-                            (Ok(nonce), Ok(hash)) => {
-                                info!("Decoded server nonce length: {}", nonce.len());
-                                info!("Decoded server hash length: {}", hash.len());
-                                (hash, nonce)
-                            }
-                            _ => {
-                                warn!("Failed to decode server nonce or hash");
-                                return Err(anyhow!("Failed to decode server nonce or hash"));
-                            }
-                        }
-                    }
-                    None => {
-                        warn!("Failed to get server nonce from AUTHCHALLENGE response");
-                        return Err(anyhow!("Failed to get server nonce from AUTHCHALLENGE response"));
-                    }
-                };
-
-                // Compute HMAC
-                let mut auth_input = Vec::new();
-                auth_input.extend_from_slice(&cookie_data);
-                auth_input.extend_from_slice(&client_nonce);
-                auth_input.extend_from_slice(&server_nonce);
-                info!("Auth input length: {}", auth_input.len());
-                info!("Auth input (hex): {}", hex::encode(&auth_input).to_uppercase());
-
-                let mut mac = match Hmac::<Sha256>::new_from_slice(b"Tor safe cookie authentication server-to-controller hash") {
-                    Ok(mac) => mac,
-                    Err(e) => {
-                        warn!("Failed to create HMAC: {}", e);
-                        return Err(anyhow!("Failed to create HMAC: {}", e));
-                    }
-                };
-                mac.update(&auth_input);
-                let computed_server_hash = mac.finalize().into_bytes();
-                info!("Computed server hash (hex): {}", hex::encode(&computed_server_hash).to_uppercase());
-                info!("Received server hash (hex): {}", hex::encode(&server_hash).to_uppercase());
-
-                // Verify server hash
-                if computed_server_hash.as_slice() != server_hash {
-                    warn!("Server hash verification failed");
-                    return Err(anyhow!("Server hash verification failed"));
                 }
-                info!("Server hash verified successfully");
-
-                // Compute client hash
-                let mut mac = match Hmac::<Sha256>::new_from_slice(b"Tor safe cookie authentication controller-to-server hash") {
-                    Ok(mac) => mac,
-                    Err(e) => {
-                        warn!("Failed to create HMAC: {}", e);
-                        return Err(anyhow!("Failed to create HMAC: {}", e));
+                GeoSource::Tor => {
+                    let installed = |path: &str| Some(PathBuf::from(path)).filter(|path| path.exists());
+                    let geoip = self.geoip_file.clone().or_else(|| installed(geoip::TOR_GEOIP));
+                    let geoip6 = self.geoip6_file.clone().or_else(|| installed(geoip::TOR_GEOIP6));
+                    if geoip.is_some() || geoip6.is_some() {
+                        databases.push(Box::new(TorGeoIp::load(geoip.as_deref(), geoip6.as_deref())?));
                     }
-                };
-                mac.update(&auth_input);
-                let client_hash = mac.finalize().into_bytes();
-                info!("Client hash (hex): {}", hex::encode(&client_hash).to_uppercase());
-
-                // Send the authentication command
-                let auth_cmd = format!(
-                    "AUTHENTICATE {}",
-                    hex::encode(client_hash).to_uppercase()
-                );
-                info!("Sending authentication command: {}", auth_cmd);
-                self.send_command(&auth_cmd)?;
-                let response = self.read_response()?;
-                info!("Authentication response: {:?}", response);
-                
-                if response.iter().any(|line| line == "OK") {
-                    info!("Successfully authenticated with SAFECOOKIE");
-                    return Ok(());
                 }
-                warn!("SAFECOOKIE authentication failed, response: {:?}", response);
+                GeoSource::Remote => remote = Some(self.geo_url.clone()),
             }
         }
-
-        // Try null authentication as last resort
-        if methods.contains(&"NULL") || methods.is_empty() {
-            info!("Attempting null authentication");
-            self.send_command("AUTHENTICATE")?;
-            let response = self.read_response()?;
-            if response.iter().any(|line| line == "OK") {
-                info!("Successfully authenticated with null authentication");
-                return Ok(());
-            }
-        }
-
-        Err(anyhow!("Failed to authenticate with Tor control port"))
+        Ok(Geolocator::new(databases, remote, timeout))
     }
 
-    fn send_command(&mut self, cmd: &str) -> Result<()> {
-        self.stream.write_all(format!("{}\r\n", cmd).as_bytes())?;
-        Ok(())
+    fn exit_selection(&self) -> Result<ExitSelection> {
+        let parse = |list: &Option<String>| list.as_deref().map(parse_countries).transpose();
+        Ok(ExitSelection {
+            countries: parse(&self.exit_countries)?.unwrap_or_default(),
+            excluded: parse(&self.exclude_countries)?.unwrap_or_default(),
+        })
     }
 
-    fn read_response(&mut self) -> Result<Vec<String>> {
-        let mut response = Vec::new();
-        let mut line = String::new();
-        let mut is_data = false;
-        
-        loop {
-            line.clear();
-            self.reader.read_line(&mut line)?;
-            let trimmed = line.trim();
-            info!("Raw response line: {}", trimmed);
-            
-            if trimmed.starts_with("250+") {
-                is_data = true;
-                response.push(trimmed[4..].to_string());
-            } else if trimmed.starts_with("250-") {
-                response.push(trimmed[4..].to_string());
-            } else if trimmed.starts_with("250 ") {
-                response.push(trimmed[4..].to_string());
-                break;
-            } else if trimmed.starts_with("515 ") || trimmed.starts_with("551 ") || trimmed.starts_with("550 ") {
-                return Err(anyhow::anyhow!("Tor control error: {}", trimmed));
-            } else if is_data && !trimmed.is_empty() {
-                response.push(trimmed.to_string());
-            } else if trimmed.starts_with("AUTHCHALLENGE ") {
-                response.push(trimmed.to_string());
-            }
+    /// --rotate, or the --interval policy.
+    fn policy_spec(&self) -> String {
+        match &self.rotate {
+            Some(spec) => spec.clone(),
+            None => format!("interval:{}", self.interval),
         }
-        
-        Ok(response)
     }
 
-    fn get_circuit_info(&mut self) -> Result<Vec<Circuit>> {
-        self.send_command("GETINFO circuit-status")?;
-        let response = self.read_response()?;
-        
-        let mut circuits = Vec::new();
-        for line in response {
-            if line.starts_with("circuit-status=") {
-                continue;
-            }
-            
-            // Parse circuit information
-            let mut parts = line.split_whitespace();
-            let id = parts.next().unwrap_or("").to_string();
-            let status = parts.next().unwrap_or("").to_string();
-            
-            if let Some(path_str) = parts.next() {
-                let path: Vec<String> = path_str.split(',')
-                    .map(|s| {
-                        if let Some(idx) = s.find('~') {
-                            s[1..idx].to_string()
-                        } else {
-                            s[1..].to_string()
-                        }
-                    })
-                    .collect();
-                
-                let purpose = parts.find(|p| p.starts_with("PURPOSE="))
-                    .map(|p| p.replace("PURPOSE=", ""))
-                    .unwrap_or_else(|| "UNKNOWN".to_string());
-                
-                circuits.push(Circuit {
-                    id,
-                    status,
-                    path,
-                    purpose,
-                });
-            }
-        }
-        
-        Ok(circuits)
+    fn rotation_policy(&self) -> Result<Box<dyn RotationPolicy>> {
+        policy::parse_policy(&self.policy_spec())
     }
 
-    async fn switch_identity(&mut self) -> Result<()> {
-        // Close all circuits first
-        self.send_command("SIGNAL CLEARDNSCACHE")?;
-        self.read_response()?;
-        
-        // Request new identity
-        self.send_command("SIGNAL NEWNYM")?;
-        self.read_response()?;
-
-        // Wait for the new circuit to be established
-        time::sleep(Duration::from_secs(10)).await;
-        Ok(())
+    /// SOCKS port and control endpoint of every running Tor instance to
+    /// use, starting with the one given by --port and --control-* unless we
+    /// launch our own.
+    fn instance_endpoints(&self) -> Result<Vec<(u16, ControlEndpoint)>> {
+        let mut endpoints = Vec::new();
+        if self.launch_tor == 0 {
+            endpoints.push((self.port, self.control_endpoint()));
+        }
+        for spec in &self.instance {
+            let (socks, control) = spec
+                .split_once(':')
+                .and_then(|(socks, control)| Some((socks.parse().ok()?, control.parse().ok()?)))
+                .ok_or_else(|| anyhow!("Invalid --instance {:?} (expected SOCKS_PORT:CONTROL_PORT)", spec))?;
+            endpoints.push((
+                socks,
+                ControlEndpoint::Tcp {
+                    host: self.control_host.clone(),
+                    port: control,
+                },
+            ));
+        }
+        Ok(endpoints)
     }
 
-    fn get_node_info(&mut self, node_id: &str) -> Result<(String, String)> {
-        self.send_command(&format!("GETINFO ns/id/{}", node_id))?;
-        let response = self.read_response()?;
-        
-        for line in response {
-            if line.contains("r ") {
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                if parts.len() > 3 {
-                    // Return (nickname, country)
-                    return Ok((parts[1].to_string(), parts[3].to_string()));
-                }
-            }
+    fn control_endpoint(&self) -> ControlEndpoint {
+        match &self.control_socket {
+            Some(path) => ControlEndpoint::Unix(path.clone()),
+            None => ControlEndpoint::Tcp {
+                host: self.control_host.clone(),
+                port: self.control_port,
+            },
         }
-        
-        Ok((node_id[..6].to_string(), "??".to_string()))
     }
 
-    async fn wait_for_circuits(&mut self) -> Result<()> {
-        for _ in 0..30 {
-            let circuits = self.get_circuit_info()?;
-            if circuits.iter().any(|c| c.status == "BUILT" && c.purpose.contains("GENERAL")) {
-                return Ok(());
+    /// Resolves the control password from `--password`, the environment or
+    /// `--password-file`.
+    fn control_password(&self) -> Result<Option<String>> {
+        if let Some(path) = &self.password_file {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read password file {}", path.display()))?;
+            let password = contents.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                return Err(anyhow!("Password file {} is empty", path.display()));
             }
-            time::sleep(Duration::from_secs(1)).await;
+            return Ok(Some(password.to_string()));
         }
-        Err(anyhow::anyhow!("Timeout waiting for circuits to be built"))
+        Ok(self.password.clone())
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct TorCheckResponse {
-    #[serde(rename = "IsTor")]
-    is_tor: bool,
-}
-
-async fn verify_tor_connection(client: &reqwest::Client) -> Result<bool> {
+async fn verify_tor_connection(
+    client: &reqwest::Client,
+    verifier: &ExitVerifier,
+    tuning: &Tuning,
+    metrics: &Metrics,
+) -> Result<bool> {
     info!("Attempting to get IP through proxy...");
     // First try to get our IP through the proxy
-    let ip_response = match client
-        .get("https://api.ipify.org?format=json")
-        .timeout(Duration::from_secs(10))
-        .send()
-        .await {
-            Ok(resp) => {
-                info!("Successfully connected to IP service");
-                resp
-            },
-            Err(e) => {
-                warn!("Failed to connect to IP service: {}", e);
-                return Err(anyhow!("Failed to connect to IP service: {}", e));
-            }
-        };
-
-    let _ip_text = match ip_response.text().await {
-        Ok(text) => {
-            info!("Successfully got IP response: {}", text);
-            text
-        },
+    let ip = match tuning.ip_providers.lookup(client, metrics).await {
+        Ok(ip) => {
+            info!("Successfully got IP through proxy: {}", ip);
+            ip
+        }
         Err(e) => {
-            warn!("Failed to get IP response text: {}", e);
-            return Err(anyhow!("Failed to get IP response text: {}", e));
+            warn!("Failed to get IP through proxy: {:#}", e);
+            return Err(anyhow!("Failed to get IP through proxy: {:#}", e));
         }
     };
 
-    info!("Verifying if IP is a Tor exit node...");
-    // Then verify if it's a Tor exit node
-    let tor_check = match client
-        .get("https://check.torproject.org/api/ip")
-        .timeout(Duration::from_secs(10))
-        .send()
-        .await {
-            Ok(resp) => {
-                info!("Successfully connected to Tor check service");
-                resp
-            },
-            Err(e) => {
-                warn!("Failed to connect to Tor check service: {}", e);
-                return Err(anyhow!("Failed to connect to Tor check service: {}", e));
-            }
-        };
-
-    match tor_check.json::<TorCheckResponse>().await {
-        Ok(response) => {
-            if response.is_tor {
-                info!("✓ Successfully verified Tor connection");
-                Ok(true)
-            } else {
-                warn!("Connection is not using Tor");
-                Ok(false)
-            }
-        }
-        Err(e) => {
-            warn!("Failed to parse Tor check response: {}", e);
-            Ok(false)
-        }
-    }
+    is_tor_exit(ip, client, Some(verifier), tuning, metrics).await
 }
 
-async fn get_ip_info(client: &reqwest::Client) -> Result<(String, Option<GeoInfo>, bool)> {
+/// Our address as seen through `client`, where it is, and whether it's a
+/// Tor exit (checked against `verifier`'s Tor, if given).
+async fn get_ip_info(
+    client: &reqwest::Client,
+    verifier: Option<&ExitVerifier>,
+    tuning: &Tuning,
+    metrics: &Metrics,
+) -> Result<(String, Option<GeoInfo>, bool)> {
     // First get the IP address
-    let ip_info = client
-        .get("https://api.ipify.org?format=json")
-        .send()
-        .await?
-        .json::<IpInfo>()
-        .await?;
+    let ip = tuning.ip_providers.lookup(client, metrics).await?;
 
     // Check if it's a Tor exit node
-    let is_tor = verify_tor_connection(client).await?;
+    let is_tor = is_tor_exit(ip, client, verifier, tuning, metrics).await?;
 
     // Then try to get location info
-    let geo_info = match client
-        .get(&format!("https://ipapi.co/{}/json/", ip_info.ip))
-        .send()
-        .await
-    {
-        Ok(response) => match response.json::<GeoInfo>().await {
-            Ok(info) => Some(info),
-            Err(e) => {
-                warn!("Failed to parse location info: {}", e);
-                None
-            }
-        },
-        Err(e) => {
-            warn!("Failed to fetch location info: {}", e);
-            None
-        }
-    };
+    let geo_info = tuning.geo.locate(ip, client, metrics).await;
 
-    Ok((ip_info.ip, geo_info, is_tor))
+    Ok((ip.to_string(), geo_info, is_tor))
 }
 
 async fn verify_tor_proxy(port: u16) -> Result<bool> {
     // Try to connect to the SOCKS proxy first
-    match TcpStream::connect(format!("127.0.0.1:{}", port)) {
+    match TcpStream::connect(format!("127.0.0.1:{}", port)).await {
         Ok(_) => {
             info!("✓ Successfully connected to Tor SOCKS proxy on port {}", port);
             Ok(true)
@@ -516,7 +533,12 @@ async fn verify_tor_proxy(port: u16) -> Result<bool> {
     }
 }
 
-async fn create_tor_client(port: u16) -> Result<reqwest::Client> {
+async fn create_tor_client(
+    port: u16,
+    verifier: &ExitVerifier,
+    tuning: &Tuning,
+    metrics: &Metrics,
+) -> Result<reqwest::Client> {
     let proxy_url = format!("socks5://127.0.0.1:{}", port);
     info!("Creating Tor client with proxy: {}", proxy_url);
     
@@ -526,7 +548,7 @@ async fn create_tor_client(port: u16) -> Result<reqwest::Client> {
     
     let client = reqwest::Client::builder()
         .proxy(proxy)
-        .timeout(Duration::from_secs(30))
+        .timeout(tuning.request_timeout)
         .danger_accept_invalid_certs(true)
         .build()
         .context("Failed to build client")?;
@@ -534,29 +556,18 @@ async fn create_tor_client(port: u16) -> Result<reqwest::Client> {
 
     // Verify Tor connection
     info!("Verifying Tor connection...");
-    let mut retries = 3;
-    while retries > 0 {
-        match verify_tor_connection(&client).await {
+    for attempt in 1..=tuning.client_retries {
+        match verify_tor_connection(&client, verifier, tuning, metrics).await {
             Ok(true) => {
                 info!("✓ Successfully connected to Tor network");
                 return Ok(client);
             }
-            Ok(false) => {
-                warn!("Attempt {} failed: Connection is not using Tor", 4 - retries);
-                retries -= 1;
-                if retries > 0 {
-                    info!("Waiting 10 seconds before retry...");
-                    time::sleep(Duration::from_secs(10)).await;
-                }
-            }
-            Err(e) => {
-                warn!("Attempt {} failed: {}", 4 - retries, e);
-                retries -= 1;
-                if retries > 0 {
-                    info!("Waiting 10 seconds before retry...");
-                    time::sleep(Duration::from_secs(10)).await;
-                }
-            }
+            Ok(false) => warn!("Attempt {} failed: Connection is not using Tor", attempt),
+            Err(e) => warn!("Attempt {} failed: {}", attempt, e),
+        }
+        if attempt < tuning.client_retries {
+            info!("Waiting {} seconds before retry...", tuning.retry_delay.as_secs());
+            time::sleep(tuning.retry_delay).await;
         }
     }
 
@@ -565,126 +576,337 @@ async fn create_tor_client(port: u16) -> Result<reqwest::Client> {
 
 fn format_location(geo: &GeoInfo) -> String {
     let country = geo.country_name
-        .as_ref()
-        .or(geo.country_code.as_ref())
-        .map(|s| s.as_str())
-        .unwrap_or("Unknown");
-    
-    let city = geo.city
-        .as_ref()
-        .map(|s| s.as_str())
+        .as_deref()
+        .or(geo.country_code.as_deref())
         .unwrap_or("Unknown");
     
-    format!("{}, {}", city, country)
+    // Tor's GeoIP files only know countries
+    let mut location = match geo.city.as_deref() {
+        Some(city) => format!("{}, {}", city, country),
+        None => country.to_string(),
+    };
+    if let Some(asn) = &geo.asn {
+        location.push_str(&format!(" · {}", asn));
+        if let Some(org) = &geo.org {
+            location.push_str(&format!(" {}", org));
+        }
+    }
+    location
 }
 
-fn format_circuit_path(_circuit: &Circuit, nodes: &[(String, String)]) -> String {
+fn format_circuit_path(circuit: &Circuit, relays: &[Option<RelayInfo>]) -> String {
     let mut path = String::new();
-    for (i, (name, country)) in nodes.iter().enumerate() {
+    for (i, (hop, relay)) in circuit.path.iter().zip(relays).enumerate() {
         if i > 0 {
             path.push_str(" → ");
         }
-        path.push_str(&format!("{} [{}]", name, country));
+        match relay {
+            Some(relay) => path.push_str(&format!(
+                "{} [{}]",
+                relay.nickname,
+                relay.country.as_deref().unwrap_or("??").to_uppercase()
+            )),
+            None => path.push_str(&format!("{} [??]", hop.short_name())),
+        }
     }
     path
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+fn format_exit(relay: &RelayInfo) -> String {
+    let mut exit = format!("exit {}:{}", relay.address, relay.or_port);
+    if let Some(bandwidth) = relay.bandwidth {
+        exit.push_str(&format!(" · weight {}", bandwidth));
+    }
+    if !relay.flags.is_empty() {
+        exit.push_str(&format!(" · {}", relay.flags.join(" ")));
+    }
+    if let Some(policy) = &relay.exit_policy {
+        exit.push_str(&format!(" · {}", policy));
+    }
+    exit
+}
+
+/// What the status API shows for an exit IP.
+fn exit_status(ip: &str, geo: Option<&GeoInfo>, is_tor: Option<bool>) -> ExitStatus {
+    ExitStatus {
+        ip: Some(ip.to_string()),
+        location: geo.map(format_location),
+        country: geo.and_then(|geo| geo.country_code.clone()),
+        asn: geo.and_then(|geo| geo.asn.clone()),
+        is_tor,
+        checked_at: Some(chrono::Utc::now()),
+    }
+}
+
+/// Locates and verifies the exit a rotation moved to.
+async fn check_new_exit(
+    ip: &str,
+    outcome: &RotationOutcome,
+    client: &reqwest::Client,
+    instance: &TorInstance,
+    tuning: &Tuning,
+    metrics: &Metrics,
+) -> ExitStatus {
+    let Ok(address) = ip.parse() else {
+        return exit_status(ip, None, None);
+    };
+    let geo = tuning.geo.locate(address, client, metrics).await;
+    let is_tor = match is_tor_exit(address, client, Some(&instance.exit_verifier), tuning, metrics).await {
+        Ok(is_tor) => Some(is_tor),
+        Err(e) => {
+            warn!("Failed to verify new exit {}: {:#}", ip, e);
+            None
+        }
+    };
+    let mut exit = exit_status(ip, geo.as_ref(), is_tor);
+    exit.country = exit.country.or_else(|| outcome.exit_country.clone());
+    exit
+}
+
+async fn change_exits(manager: &mut ExitManager, rotator: &mut Rotator, selection: &ExitSelection) {
+    match manager.apply(selection).await {
+        Ok(()) => rotator.set_exit_selection(selection.clone()),
+        Err(e) => warn!("Failed to change exit countries: {}", e),
+    }
+}
+
+/// Puts our exit selection back into a Tor that restarted from its torrc.
+async fn reapply_exits(manager: &mut ExitManager, selection: &ExitSelection) {
+    if selection.is_empty() {
+        return;
+    }
+    if let Err(e) = manager.apply(selection).await {
+        warn!("Failed to reapply exit countries after Tor restarted: {}", e);
+    }
+}
+
+/// Logs Tor's async events; warnings and failures are surfaced at `warn`.
+async fn log_tor_events(mut events: broadcast::Receiver<TorEvent>) {
+    use broadcast::error::RecvError;
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                warn!("Event logger fell behind, skipped {} Tor events", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        match event {
+            TorEvent::Circ(circuit) if circuit.status == CircuitStatus::Failed => {
+                warn!("Circuit #{} failed: {}", circuit.id, circuit.failure_reason());
+            }
+            TorEvent::Circ(circuit) => debug!("Circuit {}", circuit),
+            TorEvent::Stream(stream) => debug!(
+                "Stream {} {} on circuit {} to {}{}",
+                stream.id,
+                stream.status,
+                stream.circuit_id,
+                stream.target,
+                stream.reason.map(|r| format!(" ({})", r)).unwrap_or_default()
+            ),
+            TorEvent::OrConn(conn) if conn.status == "FAILED" => warn!(
+                "OR connection to {} failed: {}",
+                conn.target,
+                conn.reason.as_deref().unwrap_or("unknown reason")
+            ),
+            TorEvent::OrConn(conn) => debug!("OR connection to {} {}", conn.target, conn.status),
+            TorEvent::Bandwidth { read, written } => {
+                debug!("Bandwidth: {} B read, {} B written", read, written);
+            }
+            // Progress and its problems are reported while waiting for bootstrap
+            TorEvent::StatusClient(status) if status.action == "BOOTSTRAP" => {}
+            TorEvent::StatusClient(status) | TorEvent::StatusGeneral(status) => {
+                if let Some(problem) = bootstrap::describe_problem(&status) {
+                    warn!("{}", problem);
+                } else if status.severity == "NOTICE" {
+                    debug!("Tor status: {} {:?}", status.action, status.args);
+                } else {
+                    warn!("Tor status ({}): {} {:?}", status.severity, status.action, status.args);
+                }
+            }
+            TorEvent::NewDesc(relays) => debug!("{} new relay descriptors", relays.len()),
+            TorEvent::Log { severity, message } => debug!("Tor [{}] {}", severity, message),
+            TorEvent::Guard(guard) => info!("Guard {} {} is now {}", guard.kind, guard.name, guard.status),
+            TorEvent::Other { keyword, text } => debug!("Tor event {}: {}", keyword, text),
+        }
+    }
+}
 
-    println!("\x1b[31m{}\x1b[0m", r#"
+const BANNER: &str = r#"
 ██████╗ ██╗   ██╗███████╗████████╗████████╗ █████╗ ████████╗ ██████╗ ██████╗ 
 ██╔══██╗██║   ██║██╔════╝╚══██╔══╝╚══██╔══╝██╔══██╗╚══██╔══╝██╔═══██╗██╔══██╗
 ██████╔╝██║   ██║███████╗   ██║      ██║   ███████║   ██║   ██║   ██║██████╔╝
 ██╔══██╗██║   ██║╚════██║   ██║      ██║   ██╔══██║   ██║   ██║   ██║██╔══██╗
 ██║  ██║╚██████╔╝███████║   ██║      ██║   ██║  ██║   ██║   ╚██████╔╝██║  ██║
 ╚═╝  ╚═╝ ╚═════╝ ╚══════╝   ╚═╝      ╚═╝   ╚═╝  ╚═╝   ╚═╝    ╚═════╝ ╚═╝  ╚═╝
-    "#);
-    println!("\x1b[33m{}\x1b[0m", "Anonymous Internet Access Through Tor");
-    println!("\x1b[32m{}\x1b[0m", "Version 0.1.0");
-    println!();
+    "#;
 
-    let args = Args::parse();
-    
+/// Connects to and authenticates with one Tor instance, subscribing to its
+/// events.
+async fn connect_instance(
+    socks_port: u16,
+    endpoint: &ControlEndpoint,
+    password: Option<String>,
+) -> Result<TorControl> {
     // Verify Tor SOCKS proxy is accessible
     info!("Verifying Tor SOCKS proxy connection...");
-    if !verify_tor_proxy(args.port).await? {
+    if !verify_tor_proxy(socks_port).await? {
         return Err(anyhow::anyhow!("Cannot proceed without Tor SOCKS proxy connection"));
     }
-    
+
     // Initialize Tor control connection
-    info!("Connecting to Tor control port...");
-    let mut tor_control = TorControl::new(args.control_port)
+    info!("Connecting to Tor control port at {}...", endpoint);
+    let tor_control = TorControl::connect(endpoint)
+        .await
         .context("Failed to connect to Tor control port")?;
-    
+
     // Authenticate with Tor control port
     info!("Authenticating with Tor control port...");
-    tor_control.authenticate(args.password.clone())
+    tor_control.authenticate(password)
+        .await
         .context("Failed to authenticate with Tor control port")?;
-    
-    // Get original IP without Tor
-    info!("Checking original IP...");
-    let regular_client = reqwest::Client::new();
-    match get_ip_info(&regular_client).await {
-        Ok((ip, geo_info, is_tor)) => {
-            match geo_info {
-                Some(geo) => {
-                    info!(
-                        "Original IP: {} ({}) [{}]",
-                        ip,
-                        format_location(&geo),
-                        if is_tor { "Tor" } else { "Direct" }
-                    );
-                }
-                None => {
-                    info!("Original IP: {} (Location unavailable) [{}]", 
-                        ip,
-                        if is_tor { "Tor" } else { "Direct" }
-                    );
-                }
-            }
-        }
-        Err(e) => {
-            warn!("Failed to get original IP: {}", e);
+
+    // Subscribe to async events so we react to circuits instead of polling
+    tokio::spawn(log_tor_events(tor_control.subscribe()).in_current_span());
+    tor_control.set_events(events::DEFAULT_EVENTS)
+        .await
+        .context("Failed to subscribe to Tor events")?;
+    Ok(tor_control)
+}
+
+/// Everything one instance's rotation loop needs.
+struct RotationTask {
+    instance: Arc<TorInstance>,
+    policy: Box<dyn RotationPolicy>,
+    exit_selection: ExitSelection,
+    max_attempts: u32,
+    bootstrap_timeout: Duration,
+    relay_cache: RelayCache,
+    isolator: Arc<Isolator>,
+    status: Arc<Status>,
+    metrics: Arc<Metrics>,
+    tuning: Arc<Tuning>,
+    kill_switch: KillSwitch,
+    commands: broadcast::Receiver<Command>,
+    /// Delay before the first rotation so pooled instances take turns.
+    stagger: Duration,
+    shutdown: Shutdown,
+}
+
+/// Rotates until shutdown, cancelling a rotation in flight, then puts back
+/// the exit settings we changed in Tor.
+async fn run_rotation(task: RotationTask) -> Result<()> {
+    let shutdown = task.shutdown.clone();
+    let mut exit_manager = ExitManager::new(task.instance.control.clone());
+    let result = tokio::select! {
+        result = rotate_until_stopped(task, &mut exit_manager) => result,
+        _ = shutdown.requested() => Ok(()),
+    };
+    match (result, exit_manager.restore().await) {
+        (Err(e), Err(restore_error)) => {
+            error!("Failed to restore Tor exit configuration: {:#}", restore_error);
+            Err(e)
         }
+        (result, restored) => result.and(restored.context("Failed to restore Tor exit configuration")),
+    }
+}
+
+/// Shows circuits and the exit IP, rotates, and waits for the policy (or a
+/// console command), until it fails.
+async fn rotate_until_stopped(task: RotationTask, exit_manager: &mut ExitManager) -> Result<()> {
+    let RotationTask {
+        instance,
+        policy: mut rotation_policy,
+        mut exit_selection,
+        max_attempts,
+        bootstrap_timeout,
+        relay_cache,
+        isolator,
+        status,
+        metrics,
+        mut tuning,
+        kill_switch,
+        mut commands,
+        stagger,
+        shutdown: _,
+    } = task;
+    let tor_control = &instance.control;
+    let traffic = &instance.traffic;
+    let mut reattached = tor_control.reattached();
+    status.set_policy(&instance.name, rotation_policy.describe());
+
+    // Nothing works until Tor has a consensus and enough descriptors
+    bootstrap::wait_for_bootstrap(tor_control, bootstrap_timeout).await?;
+
+    // Restrict exit countries before any circuits we care about are built
+    if !exit_selection.is_empty() {
+        exit_manager.apply(&exit_selection)
+            .await
+            .context("Failed to apply exit country selection")?;
     }
 
     // Create initial Tor client
     info!("Initializing Tor client...");
-    let mut tor_client = create_tor_client(args.port).await?;
+    let mut tor_client = create_tor_client(instance.socks.port(), &instance.exit_verifier, &tuning, &metrics).await?;
     info!("✓ Tor client initialized successfully");
 
     // Wait for circuits to be built
     info!("Waiting for Tor circuits to be established...");
-    if let Err(e) = tor_control.wait_for_circuits().await {
+    if let Err(e) = tor_control.wait_for_circuits(tuning.circuit_timeout).await {
         error!("Failed to establish Tor circuits: {}", e);
         return Err(e);
     }
     info!("✓ Tor circuits established successfully");
-    
+
+    let mut rotator = Rotator::new(
+        tor_control.clone(),
+        instance.socks.port(),
+        max_attempts,
+        tuning.clone(),
+        metrics.clone(),
+    );
+    rotator.set_exit_selection(exit_selection.clone());
+
+    if !stagger.is_zero() {
+        info!("Staggering first rotation by {}s", stagger.as_secs());
+        time::sleep(stagger).await;
+    }
+
     loop {
+        if reattached.has_changed().unwrap_or(false) {
+            reattached.mark_unchanged();
+            reapply_exits(exit_manager, &exit_selection).await;
+        }
+
         // Get circuit information
-        match tor_control.get_circuit_info() {
+        match tor_control.get_circuit_info().await {
             Ok(circuits) => {
                 let built_circuits: Vec<_> = circuits.iter()
-                    .filter(|c| c.status == "BUILT" && c.purpose.contains("GENERAL"))
+                    .filter(|c| c.is_usable())
                     .collect();
 
                 if !built_circuits.is_empty() {
                     info!("🌐 Active Tor Circuits:");
                     for circuit in built_circuits {
-                        let mut node_info = Vec::new();
-                        for node in &circuit.path {
-                            match tor_control.get_node_info(node) {
-                                Ok((name, country)) => node_info.push((name, country)),
-                                Err(_) => node_info.push((node[..6].to_string(), "??".to_string())),
+                        let mut relays = Vec::new();
+                        for hop in &circuit.path {
+                            match relay_cache.lookup(tor_control, &hop.fingerprint).await {
+                                Ok(relay) => relays.push(Some(relay)),
+                                Err(e) => {
+                                    warn!("Failed to look up relay {}: {}", hop.fingerprint, e);
+                                    relays.push(None);
+                                }
                             }
                         }
                         
                         info!("  └─ Circuit #{}", circuit.id);
-                        info!("     {}", format_circuit_path(circuit, &node_info));
+                        info!("     {}", format_circuit_path(circuit, &relays));
+                        if let Some(Some(exit)) = relays.last() {
+                            info!("     {}", format_exit(exit));
+                        }
                     }
                 } else {
                     warn!("No active Tor circuits found!");
@@ -694,8 +916,18 @@ async fn main() -> Result<()> {
         }
 
         // Get current IP through Tor
-        match get_ip_info(&tor_client).await {
+        let mut current_ip = None;
+        match get_ip_info(&tor_client, Some(&instance.exit_verifier), &tuning, &metrics).await {
             Ok((ip, geo_info, is_tor)) => {
+                if let Ok(address) = ip.parse() {
+                    kill_switch.observe(&instance.name, address);
+                    if !is_tor {
+                        kill_switch.trip(&instance.name, Leak::NotTor(address));
+                    }
+                }
+                current_ip = Some(ip.clone());
+                metrics.record_exit(&ip, geo_info.as_ref().and_then(|geo| geo.country_code.as_deref()));
+                status.set_exit(&instance.name, exit_status(&ip, geo_info.as_ref(), Some(is_tor)));
                 match geo_info {
                     Some(geo) => {
                         info!(
@@ -721,28 +953,472 @@ async fn main() -> Result<()> {
 
         // Switch identity
         info!("🔄 Switching Tor identity...");
-        if let Err(e) = tor_control.switch_identity().await {
-            warn!("Failed to switch identity: {}", e);
+        let outcome = rotator.rotate(current_ip).await;
+        let new_ip = outcome.as_ref().ok().and_then(|outcome| outcome.new_ip.as_deref());
+        let new_address: Option<IpAddr> = new_ip.and_then(|ip| ip.parse().ok());
+        if let Some(address) = new_address {
+            kill_switch.observe(&instance.name, address);
+        }
+        metrics.record_rotation(&instance.name, &outcome);
+        if let Ok(outcome) = &outcome {
+            // Check the new exit now, or the status API would show it
+            // unlocated until the check before the next rotation
+            let exit = match new_ip.filter(|_| outcome.changed()) {
+                Some(ip) => Some(check_new_exit(ip, outcome, &tor_client, &instance, &tuning, &metrics).await),
+                None => None,
+            };
+            if let (Some(address), Some(ExitStatus { is_tor: Some(false), .. })) = (new_address, &exit) {
+                kill_switch.trip(&instance.name, Leak::NotTor(address));
+            }
+            status.record_rotation(&instance.name, outcome, exit);
+        }
+        match outcome {
+            Err(e) => warn!("Failed to switch identity: {}", e),
+            Ok(outcome) if !outcome.changed() => warn!(
+                "Exit IP did not change after {} attempts in {:.1}s (still {})",
+                outcome.attempts,
+                outcome.elapsed.as_secs_f32(),
+                outcome.old_ip.as_deref().unwrap_or("unknown")
+            ),
+            Ok(outcome) if outcome.country_mismatch => warn!(
+                "No exit in {} after {} attempts in {:.1}s (now {} in {})",
+                exit_selection,
+                outcome.attempts,
+                outcome.elapsed.as_secs_f32(),
+                outcome.new_ip.as_deref().unwrap_or("unknown"),
+                outcome.exit_country.as_deref().unwrap_or("??")
+            ),
+            Ok(outcome) => {
+                info!(
+                    "Identity switched: {} → {} [{}] ({} attempt(s), {:.1}s{})",
+                    outcome.old_ip.as_deref().unwrap_or("unknown"),
+                    outcome.new_ip.as_deref().unwrap_or("unknown"),
+                    outcome.exit_country.as_deref().unwrap_or("??").to_uppercase(),
+                    outcome.attempts,
+                    outcome.elapsed.as_secs_f32(),
+                    if outcome.rate_limited > 0 { ", rate limited by Tor" } else { "" }
+                );
+            }
+        }
+
+        // Keep new frontend connections off circuits used before the switch
+        isolator.next_epoch();
+
+        // Create a new Tor client to force using the new circuit
+        match create_tor_client(instance.socks.port(), &instance.exit_verifier, &tuning, &metrics).await {
+            Ok(new_client) => {
+                tor_client = new_client;
+                info!("✓ New Tor circuit established");
+            }
+            Err(e) => warn!("Failed to create new Tor client: {}", e),
+        }
+
+        // Wait until the rotation policy says it's time again, or we're
+        // told to rotate (or change exits) on the console or the API. A new
+        // policy restarts the wait without rotating.
+        rotation_policy.reset(Instant::now());
+        let baseline = traffic.snapshot();
+        loop {
+            tokio::select! {
+                _ = policy::wait_until_due(rotation_policy.as_ref(), traffic, &baseline) => break,
+                Ok(()) = reattached.changed() => reapply_exits(exit_manager, &exit_selection).await,
+                Ok(command) = commands.recv() => match command {
+                    Command::Rotate => {
+                        info!("Rotation requested");
+                        break;
+                    }
+                    Command::ExitCountries(countries) => {
+                        exit_selection.countries = countries;
+                        change_exits(exit_manager, &mut rotator, &exit_selection).await;
+                        break;
+                    }
+                    Command::ExcludeCountries(countries) => {
+                        exit_selection.excluded = countries;
+                        change_exits(exit_manager, &mut rotator, &exit_selection).await;
+                        break;
+                    }
+                    Command::Exits(selection) => {
+                        exit_selection = selection;
+                        change_exits(exit_manager, &mut rotator, &exit_selection).await;
+                        break;
+                    }
+                    Command::Tuning(new_tuning) => {
+                        rotator.set_tuning(new_tuning.clone());
+                        tuning = new_tuning;
+                    }
+                    Command::Policy(spec) => match policy::parse_policy(&spec) {
+                        Ok(new_policy) => {
+                            rotation_policy = new_policy;
+                            rotation_policy.reset(Instant::now());
+                            info!("Rotation policy: {}", rotation_policy.describe());
+                            status.set_policy(&instance.name, rotation_policy.describe());
+                        }
+                        Err(e) => warn!("Invalid rotation policy {:?}: {}", spec, e),
+                    },
+                },
+            }
+        }
+    }
+}
+
+/// Runs the DNS leak check against the Tor at --port and --dns-port and
+/// prints where each kind of lookup was resolved.
+async fn dns_leak_check(args: &Args) -> Result<ExitCode> {
+    let check = DnsLeakCheck {
+        zone: args.dns_leak_zone.clone().unwrap_or_default(),
+        report_port: args.dns_leak_report_port,
+        socks: SocketAddr::from(([127, 0, 0, 1], args.port)),
+        dns_port: args.dns_port.map(|port| SocketAddr::from(([127, 0, 0, 1], port))),
+        timeout: Duration::from_secs(args.request_timeout),
+    };
+    info!("Looking up names under {} with and without Tor...", check.zone);
+    let report = check.run().await;
+
+    let mut methods = vec![Method::SocksConnect, Method::SocksResolve];
+    if check.dns_port.is_some() {
+        methods.push(Method::DnsPort);
+    }
+    methods.push(Method::Local);
+    for method in methods {
+        let resolvers = report.resolvers(method);
+        if resolvers.is_empty() {
+            let error = report
+                .probes
+                .iter()
+                .filter(|probe| probe.method == method)
+                .find_map(|probe| probe.resolvers.as_ref().err())
+                .map(|e| format!("{:#}", e))
+                .unwrap_or_else(|| "no resolver seen".to_string());
+            println!("{:<15} ⚠ {}", method, error);
         } else {
-            info!("Identity switch requested, establishing new circuit...");
-            
-            // Wait for new circuits to be built
-            if let Err(e) = tor_control.wait_for_circuits().await {
-                warn!("Failed to establish new circuits: {}", e);
-                continue;
+            let resolvers: Vec<String> = resolvers.iter().map(IpAddr::to_string).collect();
+            println!("{:<15} {}", method, resolvers.join(", "));
+        }
+    }
+
+    let leaks = report.leaks();
+    if !leaks.is_empty() {
+        for (probe, resolver) in &leaks {
+            error!("🛑 DNS leak: {} lookup of {} reached our resolver {}", probe.method, probe.name, resolver);
+        }
+        return Ok(ExitCode::FAILURE);
+    }
+    if !report.is_conclusive() {
+        error!("No lookup through Tor reached the server; cannot tell whether DNS leaks");
+        return Ok(ExitCode::FAILURE);
+    }
+    if report.resolvers(Method::Local).is_empty() {
+        warn!("⚠ The local lookups failed, so Tor's resolvers could not be compared with ours");
+    }
+    info!("✓ No DNS leak: lookups through Tor never reached our resolver");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Serves the zone for dns-leak until interrupted.
+async fn dns_leak_server(zone: &str, answer: Ipv4Addr, listen: SocketAddr, report_listen: SocketAddr) -> Result<ExitCode> {
+    let socket = UdpSocket::bind(listen)
+        .await
+        .with_context(|| format!("Failed to listen for DNS on {}", listen))?;
+    let listener = TcpListener::bind(report_listen)
+        .await
+        .with_context(|| format!("Failed to listen for reports on {}", report_listen))?;
+    let server = Arc::new(LeakServer::new(zone, answer));
+    tokio::spawn(server.clone().serve_dns(socket));
+    tokio::spawn(server.serve_reports(listener));
+
+    let shutdown = Shutdown::default();
+    tokio::spawn(shutdown::on_signals(shutdown.clone()));
+    shutdown.requested().await;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    let config = config::load()?;
+    if let Some(CliCommand::Config { action: ConfigAction::Print }) = &config.args.command {
+        config.print();
+        config.args.validate()?;
+        config.args.tuning()?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    config.args.validate()?;
+
+    // Initialize logging
+    let (log_filter, log_level) = reload::Layer::new(config.args.log_level);
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    match &config.args.command {
+        Some(CliCommand::DnsLeak) => return dns_leak_check(&config.args).await,
+        Some(CliCommand::DnsLeakServer {
+            answer,
+            listen,
+            report_listen,
+        }) => {
+            let zone = config.args.dns_leak_zone.as_deref().unwrap_or_default();
+            return dns_leak_server(zone, *answer, *listen, *report_listen).await;
+        }
+        _ => {}
+    }
+
+    let shutdown = Shutdown::default();
+    tokio::spawn(shutdown::on_signals(shutdown.clone()));
+
+    println!("\x1b[31m{}\x1b[0m", BANNER);
+    println!("\x1b[33mAnonymous Internet Access Through Tor\x1b[0m");
+    println!("\x1b[32mVersion 0.1.0\x1b[0m");
+    println!();
+
+    if let Some(path) = &config.file {
+        info!("Using config file {}", path.display());
+    }
+    let args = config.args.clone();
+    let tuning = Arc::new(args.tuning()?);
+    if tuning.geo.is_empty() {
+        warn!("No GeoIP database found; exit locations are unavailable (see --geo-source)");
+    }
+    let rotation_policy = args.rotation_policy()?;
+    let exit_selection = args.exit_selection()?;
+    let endpoints = args.instance_endpoints()?;
+    let password = args.control_password()?;
+    let bootstrap_timeout = Duration::from_secs(args.bootstrap_timeout);
+
+    // With several instances, prefix each one's logs with its name
+    let pooled = args.launch_tor + endpoints.len() > 1;
+    let span_for = |name: &str| if pooled { info_span!("tor", instance = %name) } else { Span::none() };
+
+    let metrics = Arc::new(Metrics::default());
+    let mut instances = Vec::new();
+    let mut managed_tors = JoinSet::new();
+    for i in 0..args.launch_tor {
+        let name = format!("tor{}", i);
+        let span = span_for(&name);
+        let mut tor = ManagedTor::prepare(&name, &args.tor_binary)?;
+        let (child, control) = tor.start()
+            .instrument(span.clone())
+            .await
+            .with_context(|| format!("Failed to launch {}", name))?;
+        tokio::spawn(log_tor_events(control.subscribe()).instrument(span.clone()));
+        let socks = tor.socks_addr().ok_or_else(|| anyhow!("{} has no SOCKS port", name))?;
+        managed_tors.spawn(tor.supervise(child, control.clone(), metrics.clone(), shutdown.clone()).instrument(span));
+        instances.push(Arc::new(TorInstance::new(name, socks, control)));
+    }
+    for (socks_port, endpoint) in &endpoints {
+        let name = format!("tor{}", instances.len());
+        let control = connect_instance(*socks_port, endpoint, password.clone())
+            .instrument(span_for(&name))
+            .await?;
+        let socks = SocketAddr::from(([127, 0, 0, 1], *socks_port));
+        instances.push(Arc::new(TorInstance::new(name, socks, control)));
+    }
+
+    let kill_switch = if args.kill_switch {
+        info!("Kill switch on: proxy frontends stay closed until Tor is verified");
+        KillSwitch::new(instances.iter().map(|instance| instance.name.clone()), args.leak_hook.clone())
+    } else {
+        KillSwitch::default()
+    };
+
+    // Get original IP without Tor
+    info!("Checking original IP...");
+    let regular_client = reqwest::Client::new();
+    match get_ip_info(&regular_client, None, &tuning, &metrics).await {
+        Ok((ip, geo_info, is_tor)) => {
+            if args.kill_switch {
+                if let Ok(address) = ip.parse() {
+                    kill_switch.set_real_ip(address);
+                }
             }
-            
-            // Create a new Tor client to force using the new circuit
-            match create_tor_client(args.port).await {
-                Ok(new_client) => {
-                    tor_client = new_client;
-                    info!("✓ New Tor circuit established");
+            match geo_info {
+                Some(geo) => {
+                    info!(
+                        "Original IP: {} ({}) [{}]",
+                        ip,
+                        format_location(&geo),
+                        if is_tor { "Tor" } else { "Direct" }
+                    );
                 }
-                Err(e) => warn!("Failed to create new Tor client: {}", e),
+                None => {
+                    info!("Original IP: {} (Location unavailable) [{}]", 
+                        ip,
+                        if is_tor { "Tor" } else { "Direct" }
+                    );
+                }
+            }
+        }
+        Err(e) if args.kill_switch => {
+            return Err(e.context("The kill switch needs our original IP to detect leaks"));
+        }
+        Err(e) => {
+            warn!("Failed to get original IP: {}", e);
+        }
+    }
+
+    let (command_tx, _) = broadcast::channel(16);
+    for instance in &instances {
+        let span = span_for(&instance.name);
+        tokio::spawn(
+            policy::track_bandwidth(instance.control.subscribe(), instance.traffic.clone())
+                .instrument(span.clone()),
+        );
+        tokio::spawn(metrics::track_events(instance.control.subscribe(), metrics.clone(), instance.name.clone()));
+        if args.kill_switch {
+            tokio::spawn(
+                killswitch::monitor(
+                    kill_switch.clone(),
+                    instance.clone(),
+                    tuning.clone(),
+                    metrics.clone(),
+                    Duration::from_secs(args.leak_check_interval),
+                    command_tx.subscribe(),
+                    shutdown.clone(),
+                )
+                .instrument(span.clone()),
+            );
+        }
+        if let Some(url) = &args.block_probe {
+            tokio::spawn(
+                policy::run_block_probe(
+                    url.clone(),
+                    args.block_status.clone(),
+                    instance.socks.port(),
+                    tuning.request_timeout,
+                    instance.traffic.clone(),
+                )
+                .instrument(span),
+            );
+        }
+    }
+
+    if let Some(addr) = args.metrics_listen {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        tokio::spawn(metrics::serve(metrics.clone(), listener));
+    }
+
+    let pool = Arc::new(TorPool::new(instances, args.balance));
+    let isolator = Arc::new(Isolator::new(args.isolation));
+    let mut frontends = JoinSet::new();
+    if let Some(addr) = args.socks_listen {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        let server = SocksServer::new(pool.clone(), isolator.clone(), kill_switch.clone());
+        frontends.spawn(Arc::new(server).serve(listener, shutdown.clone()));
+    }
+    if let Some(addr) = args.http_listen {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        let proxy = HttpProxy::new(pool.clone(), isolator.clone(), kill_switch.clone());
+        frontends.spawn(Arc::new(proxy).serve(listener, shutdown.clone()));
+    }
+
+    let console_commands = command_tx.clone();
+    std::thread::spawn(move || console::read_stdin(console_commands));
+    #[cfg(unix)]
+    tokio::spawn(config::reload_on_hangup(config, tuning.clone(), command_tx.clone(), log_level));
+    info!("Rotation policy: {}", rotation_policy.describe());
+    if pooled {
+        info!("Tor pool of {} instances, balanced {}", pool.instances().len(), args.balance);
+    }
+
+    // Spread the pool's rotations over one interval so some exit is always fresh
+    let mut stagger_step = Duration::ZERO;
+    if args.stagger && pooled {
+        let mut probe = args.rotation_policy()?;
+        let now = Instant::now();
+        probe.reset(now);
+        match probe.deadline() {
+            Some(deadline) => {
+                stagger_step = deadline.saturating_duration_since(now) / pool.instances().len() as u32;
             }
+            None => warn!("--stagger needs a time-based rotation policy; rotating independently"),
         }
+    }
 
-        // Wait for the specified interval
-        time::sleep(Duration::from_secs(args.interval)).await;
+    let relay_cache = RelayCache::new();
+    let status = Arc::new(Status::default());
+    let mut api = None;
+    if let Some(endpoint) = &args.api_listen {
+        let token = match &args.api_token {
+            Some(token) => token.clone(),
+            None => {
+                let token = api::generate_token();
+                let path = api::token_path().ok_or_else(|| anyhow!("No place to write the API token; set --api-token"))?;
+                api::write_token(&path, &token)?;
+                info!("Management API token written to {}", path.display());
+                token
+            }
+        };
+        let server = ApiServer::new(token, status.clone(), pool.clone(), relay_cache.clone(), command_tx.clone());
+        api = Some(Arc::new(server).listen(endpoint, shutdown.clone()).await?);
     }
+
+    let mut rotations = tokio::task::JoinSet::new();
+    for (i, instance) in pool.instances().iter().enumerate() {
+        let task = RotationTask {
+            instance: instance.clone(),
+            policy: args.rotation_policy()?,
+            exit_selection: exit_selection.clone(),
+            max_attempts: args.max_rotation_attempts,
+            bootstrap_timeout,
+            relay_cache: relay_cache.clone(),
+            isolator: isolator.clone(),
+            status: status.clone(),
+            metrics: metrics.clone(),
+            tuning: tuning.clone(),
+            kill_switch: kill_switch.clone(),
+            commands: command_tx.subscribe(),
+            stagger: stagger_step * i as u32,
+            shutdown: shutdown.clone(),
+        };
+        rotations.spawn(run_rotation(task).instrument(span_for(&instance.name)));
+    }
+
+    // Rotations only end on shutdown, or when one fails; that stops the rest
+    let mut failed = false;
+    while let Some(rotation) = rotations.join_next().await {
+        if let Err(e) = rotation.context("Rotation task panicked").and_then(|result| result) {
+            error!("{:#}", e);
+            failed = true;
+            shutdown.request();
+        }
+    }
+
+    while frontends.join_next().await.is_some() {}
+    if let Some(api) = api {
+        let _ = api.await;
+    }
+    for instance in pool.instances() {
+        match time::timeout(shutdown::QUIT_TIMEOUT, instance.control.quit()).await {
+            Ok(Ok(())) => debug!("Closed control connection to {}", instance.name),
+            Ok(Err(e)) => debug!("Failed to close control connection to {}: {}", instance.name, e),
+            Err(_) => warn!("{} did not close its control connection", instance.name),
+        }
+    }
+    // Our Tors exit with their owning control connection; kill any that don't
+    let stopped = time::timeout(shutdown::TOR_EXIT_TIMEOUT, async {
+        // A supervisor that gave up has already said so
+        let mut gave_up = false;
+        while let Some(supervisor) = managed_tors.join_next().await {
+            gave_up |= !matches!(supervisor, Ok(Ok(())));
+        }
+        gave_up
+    })
+    .await;
+    match stopped {
+        Ok(gave_up) => failed |= gave_up,
+        Err(_) => {
+            warn!("Killing {} Tor process(es) that did not exit", managed_tors.len());
+            managed_tors.shutdown().await;
+            failed = true;
+        }
+    }
+
+    info!("Shutdown complete");
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
 }
@@ -0,0 +1,510 @@
+//! Prometheus metrics, served in the text exposition format on
+//! `GET /metrics`.
+
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::circuit::CircuitStatus;
+use crate::events::TorEvent;
+use crate::http_proxy::{read_head, write_response, RequestHead};
+use crate::rotation::RotationOutcome;
+
+/// Bucket bounds in seconds for the time a rotation takes.
+const ROTATION_BUCKETS: &[f64] = &[1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+/// Bucket bounds in seconds for Tor to build a circuit after a NEWNYM.
+const CIRCUIT_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// Bucket bounds in seconds for one request to an IP check or geolocation
+/// service.
+const SERVICE_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Scrapers must send their request within this time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Cumulative, one per bound.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(self.bounds) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    rotations_attempted: BTreeMap<String, u64>,
+    rotations_succeeded: BTreeMap<String, u64>,
+    rotations_failed: BTreeMap<String, u64>,
+    rotation_seconds: BTreeMap<String, Histogram>,
+    new_circuit_seconds: BTreeMap<String, Histogram>,
+    newnym_rate_limited: BTreeMap<String, u64>,
+    /// By instance and reason.
+    circuit_failures: BTreeMap<(String, String), u64>,
+    bytes_read: BTreeMap<String, u64>,
+    bytes_written: BTreeMap<String, u64>,
+    /// By provider.
+    ip_check_seconds: BTreeMap<String, Histogram>,
+    ip_check_failures: BTreeMap<String, u64>,
+    /// By service.
+    geo_lookup_seconds: BTreeMap<String, Histogram>,
+    geo_lookup_failures: BTreeMap<String, u64>,
+    exit_ips: HashSet<String>,
+    exit_countries: HashSet<String>,
+    control_reconnects: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+impl Metrics {
+    fn update(&self, f: impl FnOnce(&mut Registry)) {
+        f(&mut self.registry.lock().unwrap());
+    }
+
+    /// One call per rotation, successful or not.
+    pub fn record_rotation(&self, instance: &str, outcome: &Result<RotationOutcome>) {
+        self.update(|r| {
+            *r.rotations_attempted.entry(instance.to_string()).or_default() += 1;
+            let outcome = match outcome {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    *r.rotations_failed.entry(instance.to_string()).or_default() += 1;
+                    return;
+                }
+            };
+            let result = if outcome.succeeded() {
+                &mut r.rotations_succeeded
+            } else {
+                &mut r.rotations_failed
+            };
+            *result.entry(instance.to_string()).or_default() += 1;
+            *r.newnym_rate_limited.entry(instance.to_string()).or_default() += u64::from(outcome.rate_limited);
+            for built in &outcome.circuit_times {
+                r.new_circuit_seconds
+                    .entry(instance.to_string())
+                    .or_insert_with(|| Histogram::new(CIRCUIT_BUCKETS))
+                    .observe(built.as_secs_f64());
+            }
+            if outcome.changed() {
+                r.rotation_seconds
+                    .entry(instance.to_string())
+                    .or_insert_with(|| Histogram::new(ROTATION_BUCKETS))
+                    .observe(outcome.elapsed.as_secs_f64());
+            }
+        });
+        if let Ok(outcome) = outcome {
+            if let Some(ip) = &outcome.new_ip {
+                self.record_exit(ip, outcome.exit_country.as_deref());
+            }
+        }
+    }
+
+    /// An exit IP we have been seen as, for the distinct IP and country gauges.
+    pub fn record_exit(&self, ip: &str, country: Option<&str>) {
+        self.update(|r| {
+            r.exit_ips.insert(ip.to_string());
+            if let Some(country) = country {
+                r.exit_countries.insert(country.to_lowercase());
+            }
+        });
+    }
+
+    /// One request to an IP check service and how long it took. `service`
+    /// is a provider name or a URL, which is labelled by its host.
+    pub fn record_ip_check(&self, service: &str, elapsed: Duration, succeeded: bool) {
+        let provider = provider(service);
+        self.update(|r| {
+            r.ip_check_seconds
+                .entry(provider.to_string())
+                .or_insert_with(|| Histogram::new(SERVICE_BUCKETS))
+                .observe(elapsed.as_secs_f64());
+            if !succeeded {
+                *r.ip_check_failures.entry(provider.to_string()).or_default() += 1;
+            }
+        });
+    }
+
+    /// One request to the remote geolocation service, labelled like
+    /// [`Metrics::record_ip_check`].
+    pub fn record_geo_lookup(&self, service: &str, elapsed: Duration, succeeded: bool) {
+        let service = provider(service);
+        self.update(|r| {
+            r.geo_lookup_seconds
+                .entry(service.to_string())
+                .or_insert_with(|| Histogram::new(SERVICE_BUCKETS))
+                .observe(elapsed.as_secs_f64());
+            if !succeeded {
+                *r.geo_lookup_failures.entry(service.to_string()).or_default() += 1;
+            }
+        });
+    }
+
+    /// The control connection to `instance` was re-established.
+    pub fn record_reconnect(&self, instance: &str) {
+        self.update(|r| *r.control_reconnects.entry(instance.to_string()).or_default() += 1);
+    }
+
+    pub fn render(&self) -> String {
+        let r = self.registry.lock().unwrap();
+        let mut out = String::new();
+
+        let by_instance = |map: &BTreeMap<String, u64>| -> Vec<(String, f64)> {
+            map.iter()
+                .map(|(instance, value)| (labels(&[("instance", instance)]), *value as f64))
+                .collect()
+        };
+        counter(&mut out, "rusttator_rotations_attempted_total", "Identity rotations started.", by_instance(&r.rotations_attempted));
+        counter(
+            &mut out,
+            "rusttator_rotations_succeeded_total",
+            "Rotations that ended on a new exit IP in an allowed country.",
+            by_instance(&r.rotations_succeeded),
+        );
+        counter(
+            &mut out,
+            "rusttator_rotations_failed_total",
+            "Rotations that errored, kept the old exit IP or landed outside the allowed countries.",
+            by_instance(&r.rotations_failed),
+        );
+        histogram(
+            &mut out,
+            "rusttator_rotation_duration_seconds",
+            "Time from the first NEWNYM until a new exit IP was confirmed.",
+            r.rotation_seconds.iter().map(|(instance, h)| (vec![("instance", instance.as_str())], h)),
+        );
+        histogram(
+            &mut out,
+            "rusttator_new_circuit_seconds",
+            "Time from a NEWNYM until Tor reported a new circuit built.",
+            r.new_circuit_seconds.iter().map(|(instance, h)| (vec![("instance", instance.as_str())], h)),
+        );
+        counter(
+            &mut out,
+            "rusttator_newnym_rate_limited_total",
+            "Times a NEWNYM had to wait out Tor's rate limit.",
+            by_instance(&r.newnym_rate_limited),
+        );
+        counter(
+            &mut out,
+            "rusttator_circuit_build_failures_total",
+            "Circuits that failed to build, by Tor's reason.",
+            r.circuit_failures
+                .iter()
+                .map(|((instance, reason), value)| (labels(&[("instance", instance), ("reason", reason)]), *value as f64))
+                .collect(),
+        );
+        counter(&mut out, "rusttator_tor_read_bytes_total", "Bytes Tor read, from BW events.", by_instance(&r.bytes_read));
+        counter(
+            &mut out,
+            "rusttator_tor_written_bytes_total",
+            "Bytes Tor wrote, from BW events.",
+            by_instance(&r.bytes_written),
+        );
+        histogram(
+            &mut out,
+            "rusttator_ip_check_duration_seconds",
+            "Latency of requests to IP check services.",
+            r.ip_check_seconds.iter().map(|(provider, h)| (vec![("provider", provider.as_str())], h)),
+        );
+        counter(
+            &mut out,
+            "rusttator_ip_check_failures_total",
+            "Failed requests to IP check services.",
+            r.ip_check_failures
+                .iter()
+                .map(|(provider, value)| (labels(&[("provider", provider)]), *value as f64))
+                .collect(),
+        );
+        histogram(
+            &mut out,
+            "rusttator_geo_lookup_duration_seconds",
+            "Latency of requests to the geolocation service.",
+            r.geo_lookup_seconds.iter().map(|(service, h)| (vec![("service", service.as_str())], h)),
+        );
+        counter(
+            &mut out,
+            "rusttator_geo_lookup_failures_total",
+            "Failed requests to the geolocation service.",
+            r.geo_lookup_failures
+                .iter()
+                .map(|(service, value)| (labels(&[("service", service)]), *value as f64))
+                .collect(),
+        );
+        gauge(
+            &mut out,
+            "rusttator_exit_ips_seen",
+            "Distinct exit IPs seen since start.",
+            r.exit_ips.len() as f64,
+        );
+        gauge(
+            &mut out,
+            "rusttator_exit_countries_seen",
+            "Distinct exit countries seen since start.",
+            r.exit_countries.len() as f64,
+        );
+        counter(
+            &mut out,
+            "rusttator_control_reconnects_total",
+            "Times the control connection was re-established after Tor restarted.",
+            by_instance(&r.control_reconnects),
+        );
+        out
+    }
+}
+
+/// The host of a service URL, which labels its metrics.
+fn provider(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split(['/', '?', '#']).next().unwrap_or(rest)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, samples: Vec<(String, f64)>) {
+    header(out, name, "counter", help);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn histogram<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    series: impl Iterator<Item = (Vec<(&'a str, &'a str)>, &'a Histogram)>,
+) {
+    header(out, name, "histogram", help);
+    for (base, h) in series {
+        for (bound, count) in h.bounds.iter().zip(&h.buckets) {
+            let le = bound.to_string();
+            let mut with_le = base.clone();
+            with_le.push(("le", &le));
+            let _ = writeln!(out, "{}_bucket{} {}", name, labels(&with_le), count);
+        }
+        let mut with_le = base.clone();
+        with_le.push(("le", "+Inf"));
+        let _ = writeln!(out, "{}_bucket{} {}", name, labels(&with_le), h.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, labels(&base), h.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels(&base), h.count);
+    }
+}
+
+/// `{name="value",...}` with values escaped as the format requires.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Counts circuit build failures and Tor's traffic from one instance's events.
+pub async fn track_events(mut events: broadcast::Receiver<TorEvent>, metrics: Arc<Metrics>, instance: String) {
+    loop {
+        match events.recv().await {
+            Ok(TorEvent::Circ(circuit)) if circuit.status == CircuitStatus::Failed => {
+                let reason = circuit.reason.unwrap_or_else(|| "NONE".to_string());
+                metrics.update(|r| *r.circuit_failures.entry((instance.clone(), reason)).or_default() += 1);
+            }
+            Ok(TorEvent::Bandwidth { read, written }) => metrics.update(|r| {
+                *r.bytes_read.entry(instance.clone()).or_default() += read;
+                *r.bytes_written.entry(instance.clone()).or_default() += written;
+            }),
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Serves `GET /metrics` until the listener fails.
+pub async fn serve(metrics: Arc<Metrics>, listener: TcpListener) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("📈 Metrics on http://{}/metrics", addr);
+    }
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(&metrics, stream).await {
+                        debug!("Metrics request from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn handle(metrics: &Metrics, stream: TcpStream) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let head = time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| anyhow!("request timed out"))??;
+    let Some(head) = head else {
+        return Ok(());
+    };
+    let head = RequestHead::parse(&head)?;
+    let client = stream.get_mut();
+    match (head.method.as_str(), head.target.split('?').next().unwrap_or_default()) {
+        ("GET", "/metrics") => write_response(client, 200, "OK", CONTENT_TYPE, &metrics.render()).await,
+        (_, "/metrics") => write_response(client, 405, "Method Not Allowed", CONTENT_TYPE, "Only GET is supported\n").await,
+        _ => write_response(client, 404, "Not Found", CONTENT_TYPE, "Metrics are served on /metrics\n").await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation(circuit_times: &[f64], elapsed: f64) -> RotationOutcome {
+        RotationOutcome {
+            old_ip: Some("192.0.2.1".to_string()),
+            new_ip: Some("192.0.2.2".to_string()),
+            attempts: circuit_times.len() as u32,
+            elapsed: Duration::from_secs_f64(elapsed),
+            circuit_times: circuit_times.iter().map(|secs| Duration::from_secs_f64(*secs)).collect(),
+            rate_limited: 1,
+            exit_country: Some("DE".to_string()),
+            country_mismatch: false,
+        }
+    }
+
+    /// The lines of `out` belonging to `name`, including its HELP and TYPE.
+    fn family<'a>(out: &'a str, name: &str) -> Vec<&'a str> {
+        out.lines()
+            .filter(|line| {
+                let line = line.strip_prefix("# HELP ").or_else(|| line.strip_prefix("# TYPE ")).unwrap_or(line);
+                line.strip_prefix(name).is_some_and(|rest| rest.starts_with([' ', '{', '_']))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let metrics = Metrics::default();
+        metrics.record_rotation("tor0", &Ok(rotation(&[1.5], 4.0)));
+        metrics.record_rotation("tor0", &Err(anyhow!("control connection closed")));
+        metrics.record_reconnect("tor\"0\\\n");
+        let out = metrics.render();
+
+        assert_eq!(
+            family(&out, "rusttator_rotations_attempted_total"),
+            [
+                "# HELP rusttator_rotations_attempted_total Identity rotations started.",
+                "# TYPE rusttator_rotations_attempted_total counter",
+                "rusttator_rotations_attempted_total{instance=\"tor0\"} 2",
+            ]
+        );
+        assert_eq!(
+            family(&out, "rusttator_rotations_failed_total")[2],
+            "rusttator_rotations_failed_total{instance=\"tor0\"} 1"
+        );
+        assert_eq!(
+            family(&out, "rusttator_newnym_rate_limited_total")[2],
+            "rusttator_newnym_rate_limited_total{instance=\"tor0\"} 1"
+        );
+        assert_eq!(
+            family(&out, "rusttator_control_reconnects_total")[2],
+            r#"rusttator_control_reconnects_total{instance="tor\"0\\\n"} 1"#
+        );
+        assert_eq!(
+            family(&out, "rusttator_exit_countries_seen"),
+            [
+                "# HELP rusttator_exit_countries_seen Distinct exit countries seen since start.",
+                "# TYPE rusttator_exit_countries_seen gauge",
+                "rusttator_exit_countries_seen 1",
+            ]
+        );
+        // Families without samples still describe themselves
+        assert_eq!(family(&out, "rusttator_circuit_build_failures_total").len(), 2);
+    }
+
+    #[test]
+    fn renders_cumulative_histograms() {
+        let metrics = Metrics::default();
+        metrics.record_rotation("tor0", &Ok(rotation(&[0.3, 1.5, 90.0], 100.0)));
+        let out = metrics.render();
+        assert_eq!(
+            family(&out, "rusttator_new_circuit_seconds"),
+            [
+                "# HELP rusttator_new_circuit_seconds Time from a NEWNYM until Tor reported a new circuit built.",
+                "# TYPE rusttator_new_circuit_seconds histogram",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"0.5\"} 1",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"1\"} 1",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"2\"} 2",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"5\"} 2",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"10\"} 2",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"20\"} 2",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"30\"} 2",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"60\"} 2",
+                "rusttator_new_circuit_seconds_bucket{instance=\"tor0\",le=\"+Inf\"} 3",
+                "rusttator_new_circuit_seconds_sum{instance=\"tor0\"} 91.8",
+                "rusttator_new_circuit_seconds_count{instance=\"tor0\"} 3",
+            ]
+        );
+        // The whole rotation is tracked on its own
+        assert!(out.contains("rusttator_rotation_duration_seconds_sum{instance=\"tor0\"} 100\n"));
+    }
+
+    #[test]
+    fn keeps_geo_lookups_apart_from_ip_checks() {
+        let metrics = Metrics::default();
+        metrics.record_ip_check("https://api.ipify.org/?format=json", Duration::from_millis(200), true);
+        metrics.record_geo_lookup("https://ipapi.co/{ip}/json/", Duration::from_millis(300), false);
+        let out = metrics.render();
+        assert!(out.contains("rusttator_ip_check_duration_seconds_count{provider=\"api.ipify.org\"} 1\n"));
+        assert!(!out.contains("rusttator_ip_check_duration_seconds_count{provider=\"ipapi.co\"}"));
+        assert_eq!(family(&out, "rusttator_ip_check_failures_total").len(), 2);
+        assert!(out.contains("rusttator_geo_lookup_duration_seconds_count{service=\"ipapi.co\"} 1\n"));
+        assert!(out.contains("rusttator_geo_lookup_failures_total{service=\"ipapi.co\"} 1\n"));
+    }
+}
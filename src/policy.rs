@@ -0,0 +1,685 @@
+//! Rotation policies: when to ask Tor for a new identity.
+//!
+//! Policies are described on the command line as `kind:arg` terms joined
+//! with `|`, and the composite rotates as soon as any term is due:
+//!
+//! ```text
+//! interval:5m | requests:100
+//! random:2m-10m
+//! cron:*/15 * * * *
+//! bytes:50MB | block
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, Duration as ChronoDuration, Local, NaiveDateTime, TimeZone, Timelike};
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::events::TorEvent;
+
+/// How often traffic-based policies are re-evaluated.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the block probe fetches its URL.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Counters fed by the proxy frontends, Tor's BW events and the block probe.
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    relayed: AtomicU64,
+    blocks: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct TrafficSnapshot {
+    pub requests: u64,
+    /// Everything Tor read and wrote.
+    pub bytes: u64,
+    /// The part of it the frontends relayed, both ways.
+    pub relayed: u64,
+    pub blocks: u64,
+}
+
+impl TrafficStats {
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_relayed(&self, sent: u64, received: u64) {
+        self.relayed.fetch_add(sent + received, Ordering::Relaxed);
+    }
+
+    pub fn record_block(&self) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            relayed: self.relayed.load(Ordering::Relaxed),
+            blocks: self.blocks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl TrafficSnapshot {
+    /// Traffic accumulated since `earlier`.
+    pub fn since(&self, earlier: &TrafficSnapshot) -> TrafficSnapshot {
+        TrafficSnapshot {
+            requests: self.requests.saturating_sub(earlier.requests),
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+            relayed: self.relayed.saturating_sub(earlier.relayed),
+            blocks: self.blocks.saturating_sub(earlier.blocks),
+        }
+    }
+}
+
+pub trait RotationPolicy: Send + Sync {
+    /// Short description for logs, in the same syntax it was parsed from.
+    fn describe(&self) -> String;
+
+    /// Called right after a rotation (and once at startup).
+    fn reset(&mut self, now: Instant);
+
+    /// Whether a rotation is due, given the traffic since the last one.
+    fn is_due(&self, now: Instant, traffic: &TrafficSnapshot) -> bool;
+
+    /// When a time-based policy next becomes due; `None` for policies that
+    /// only react to traffic.
+    fn deadline(&self) -> Option<Instant>;
+
+    /// Whether it needs the block probe to ever become due.
+    fn needs_block_probe(&self) -> bool {
+        false
+    }
+}
+
+pub struct FixedInterval {
+    interval: Duration,
+    due: Instant,
+}
+
+impl FixedInterval {
+    pub fn new(interval: Duration) -> Result<Self> {
+        Ok(Self { interval, due: due_after(Instant::now(), interval)? })
+    }
+}
+
+impl RotationPolicy for FixedInterval {
+    fn describe(&self) -> String {
+        format!("interval:{}s", self.interval.as_secs())
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.due = due_after(now, self.interval).unwrap_or(self.due);
+    }
+
+    fn is_due(&self, now: Instant, _: &TrafficSnapshot) -> bool {
+        now >= self.due
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.due)
+    }
+}
+
+/// Interval drawn uniformly from `[min, max]` after every rotation.
+pub struct RandomInterval {
+    min: Duration,
+    max: Duration,
+    due: Instant,
+}
+
+impl RandomInterval {
+    pub fn new(min: Duration, max: Duration) -> Result<Self> {
+        let now = Instant::now();
+        let due = due_after(now, max)?;
+        let mut policy = Self { min, max, due };
+        policy.reset(now);
+        Ok(policy)
+    }
+}
+
+impl RotationPolicy for RandomInterval {
+    fn describe(&self) -> String {
+        format!("random:{}s-{}s", self.min.as_secs(), self.max.as_secs())
+    }
+
+    fn reset(&mut self, now: Instant) {
+        let secs = rand::thread_rng().gen_range(self.min.as_secs_f64()..=self.max.as_secs_f64());
+        self.due = due_after(now, Duration::from_secs_f64(secs)).unwrap_or(self.due);
+    }
+
+    fn is_due(&self, now: Instant, _: &TrafficSnapshot) -> bool {
+        now >= self.due
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Some(self.due)
+    }
+}
+
+/// `now + interval`, or an error if that's beyond what an `Instant` holds.
+fn due_after(now: Instant, interval: Duration) -> Result<Instant> {
+    now.checked_add(interval)
+        .ok_or_else(|| anyhow!("interval of {}s is too long", interval.as_secs()))
+}
+
+pub struct Cron {
+    schedule: CronSchedule,
+    due: Option<Instant>,
+}
+
+impl Cron {
+    pub fn new(schedule: CronSchedule) -> Self {
+        let mut policy = Self { schedule, due: None };
+        policy.reset(Instant::now());
+        policy
+    }
+}
+
+impl RotationPolicy for Cron {
+    fn describe(&self) -> String {
+        format!("cron:{}", self.schedule.expression)
+    }
+
+    fn reset(&mut self, now: Instant) {
+        let local_now = Local::now();
+        self.due = self.schedule.next_after(local_now.naive_local()).and_then(|next| {
+            let next = Local.from_local_datetime(&next).earliest()?;
+            Some(now + (next - local_now).to_std().unwrap_or_default())
+        });
+    }
+
+    fn is_due(&self, now: Instant, _: &TrafficSnapshot) -> bool {
+        self.due.is_some_and(|due| now >= due)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.due
+    }
+}
+
+/// Rotate after this many proxied requests.
+pub struct RequestCount(pub u64);
+
+impl RotationPolicy for RequestCount {
+    fn describe(&self) -> String {
+        format!("requests:{}", self.0)
+    }
+
+    fn reset(&mut self, _: Instant) {}
+
+    fn is_due(&self, _: Instant, traffic: &TrafficSnapshot) -> bool {
+        traffic.requests >= self.0
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// Rotate after this many bytes have gone through Tor.
+pub struct ByteCount(pub u64);
+
+impl RotationPolicy for ByteCount {
+    fn describe(&self) -> String {
+        format!("bytes:{}", self.0)
+    }
+
+    fn reset(&mut self, _: Instant) {}
+
+    fn is_due(&self, _: Instant, traffic: &TrafficSnapshot) -> bool {
+        traffic.bytes >= self.0
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// Rotate as soon as a block (e.g. HTTP 403/429 from the probe) is seen.
+pub struct OnBlock;
+
+impl RotationPolicy for OnBlock {
+    fn describe(&self) -> String {
+        "block".to_string()
+    }
+
+    fn reset(&mut self, _: Instant) {}
+
+    fn is_due(&self, _: Instant, traffic: &TrafficSnapshot) -> bool {
+        traffic.blocks > 0
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn needs_block_probe(&self) -> bool {
+        true
+    }
+}
+
+/// Due as soon as any of its members is due.
+pub struct AnyOf(pub Vec<Box<dyn RotationPolicy>>);
+
+impl RotationPolicy for AnyOf {
+    fn describe(&self) -> String {
+        self.0.iter().map(|p| p.describe()).collect::<Vec<_>>().join(" | ")
+    }
+
+    fn reset(&mut self, now: Instant) {
+        for policy in &mut self.0 {
+            policy.reset(now);
+        }
+    }
+
+    fn is_due(&self, now: Instant, traffic: &TrafficSnapshot) -> bool {
+        self.0.iter().any(|p| p.is_due(now, traffic))
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.0.iter().filter_map(|p| p.deadline()).min()
+    }
+
+    fn needs_block_probe(&self) -> bool {
+        self.0.iter().any(|p| p.needs_block_probe())
+    }
+}
+
+/// Parses a `kind:arg | kind:arg` policy description.
+pub fn parse_policy(spec: &str) -> Result<Box<dyn RotationPolicy>> {
+    let mut policies = spec
+        .split('|')
+        .map(|term| parse_term(term.trim()).with_context(|| format!("Invalid rotation policy {:?}", term.trim())))
+        .collect::<Result<Vec<_>>>()?;
+
+    match policies.len() {
+        0 => Err(anyhow!("Empty rotation policy")),
+        1 => Ok(policies.remove(0)),
+        _ => Ok(Box::new(AnyOf(policies))),
+    }
+}
+
+fn parse_term(term: &str) -> Result<Box<dyn RotationPolicy>> {
+    let (kind, arg) = term.split_once(':').unwrap_or((term, ""));
+    let arg = arg.trim();
+
+    Ok(match kind.trim() {
+        "interval" => Box::new(FixedInterval::new(parse_duration(arg)?)?),
+        "random" => {
+            let (min, max) = arg
+                .split_once('-')
+                .ok_or_else(|| anyhow!("expected random:<min>-<max>"))?;
+            let (min, max) = (parse_duration(min)?, parse_duration(max)?);
+            if min > max {
+                return Err(anyhow!("minimum interval is larger than the maximum"));
+            }
+            Box::new(RandomInterval::new(min, max)?)
+        }
+        "cron" => Box::new(Cron::new(CronSchedule::parse(arg)?)),
+        "requests" => {
+            let count: u64 = arg.parse().map_err(|_| anyhow!("expected requests:<count>"))?;
+            if count == 0 {
+                return Err(anyhow!("request count must be greater than zero"));
+            }
+            Box::new(RequestCount(count))
+        }
+        "bytes" => Box::new(ByteCount(parse_bytes(arg)?)),
+        "block" => Box::new(OnBlock),
+        other => {
+            return Err(anyhow!(
+                "unknown policy {:?} (expected interval, random, cron, requests, bytes or block)",
+                other
+            ))
+        }
+    })
+}
+
+/// Parses `90`, `90s`, `5m`, `2h` or `1d`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| anyhow!("invalid duration {:?}", s))?;
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(anyhow!("invalid duration unit in {:?} (use s, m, h or d)", s)),
+    };
+    if value == 0 {
+        return Err(anyhow!("duration must be greater than zero"));
+    }
+    let secs = value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("duration {:?} is too long", s))?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses `1000`, `512KB`, `50MB` or `2GB` (powers of 1024).
+fn parse_bytes(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| anyhow!("invalid byte count {:?}", s))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(anyhow!("invalid byte unit in {:?} (use KB, MB or GB)", s)),
+    };
+    if value == 0 {
+        return Err(anyhow!("byte count must be greater than zero"));
+    }
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("byte count {:?} is too large", s))
+}
+
+/// Standard five-field cron expression (minute hour day-of-month month
+/// day-of-week) evaluated in local time.
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(anyhow!("expected 5 cron fields, got {}", fields.len()));
+        };
+
+        let mut weekdays = parse_cron_field(weekday, 0, 7).context("day-of-week")?;
+        // Both 0 and 7 mean Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: parse_cron_field(minute, 0, 59).context("minute")?,
+            hours: parse_cron_field(hour, 0, 23).context("hour")?,
+            days: parse_cron_field(day, 1, 31).context("day-of-month")?,
+            months: parse_cron_field(month, 1, 12).context("month")?,
+            weekdays,
+            // Like Vixie cron, `*/2` still counts as unrestricted
+            days_restricted: !day.starts_with('*'),
+            weekdays_restricted: !weekday.starts_with('*'),
+        })
+    }
+
+    fn day_matches(&self, t: &NaiveDateTime) -> bool {
+        let day = self.days & (1 << t.day()) != 0;
+        let weekday = self.weekdays & (1 << t.weekday().num_days_from_sunday()) != 0;
+        // Like Vixie cron: if both fields are restricted either may match
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            (true, false) => day,
+            (false, true) => weekday,
+            (false, false) => true,
+        }
+    }
+
+    /// First matching minute strictly after `after`.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+        let limit = after + ChronoDuration::days(366 * 5);
+
+        while t <= limit {
+            if self.months & (1 << t.month()) == 0 {
+                let (year, month) = if t.month() == 12 { (t.year() + 1, 1) } else { (t.year(), t.month() + 1) };
+                t = chrono::NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(&t) {
+                t = (t.date() + ChronoDuration::days(1)).and_hms_opt(0, 0, 0)?;
+            } else if self.hours & (1 << t.hour()) == 0 {
+                t = t.with_minute(0)? + ChronoDuration::hours(1);
+            } else if self.minutes & (1 << t.minute()) == 0 {
+                t += ChronoDuration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+/// Parses one cron field (`*`, `*/n`, `a`, `a-b`, `a-b/n`, comma lists)
+/// into a bitmask.
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| anyhow!("invalid step {:?}", step))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(anyhow!("step must be greater than zero"));
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (parse_cron_value(a, min, max)?, parse_cron_value(b, min, max)?)
+        } else {
+            let value = parse_cron_value(range, min, max)?;
+            // `5/10` means "from 5 to the end in steps of 10"
+            (value, if part.contains('/') { max } else { value })
+        };
+        if start > end {
+            return Err(anyhow!("invalid range {:?}", range));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_cron_value(s: &str, min: u32, max: u32) -> Result<u32> {
+    let value: u32 = s.parse().map_err(|_| anyhow!("invalid value {:?}", s))?;
+    if value < min || value > max {
+        return Err(anyhow!("{} is out of range {}-{}", value, min, max));
+    }
+    Ok(value)
+}
+
+/// Sleeps until `policy` says it's time to rotate.
+pub async fn wait_until_due(
+    policy: &dyn RotationPolicy,
+    stats: &TrafficStats,
+    baseline: &TrafficSnapshot,
+) {
+    loop {
+        let now = Instant::now();
+        if policy.is_due(now, &stats.snapshot().since(baseline)) {
+            return;
+        }
+        let wake = match policy.deadline() {
+            Some(deadline) => deadline.min(now + POLL_INTERVAL),
+            None => now + POLL_INTERVAL,
+        };
+        time::sleep_until(wake.into()).await;
+    }
+}
+
+/// Periodically fetches `url` through Tor and records a block whenever the
+/// response status is one of `block_statuses`.
+pub async fn run_block_probe(
+    url: String,
+    block_statuses: Vec<u16>,
+    socks_port: u16,
+    timeout: Duration,
+    stats: Arc<TrafficStats>,
+) {
+    info!("Probing {} for blocks every {}s", url, PROBE_INTERVAL.as_secs());
+    loop {
+        match crate::rotation::tor_client(socks_port, timeout) {
+            Ok(client) => match client.get(&url).send().await {
+                Ok(response) if block_statuses.contains(&response.status().as_u16()) => {
+                    warn!("Block detected: {} answered {}", url, response.status());
+                    stats.record_block();
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Block probe failed: {}", e),
+            },
+            Err(e) => warn!("Block probe failed: {}", e),
+        }
+        time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+/// Counts the bytes Tor reports in its per-second BW events.
+pub async fn track_bandwidth(mut events: broadcast::Receiver<TorEvent>, stats: Arc<TrafficStats>) {
+    loop {
+        match events.recv().await {
+            Ok(TorEvent::Bandwidth { read, written }) => stats.record_bytes(read + written),
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_and_byte_counts() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert_eq!(parse_bytes("50MB").unwrap(), 50 << 20);
+        assert_eq!(parse_bytes("2g").unwrap(), 2 << 30);
+    }
+
+    #[test]
+    fn rejects_overflow_and_zero() {
+        for spec in [
+            "interval:0",
+            "interval:18446744073709551615",
+            "interval:9999999999999999d",
+            "random:1s-18446744073709551615s",
+            "random:5m-1m",
+            "requests:0",
+            "bytes:0",
+            "bytes:0MB",
+            "bytes:99999999999GB",
+        ] {
+            assert!(parse_policy(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn composes_terms() {
+        let policy = parse_policy("interval:5m | requests:100 | bytes:1KB").unwrap();
+        assert_eq!(policy.describe(), "interval:300s | requests:100 | bytes:1024");
+        let traffic = TrafficSnapshot { requests: 100, ..TrafficSnapshot::default() };
+        assert!(policy.is_due(Instant::now(), &traffic));
+        assert!(!policy.is_due(Instant::now(), &TrafficSnapshot::default()));
+    }
+
+    fn at(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> NaiveDateTime {
+        CronSchedule::parse(expression).unwrap().next_after(at(after)).unwrap()
+    }
+
+    #[test]
+    fn parses_cron_fields() {
+        assert_eq!(parse_cron_field("*", 0, 7).unwrap(), 0xff);
+        assert_eq!(parse_cron_field("*/15", 0, 59).unwrap(), 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(parse_cron_field("1-5", 0, 7).unwrap(), 0b111110);
+        assert_eq!(parse_cron_field("10-20/5", 0, 59).unwrap(), 1 << 10 | 1 << 15 | 1 << 20);
+        assert_eq!(parse_cron_field("50/5", 0, 59).unwrap(), 1 << 50 | 1 << 55);
+        assert_eq!(parse_cron_field("1,3,5", 1, 12).unwrap(), 1 << 1 | 1 << 3 | 1 << 5);
+
+        let schedule = CronSchedule::parse("  0   12 * *   7 ").unwrap();
+        assert_eq!(schedule.expression, "0 12 * * 7");
+        // Sunday is both 0 and 7
+        assert_eq!(schedule.weekdays, 1 | 1 << 7);
+    }
+
+    #[test]
+    fn rejects_invalid_cron_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * 32 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "1,,2 * * * *",
+            "-1 * * * *",
+            "*/x * * * *",
+        ] {
+            assert!(CronSchedule::parse(expression).is_err(), "{:?}", expression);
+        }
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        assert_eq!(next("*/15 * * * *", "2024-03-01 10:07"), at("2024-03-01 10:15"));
+        // Strictly after
+        assert_eq!(next("*/15 * * * *", "2024-03-01 10:15"), at("2024-03-01 10:30"));
+        assert_eq!(next("30 9 * * *", "2024-03-01 10:00"), at("2024-03-02 09:30"));
+        // Month and year rollover
+        assert_eq!(next("0 0 1 * *", "2024-01-31 23:59"), at("2024-02-01 00:00"));
+        assert_eq!(next("0 0 * * *", "2024-12-31 23:30"), at("2025-01-01 00:00"));
+        assert_eq!(next("0 12 1 3 *", "2024-03-01 12:00"), at("2025-03-01 12:00"));
+        // Only leap years have a 29th of February
+        assert_eq!(next("0 0 29 2 *", "2024-03-01 00:00"), at("2028-02-29 00:00"));
+        // 2024-03-01 is a Friday; Monday and Sunday wrap into the next week
+        assert_eq!(next("0 8 * * 1", "2024-03-01 10:00"), at("2024-03-04 08:00"));
+        assert_eq!(next("0 8 * * 7", "2024-03-01 10:00"), at("2024-03-03 08:00"));
+        assert_eq!(next("0 8 * * 0", "2024-03-01 10:00"), at("2024-03-03 08:00"));
+        assert!(CronSchedule::parse("0 0 31 2 *").unwrap().next_after(at("2024-01-01 00:00")).is_none());
+    }
+
+    #[test]
+    fn matches_either_day_field_only_when_both_are_restricted() {
+        // Both restricted: the 15th or any Monday
+        assert_eq!(next("0 0 15 * 1", "2024-03-01 10:00"), at("2024-03-04 00:00"));
+        assert_eq!(next("0 0 15 * 1", "2024-03-12 10:00"), at("2024-03-15 00:00"));
+        // A stepped `*` still leaves the field unrestricted, so only the
+        // other one decides
+        assert_eq!(next("0 0 15 * */2", "2024-03-01 10:00"), at("2024-03-15 00:00"));
+        assert_eq!(next("0 0 */2 * 1", "2024-03-01 10:00"), at("2024-03-04 00:00"));
+    }
+
+    #[test]
+    fn block_policies_need_the_probe() {
+        assert!(parse_policy("block").unwrap().needs_block_probe());
+        assert!(parse_policy("interval:5m | block").unwrap().needs_block_probe());
+        assert!(!parse_policy("interval:5m | requests:10").unwrap().needs_block_probe());
+    }
+}
@@ -0,0 +1,121 @@
+//! Several Tor instances behind the proxy frontends, each rotating on its
+//! own schedule, with connections spread across them.
+
+use rand::Rng;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::control::TorControl;
+use crate::exit_verify::ExitVerifier;
+use crate::policy::TrafficStats;
+
+/// How frontend connections are spread over the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Balance {
+    /// Each instance in turn.
+    RoundRobin,
+    /// The instance with the fewest open connections.
+    LeastConnections,
+    /// A random instance.
+    Random,
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Balance::RoundRobin => write!(f, "round-robin"),
+            Balance::LeastConnections => write!(f, "least-connections"),
+            Balance::Random => write!(f, "random"),
+        }
+    }
+}
+
+pub struct TorInstance {
+    pub name: String,
+    pub socks: SocketAddr,
+    pub control: TorControl,
+    /// Traffic through this instance; drives its rotation policy.
+    pub traffic: Arc<TrafficStats>,
+    /// Checks exit IPs against this instance's circuits and consensus.
+    pub exit_verifier: ExitVerifier,
+    active: AtomicUsize,
+}
+
+impl TorInstance {
+    pub fn new(name: String, socks: SocketAddr, control: TorControl) -> Self {
+        Self {
+            name,
+            socks,
+            exit_verifier: ExitVerifier::new(control.clone()),
+            control,
+            traffic: Arc::new(TrafficStats::default()),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Frontend connections currently open through this instance.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for TorInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (socks {})", self.name, self.socks)
+    }
+}
+
+pub struct TorPool {
+    instances: Vec<Arc<TorInstance>>,
+    balance: Balance,
+    next: AtomicUsize,
+}
+
+impl TorPool {
+    /// `instances` must not be empty.
+    pub fn new(instances: Vec<Arc<TorInstance>>, balance: Balance) -> Self {
+        assert!(!instances.is_empty(), "a Tor pool needs at least one instance");
+        Self {
+            instances,
+            balance,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn instances(&self) -> &[Arc<TorInstance>] {
+        &self.instances
+    }
+
+    /// Picks an instance for a new connection. It counts as active until
+    /// the returned lease is dropped.
+    pub fn lease(&self) -> Lease {
+        let index = match self.balance {
+            Balance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.instances.len(),
+            Balance::LeastConnections => {
+                // Start the scan at a rotating offset so ties are shared out
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..self.instances.len())
+                    .map(|i| (start + i) % self.instances.len())
+                    .min_by_key(|&i| self.instances[i].active_connections())
+                    .unwrap_or(0)
+            }
+            Balance::Random => rand::thread_rng().gen_range(0..self.instances.len()),
+        };
+        let instance = self.instances[index].clone();
+        instance.active.fetch_add(1, Ordering::Relaxed);
+        Lease { instance }
+    }
+}
+
+/// An instance handed out by [`TorPool::lease`].
+pub struct Lease {
+    pub instance: Arc<TorInstance>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.instance.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,458 @@
+//! Launching our own `tor` processes: a torrc in a temporary DataDirectory,
+//! ports Tor picks itself, ownership so Tor exits with us, and restarts with
+//! backoff.
+
+use anyhow::{anyhow, Context, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::time;
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::control::{ControlEndpoint, TorControl};
+use crate::events;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+
+/// How long Tor gets to open its control port after starting.
+const CONTROL_PORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Restart delays double from the first up to the second.
+const RESTART_BACKOFF: (Duration, Duration) = (Duration::from_secs(1), Duration::from_secs(60));
+
+/// A process that stayed up this long resets the restart backoff.
+const STABLE_UPTIME: Duration = Duration::from_secs(300);
+
+/// Failed restarts in a row after which Tor is given up on.
+const RESTART_ATTEMPTS: u32 = 10;
+
+/// A `tor` we launch and own. The DataDirectory and SOCKS port are kept
+/// across restarts so clients and the cached consensus stay valid.
+pub struct ManagedTor {
+    name: String,
+    binary: PathBuf,
+    data_dir: TempDir,
+    torrc: PathBuf,
+    /// Where Tor writes the control port it picked.
+    control_port_file: PathBuf,
+    /// Picked by Tor on the first start, then pinned in the torrc.
+    socks_addr: Option<SocketAddr>,
+    restart_backoff: (Duration, Duration),
+    restart_attempts: u32,
+}
+
+impl ManagedTor {
+    /// Creates the DataDirectory and torrc; nothing is started yet.
+    pub fn prepare(name: &str, binary: &Path) -> Result<Self> {
+        let data_dir = tempfile::Builder::new()
+            .prefix(&format!("rusttator-{}-", name))
+            .tempdir()
+            .context("Failed to create Tor DataDirectory")?;
+        let tor = Self {
+            name: name.to_string(),
+            binary: binary.to_path_buf(),
+            torrc: data_dir.path().join("torrc"),
+            control_port_file: data_dir.path().join("control-port"),
+            data_dir,
+            socks_addr: None,
+            restart_backoff: RESTART_BACKOFF,
+            restart_attempts: RESTART_ATTEMPTS,
+        };
+        tor.write_torrc("auto")?;
+        Ok(tor)
+    }
+
+    /// Tor picks both ports itself, so nothing can take them between us
+    /// choosing and Tor binding them. The control port is read back from
+    /// `ControlPortWriteToFile`, the SOCKS port over the control port.
+    fn write_torrc(&self, socks_port: &str) -> Result<()> {
+        let config = format!(
+            "DataDirectory {dir}\n\
+             SocksPort {socks_port}\n\
+             ControlPort auto\n\
+             ControlPortWriteToFile {control_port_file}\n\
+             CookieAuthentication 1\n\
+             __OwningControllerProcess {pid}\n\
+             Log notice stdout\n",
+            dir = self.data_dir.path().display(),
+            control_port_file = self.control_port_file.display(),
+            pid = std::process::id(),
+        );
+        std::fs::write(&self.torrc, config)
+            .with_context(|| format!("Failed to write {}", self.torrc.display()))
+    }
+
+    /// Tor's SOCKS port; known once it has been started.
+    pub fn socks_addr(&self) -> Option<SocketAddr> {
+        self.socks_addr
+    }
+
+    /// Starts Tor and takes ownership of it; it bootstraps in the background.
+    pub async fn start(&mut self) -> Result<(Child, TorControl)> {
+        info!(
+            "Launching {} for {} (DataDirectory {})",
+            self.binary.display(),
+            self.name,
+            self.data_dir.path().display()
+        );
+        match std::fs::remove_file(&self.control_port_file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("Failed to remove {}: {}", self.control_port_file.display(), e)),
+        }
+        let mut child = Command::new(&self.binary)
+            .arg("-f")
+            .arg(&self.torrc)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to launch {}", self.binary.display()))?;
+
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("Tor stdout not captured"))?;
+        tokio::spawn(log_output(BufReader::new(stdout)).in_current_span());
+
+        let control = match self.take_control(&mut child).await {
+            Ok(control) => control,
+            Err(e) => {
+                let _ = child.kill().await;
+                return Err(e);
+            }
+        };
+        let socks = match socks_listener(&control).await {
+            Ok(socks) => socks,
+            Err(e) => {
+                let _ = child.kill().await;
+                return Err(e);
+            }
+        };
+        match self.socks_addr {
+            None => {
+                debug!("{} picked SOCKS port {}", self.name, socks);
+                self.socks_addr = Some(socks);
+                // Restarts have to come back on the same port for our clients
+                self.write_torrc(&socks.to_string())?;
+            }
+            Some(expected) if expected != socks => {
+                warn!("{} came back with SOCKS port {} instead of {}", self.name, socks, expected);
+            }
+            Some(_) => {}
+        }
+        Ok((child, control))
+    }
+
+    /// Connects to the control port once Tor opens it, authenticates with
+    /// the cookie and ties Tor's lifetime to this connection.
+    async fn take_control(&self, child: &mut Child) -> Result<TorControl> {
+        let deadline = Instant::now() + CONTROL_PORT_TIMEOUT;
+        let control = loop {
+            if let Some(status) = child.try_wait()? {
+                return Err(anyhow!("{} exited during startup ({})", self.name, status));
+            }
+            let connected = match self.control_endpoint().await {
+                Some(endpoint) => TorControl::connect(&endpoint).await,
+                None => Err(anyhow!("no control port in {}", self.control_port_file.display())),
+            };
+            match connected {
+                Ok(control) => break control,
+                Err(_) if Instant::now() < deadline => time::sleep(Duration::from_millis(200)).await,
+                Err(e) => return Err(e.context(format!("{} never opened its control port", self.name))),
+            }
+        };
+
+        control.authenticate(None).await?;
+        control.command("TAKEOWNERSHIP").await?;
+        control.set_events(events::DEFAULT_EVENTS).await?;
+        Ok(control)
+    }
+
+    /// The control port Tor wrote to `ControlPortWriteToFile`, as
+    /// `PORT=127.0.0.1:9051`; `None` until it has.
+    async fn control_endpoint(&self) -> Option<ControlEndpoint> {
+        let contents = tokio::fs::read_to_string(&self.control_port_file).await.ok()?;
+        let addr: SocketAddr = contents
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("PORT="))?
+            .parse()
+            .ok()?;
+        Some(ControlEndpoint::Tcp {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        })
+    }
+
+    /// Waits for Tor to die and starts it again, backing off on repeated
+    /// crashes. After too many failed restarts in a row it gives up and
+    /// shuts everything down, as the instance would otherwise stay in the
+    /// pool without a Tor behind it. `control` keeps working across
+    /// restarts. On shutdown Tor is left to exit once its owning control
+    /// connection is closed.
+    pub async fn supervise(
+        mut self,
+        mut child: Child,
+        control: TorControl,
+        metrics: Arc<Metrics>,
+        shutdown: Shutdown,
+    ) -> Result<()> {
+        let mut backoff = self.restart_backoff.0;
+        let mut started = Instant::now();
+        loop {
+            let status = tokio::select! {
+                status = child.wait() => status,
+                _ = shutdown.requested() => {
+                    info!("{} stopped ({})", self.name, describe_exit(child.wait().await));
+                    return Ok(());
+                }
+            };
+            warn!("{} exited unexpectedly ({})", self.name, describe_exit(status));
+            if started.elapsed() >= STABLE_UPTIME {
+                backoff = self.restart_backoff.0;
+            }
+
+            let mut failures = 0;
+            loop {
+                if failures == self.restart_attempts {
+                    error!("Giving up on {} after {} failed restarts, shutting down", self.name, failures);
+                    shutdown.request();
+                    return Err(anyhow!("{} could not be restarted", self.name));
+                }
+                info!("Restarting {} in {:.1}s", self.name, backoff.as_secs_f32());
+                tokio::select! {
+                    _ = time::sleep(backoff) => {}
+                    _ = shutdown.requested() => return Ok(()),
+                }
+                backoff = (backoff * 2).min(self.restart_backoff.1);
+                match self.start().await {
+                    Ok((new_child, fresh)) => {
+                        control.reattach(fresh);
+                        metrics.record_reconnect(&self.name);
+                        child = new_child;
+                        started = Instant::now();
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Failed to restart {}: {:#}", self.name, e);
+                        failures += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Relays Tor's log output; bootstrap progress is followed over the
+/// control port instead.
+async fn log_output<R: tokio::io::AsyncBufRead + Unpin>(output: R) {
+    let mut lines = output.lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.contains("[warn]") || line.contains("[err]") {
+            warn!("Tor: {}", line);
+        } else {
+            debug!("Tor: {}", line);
+        }
+    }
+}
+
+fn describe_exit(status: std::io::Result<ExitStatus>) -> String {
+    match status {
+        Ok(status) => status.to_string(),
+        Err(e) => format!("wait failed: {}", e),
+    }
+}
+
+/// The address of Tor's (first) SOCKS listener.
+async fn socks_listener(control: &TorControl) -> Result<SocketAddr> {
+    let reply = control.command("GETINFO net/listeners/socks").await?;
+    reply
+        .values("net/listeners/socks")
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|value| value.split_whitespace())
+        .find_map(|listener| listener.trim_matches('"').parse().ok())
+        .ok_or_else(|| anyhow!("Tor reports no SOCKS listener"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    const SOCKS: &str = "127.0.0.1:19050";
+
+    /// A `tor` that runs each start as the next of `runs` says:
+    /// `stay` up, `crash` shortly after opening its control port, or `fail`
+    /// before opening it. It reports a control port served by the test,
+    /// which drops its connections once the process is gone.
+    struct FakeTor {
+        dir: TempDir,
+        binary: PathBuf,
+        commands: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeTor {
+        async fn new(runs: &str) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let (alive, control) = serve_control(commands.clone()).await;
+            let script = format!(
+                r#"#!/bin/bash
+torrc="$2"
+echo start >> "{dir}/starts"
+run=$(wc -l < "{dir}/starts")
+runs=({runs})
+run="${{runs[run - 1]:-fail}}"
+if [ "$run" = fail ]; then
+    exit 1
+fi
+# Held open for as long as this "tor" lives
+exec 3<>/dev/tcp/127.0.0.1/{alive}
+file=$(sed -n 's/^ControlPortWriteToFile //p' "$torrc")
+echo "PORT=127.0.0.1:{control}" > "$file"
+case "$run" in
+    crash) sleep 0.3; exit 1 ;;
+    *) exec sleep 60 ;;
+esac
+"#,
+                dir = dir.path().display(),
+            );
+            let binary = dir.path().join("tor");
+            std::fs::write(&binary, script).unwrap();
+            std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+            Self { dir, binary, commands }
+        }
+
+        fn starts(&self) -> usize {
+            std::fs::read_to_string(self.dir.path().join("starts"))
+                .map(|starts| starts.lines().count())
+                .unwrap_or(0)
+        }
+
+        fn managed(&self) -> ManagedTor {
+            let mut tor = ManagedTor::prepare("tor0", &self.binary).unwrap();
+            tor.restart_backoff = (Duration::from_millis(10), Duration::from_millis(40));
+            tor
+        }
+    }
+
+    /// Returns the port the fake process holds open while alive, and the
+    /// control port.
+    async fn serve_control(commands: Arc<Mutex<Vec<String>>>) -> (u16, u16) {
+        let alive = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ports = (alive.local_addr().unwrap().port(), control.local_addr().unwrap().port());
+        // Becomes true once the latest process is gone
+        let process: Arc<Mutex<Option<watch::Receiver<bool>>>> = Arc::default();
+
+        let latest = process.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = alive.accept().await {
+                let (gone, receiver) = watch::channel(false);
+                *latest.lock().unwrap() = Some(receiver);
+                tokio::spawn(async move {
+                    let _ = stream.read_to_end(&mut Vec::new()).await;
+                    let _ = gone.send(true);
+                });
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = control.accept().await {
+                let Some(mut gone) = process.lock().unwrap().clone() else {
+                    continue;
+                };
+                let commands = commands.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = tokio::io::BufReader::new(read).lines();
+                    loop {
+                        let command = tokio::select! {
+                            Ok(Some(command)) = lines.next_line() => command,
+                            _ = gone.wait_for(|gone| *gone) => return,
+                            else => return,
+                        };
+                        let reply = match command.as_str() {
+                            "PROTOCOLINFO 1" => "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250 OK".to_string(),
+                            "GETINFO net/listeners/socks" => {
+                                format!("250-net/listeners/socks=\"{}\"\r\n250 OK", SOCKS)
+                            }
+                            _ => "250 OK".to_string(),
+                        };
+                        commands.lock().unwrap().push(command);
+                        if write.write_all(format!("{}\r\n", reply).as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        ports
+    }
+
+    #[tokio::test]
+    async fn starts_and_pins_the_ports_tor_picked() {
+        let fake = FakeTor::new("stay").await;
+        let mut tor = fake.managed();
+        let torrc = std::fs::read_to_string(&tor.torrc).unwrap();
+        assert!(torrc.contains("SocksPort auto\n"));
+        assert!(torrc.contains("ControlPort auto\n"));
+
+        let (_child, _control) = tor.start().await.unwrap();
+        assert_eq!(tor.socks_addr(), Some(SOCKS.parse().unwrap()));
+        let torrc = std::fs::read_to_string(&tor.torrc).unwrap();
+        assert!(torrc.contains(&format!("SocksPort {}\n", SOCKS)));
+        let commands = fake.commands.lock().unwrap().clone();
+        assert!(commands.iter().any(|command| command == "TAKEOWNERSHIP"));
+        assert!(commands.iter().any(|command| command.starts_with("SETEVENTS")));
+    }
+
+    #[tokio::test]
+    async fn restarts_after_a_crash() {
+        let fake = FakeTor::new("crash fail stay").await;
+        let mut tor = fake.managed();
+        let (child, control) = tor.start().await.unwrap();
+        let mut reattached = control.reattached();
+        let shutdown = Shutdown::default();
+        let metrics = Arc::new(Metrics::default());
+        tokio::spawn(tor.supervise(child, control.clone(), metrics, shutdown.clone()));
+
+        time::timeout(Duration::from_secs(20), reattached.changed())
+            .await
+            .expect("restarted")
+            .unwrap();
+        assert_eq!(fake.starts(), 3);
+        // The original handle now talks to the restarted Tor
+        time::timeout(Duration::from_secs(10), control.command("GETINFO version"))
+            .await
+            .expect("reply")
+            .unwrap();
+        shutdown.request();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_failed_restarts() {
+        let fake = FakeTor::new("crash").await;
+        let mut tor = fake.managed();
+        tor.restart_attempts = 3;
+        let (child, control) = tor.start().await.unwrap();
+        let shutdown = Shutdown::default();
+        let supervised = tor.supervise(child, control, Arc::new(Metrics::default()), shutdown.clone());
+        let result = time::timeout(Duration::from_secs(20), supervised)
+            .await
+            .expect("gave up");
+        assert!(result.is_err());
+        assert_eq!(fake.starts(), 4);
+        // Nothing is left running on an instance without its Tor
+        time::timeout(Duration::from_secs(1), shutdown.requested())
+            .await
+            .expect("shutdown requested");
+    }
+}
@@ -0,0 +1,168 @@
+//! Relay metadata from the consensus (`GETINFO ns/id/...`) plus Tor's GeoIP
+//! database (`GETINFO ip-to-country/...`).
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::control::TorControl;
+
+/// A consensus is valid for an hour; don't trust cached entries for longer.
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone)]
+pub struct RelayInfo {
+    pub nickname: String,
+    pub address: IpAddr,
+    pub or_port: u16,
+    pub flags: Vec<String>,
+    /// Consensus weight from the `w Bandwidth=` line.
+    pub bandwidth: Option<u64>,
+    /// Exit policy summary from the `p` line, e.g. `accept 80,443`.
+    pub exit_policy: Option<String>,
+    /// Two-letter country code, if Tor's GeoIP database knows the address.
+    pub country: Option<String>,
+}
+
+impl RelayInfo {
+    /// Parses the `r`/`s`/`w`/`p` lines of a router status entry.
+    pub fn parse(fingerprint: &str, lines: &[String]) -> Result<Self> {
+        let r = lines
+            .iter()
+            .find(|l| l.starts_with("r "))
+            .ok_or_else(|| anyhow!("No router status entry for {}", fingerprint))?;
+
+        // `r nickname identity [digest] date time IP ORPort DirPort`: the
+        // digest is missing in microdesc flavoured entries.
+        let parts: Vec<&str> = r.split_whitespace().collect();
+        let (nickname, address, or_port) = match parts.as_slice() {
+            ["r", nickname, _, _, _, _, address, or_port, _]
+            | ["r", nickname, _, _, _, address, or_port, _] => (*nickname, *address, *or_port),
+            _ => return Err(anyhow!("Malformed router status line: {}", r)),
+        };
+        let address = address
+            .parse()
+            .map_err(|_| anyhow!("Invalid relay address {:?}", address))?;
+        let or_port = or_port
+            .parse()
+            .map_err(|_| anyhow!("Invalid ORPort {:?}", or_port))?;
+
+        let line = |prefix: &str| lines.iter().find_map(|l| l.strip_prefix(prefix));
+        let flags = line("s ")
+            .map(|s| s.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+        let bandwidth = line("w ").and_then(|w| {
+            w.split_whitespace()
+                .find_map(|kv| kv.strip_prefix("Bandwidth="))
+                .and_then(|v| v.parse().ok())
+        });
+
+        Ok(Self {
+            nickname: nickname.to_string(),
+            address,
+            or_port,
+            flags,
+            bandwidth,
+            exit_policy: line("p ").map(str::to_string),
+            country: None,
+        })
+    }
+}
+
+/// Per-fingerprint cache in front of the relay lookups.
+#[derive(Clone, Default)]
+pub struct RelayCache {
+    entries: Arc<Mutex<HashMap<String, (Instant, RelayInfo)>>>,
+}
+
+impl RelayCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn lookup(&self, control: &TorControl, fingerprint: &str) -> Result<RelayInfo> {
+        if let Some((fetched, info)) = self.entries.lock().unwrap().get(fingerprint) {
+            if fetched.elapsed() < CACHE_TTL {
+                return Ok(info.clone());
+            }
+        }
+
+        let mut info = control.relay_info(fingerprint).await?;
+        info.country = control.ip_to_country(info.address).await?;
+
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(fingerprint.to_string(), (Instant::now(), info.clone()));
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FP: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parses_router_status_entries() {
+        let entry = lines(
+            "r relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 7PMoTHXSFPMZoFBgvFAeUf1SP+0 2024-03-01 12:00:00 192.0.2.1 9001 0\n\
+             a [2001:db8::1]:9001\n\
+             s Exit Fast Guard Running Stable Valid\n\
+             v Tor 0.4.8.9\n\
+             w Bandwidth=5400 Measured=6000\n\
+             p accept 80,443",
+        );
+        let relay = RelayInfo::parse(FP, &entry).unwrap();
+        assert_eq!(relay.nickname, "relay1");
+        assert_eq!(relay.address, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(relay.or_port, 9001);
+        assert_eq!(relay.flags, ["Exit", "Fast", "Guard", "Running", "Stable", "Valid"]);
+        assert_eq!(relay.bandwidth, Some(5400));
+        assert_eq!(relay.exit_policy.as_deref(), Some("accept 80,443"));
+        assert_eq!(relay.country, None);
+
+        // Microdesc flavour: no digest
+        let entry = lines("r relay2 ASNFZ4mrze8BI0VniavN7wEjRWc 2024-03-01 12:00:00 198.51.100.7 443 80");
+        let relay = RelayInfo::parse(FP, &entry).unwrap();
+        assert_eq!(relay.nickname, "relay2");
+        assert_eq!(relay.address, "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert_eq!(relay.or_port, 443);
+    }
+
+    #[test]
+    fn tolerates_missing_optional_lines() {
+        let entry =
+            lines("r relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 2024-03-01 12:00:00 192.0.2.1 9001 0\nw Bandwidth=lots");
+        let relay = RelayInfo::parse(FP, &entry).unwrap();
+        assert!(relay.flags.is_empty());
+        assert_eq!(relay.bandwidth, None);
+        assert_eq!(relay.exit_policy, None);
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        let cases = [
+            "",
+            "s Exit Running",
+            "r",
+            "r relay1",
+            "r relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 192.0.2.1 9001 0",
+            "r relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 2024-03-01 12:00:00 192.0.2.1 9001",
+            "r relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 2024-03-01 12:00:00 192.0.2.1 9001 0 extra fields",
+            "r relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 2024-03-01 12:00:00 not-an-ip 9001 0",
+            "r relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 2024-03-01 12:00:00 192.0.2.1 99999 0",
+            "router relay1 ASNFZ4mrze8BI0VniavN7wEjRWc 2024-03-01 12:00:00 192.0.2.1 9001 0",
+        ];
+        for case in cases {
+            assert!(RelayInfo::parse(FP, &lines(case)).is_err(), "{:?}", case);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! Parser for Tor control-port replies as described in control-spec §2.3 / §4.
+//!
+//! A reply is a sequence of lines sharing a three digit status code. Lines
+//! ending in `-` are mid-reply lines, lines ending in `+` start a data block
+//! that is terminated by a lone `.`, and the line with a space after the code
+//! ends the reply. Codes in the 6xx range are asynchronous event
+//! notifications and never answer a command.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// A single line of a reply, together with its data block (if any).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyLine {
+    pub code: u16,
+    pub text: String,
+    pub data: Option<Vec<String>>,
+}
+
+/// A complete reply (or async event) read from the control port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<ReplyLine>,
+}
+
+impl Reply {
+    pub fn is_async(&self) -> bool {
+        (600..700).contains(&self.code)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    /// Text of the final line, e.g. `OK` for a plain `250 OK`.
+    pub fn message(&self) -> &str {
+        self.lines.last().map(|l| l.text.as_str()).unwrap_or("")
+    }
+
+    /// Turns an error status code into a typed [`ControlError`].
+    pub fn into_result(self) -> Result<Reply, ControlError> {
+        match ControlError::from_reply(&self) {
+            Some(err) => Err(err),
+            None => Ok(self),
+        }
+    }
+
+    /// Looks up the value of a `GETINFO`/`GETCONF` style `key=value` line.
+    ///
+    /// Values delivered as a data block are returned line by line; single-line
+    /// values are returned as one entry.
+    pub fn values(&self, key: &str) -> Option<Vec<String>> {
+        self.lines.iter().find_map(|line| {
+            let (k, v) = line.text.split_once('=').unwrap_or((line.text.as_str(), ""));
+            if k != key {
+                return None;
+            }
+            match &line.data {
+                Some(data) => Some(data.clone()),
+                None if v.is_empty() => Some(Vec::new()),
+                None => Some(vec![v.to_string()]),
+            }
+        })
+    }
+}
+
+/// Typed view of the error status codes a controller can receive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlError {
+    /// 451 Resource exhausted
+    ResourceExhausted(String),
+    /// 500 Syntax error: protocol
+    ProtocolSyntax(String),
+    /// 510 Unrecognized command
+    UnrecognizedCommand(String),
+    /// 511 Unimplemented command
+    UnimplementedCommand(String),
+    /// 512 Syntax error in command argument
+    ArgumentSyntax(String),
+    /// 513 Unrecognized command argument
+    UnrecognizedArgument(String),
+    /// 514 Authentication required
+    AuthenticationRequired(String),
+    /// 515 Bad authentication
+    BadAuthentication(String),
+    /// 550 Unspecified Tor error
+    Unspecified(String),
+    /// 551 Internal error
+    Internal(String),
+    /// 552 Unrecognized entity
+    UnrecognizedEntity(String),
+    /// 553 Invalid configuration value
+    InvalidConfigValue(String),
+    /// 554 Invalid descriptor
+    InvalidDescriptor(String),
+    /// 555 Unmanaged entity
+    UnmanagedEntity(String),
+    /// Any other non-2xx code not listed in the spec.
+    Other { code: u16, message: String },
+    /// The controller sent something that isn't a valid reply line.
+    Malformed(String),
+}
+
+impl ControlError {
+    pub fn from_reply(reply: &Reply) -> Option<Self> {
+        if reply.is_ok() || reply.is_async() {
+            return None;
+        }
+        let message = reply.message().to_string();
+        Some(match reply.code {
+            451 => Self::ResourceExhausted(message),
+            500 => Self::ProtocolSyntax(message),
+            510 => Self::UnrecognizedCommand(message),
+            511 => Self::UnimplementedCommand(message),
+            512 => Self::ArgumentSyntax(message),
+            513 => Self::UnrecognizedArgument(message),
+            514 => Self::AuthenticationRequired(message),
+            515 => Self::BadAuthentication(message),
+            550 => Self::Unspecified(message),
+            551 => Self::Internal(message),
+            552 => Self::UnrecognizedEntity(message),
+            553 => Self::InvalidConfigValue(message),
+            554 => Self::InvalidDescriptor(message),
+            555 => Self::UnmanagedEntity(message),
+            code => Self::Other { code, message },
+        })
+    }
+
+    pub fn code(&self) -> Option<u16> {
+        Some(match self {
+            Self::ResourceExhausted(_) => 451,
+            Self::ProtocolSyntax(_) => 500,
+            Self::UnrecognizedCommand(_) => 510,
+            Self::UnimplementedCommand(_) => 511,
+            Self::ArgumentSyntax(_) => 512,
+            Self::UnrecognizedArgument(_) => 513,
+            Self::AuthenticationRequired(_) => 514,
+            Self::BadAuthentication(_) => 515,
+            Self::Unspecified(_) => 550,
+            Self::Internal(_) => 551,
+            Self::UnrecognizedEntity(_) => 552,
+            Self::InvalidConfigValue(_) => 553,
+            Self::InvalidDescriptor(_) => 554,
+            Self::UnmanagedEntity(_) => 555,
+            Self::Other { code, .. } => *code,
+            Self::Malformed(_) => return None,
+        })
+    }
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (what, message) = match self {
+            Self::ResourceExhausted(m) => ("resource exhausted", m),
+            Self::ProtocolSyntax(m) => ("protocol syntax error", m),
+            Self::UnrecognizedCommand(m) => ("unrecognized command", m),
+            Self::UnimplementedCommand(m) => ("unimplemented command", m),
+            Self::ArgumentSyntax(m) => ("syntax error in command argument", m),
+            Self::UnrecognizedArgument(m) => ("unrecognized command argument", m),
+            Self::AuthenticationRequired(m) => ("authentication required", m),
+            Self::BadAuthentication(m) => ("bad authentication", m),
+            Self::Unspecified(m) => ("unspecified Tor error", m),
+            Self::Internal(m) => ("internal error", m),
+            Self::UnrecognizedEntity(m) => ("unrecognized entity", m),
+            Self::InvalidConfigValue(m) => ("invalid configuration value", m),
+            Self::InvalidDescriptor(m) => ("invalid descriptor", m),
+            Self::UnmanagedEntity(m) => ("unmanaged entity", m),
+            Self::Other { code, message } => {
+                return write!(f, "Tor control error {}: {}", code, message)
+            }
+            Self::Malformed(line) => return write!(f, "Malformed control reply line: {:?}", line),
+        };
+        match self.code() {
+            Some(code) => write!(f, "Tor control error {} ({}): {}", code, what, message),
+            None => write!(f, "Tor control error ({}): {}", what, message),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Incremental reply parser; feed it one line at a time (without CRLF).
+///
+/// Lines of async events are collected apart from those of a command reply,
+/// so an event arriving in the middle of one doesn't get mixed into it.
+#[derive(Debug, Default)]
+pub struct ReplyParser {
+    lines: Vec<ReplyLine>,
+    event_lines: Vec<ReplyLine>,
+    data: Option<ReplyLine>,
+}
+
+impl ReplyParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `Some(reply)` once a full reply has been read.
+    pub fn feed(&mut self, line: &str) -> Result<Option<Reply>, ControlError> {
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(mut pending) = self.data.take() {
+            if line == "." {
+                self.lines_for(pending.code).push(pending);
+            } else {
+                let unescaped = line.strip_prefix('.').unwrap_or(line);
+                pending.data.get_or_insert_with(Vec::new).push(unescaped.to_string());
+                self.data = Some(pending);
+            }
+            return Ok(None);
+        }
+
+        if line.len() < 4 || !line.is_char_boundary(3) {
+            return Err(ControlError::Malformed(line.to_string()));
+        }
+        let code = line[..3]
+            .parse::<u16>()
+            .map_err(|_| ControlError::Malformed(line.to_string()))?;
+        let text = line[4..].to_string();
+
+        match line.as_bytes()[3] {
+            b'-' => {
+                self.lines_for(code).push(ReplyLine { code, text, data: None });
+                Ok(None)
+            }
+            b'+' => {
+                self.data = Some(ReplyLine { code, text, data: Some(Vec::new()) });
+                Ok(None)
+            }
+            b' ' => {
+                let lines = self.lines_for(code);
+                lines.push(ReplyLine { code, text, data: None });
+                let lines = std::mem::take(lines);
+                Ok(Some(Reply { code, lines }))
+            }
+            _ => Err(ControlError::Malformed(line.to_string())),
+        }
+    }
+
+    fn lines_for(&mut self, code: u16) -> &mut Vec<ReplyLine> {
+        if (600..700).contains(&code) {
+            &mut self.event_lines
+        } else {
+            &mut self.lines
+        }
+    }
+}
+
+/// Splits a reply line into positional words and `KEY=VALUE` pairs.
+///
+/// Quoted values (`KEY="a \"b\""`) are unescaped. Keys are kept verbatim.
+pub fn parse_args(text: &str) -> (Vec<String>, HashMap<String, String>) {
+    let mut positional = Vec::new();
+    let mut pairs = HashMap::new();
+
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut token = String::new();
+        let mut key = None;
+        while let Some(&c) = chars.peek() {
+            if c == ' ' {
+                break;
+            }
+            chars.next();
+            if c == '=' && key.is_none() {
+                key = Some(std::mem::take(&mut token));
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    token = read_quoted(&mut chars);
+                    break;
+                }
+            } else {
+                token.push(c);
+            }
+        }
+
+        match key {
+            Some(key) => {
+                pairs.insert(key, token);
+            }
+            None => positional.push(token),
+        }
+    }
+
+    (positional, pairs)
+}
+
+/// Reads the rest of a quoted string (the opening quote already consumed).
+fn read_quoted(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some(other) => out.push(other),
+                None => break,
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Quotes a string for use as a control-port argument.
+pub fn quote(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Input lines and the code and line texts of each reply they complete.
+    type Case<'a> = (&'a str, &'a [&'a str], &'a [(u16, &'a [&'a str])]);
+
+    /// Feeds `lines` and returns every reply completed along the way.
+    fn parse(lines: &[&str]) -> Vec<Reply> {
+        let mut parser = ReplyParser::new();
+        lines
+            .iter()
+            .filter_map(|line| parser.feed(line).expect("valid reply line"))
+            .collect()
+    }
+
+    #[test]
+    fn parses_replies() {
+        let cases: &[Case] = &[
+            ("single line", &["250 OK"], &[(250, &["OK"])]),
+            ("crlf", &["250 OK\r\n"], &[(250, &["OK"])]),
+            (
+                "multi-line",
+                &["250-version=0.4.8.9", "250-traffic/read=12", "250 OK"],
+                &[(250, &["version=0.4.8.9", "traffic/read=12", "OK"])],
+            ),
+            (
+                "event between replies",
+                &["650 CIRC 1 BUILT", "250 OK"],
+                &[(650, &["CIRC 1 BUILT"]), (250, &["OK"])],
+            ),
+            (
+                "event inside a reply",
+                &["250-version=0.4.8.9", "650 BW 10 20", "250 OK"],
+                &[(650, &["BW 10 20"]), (250, &["version=0.4.8.9", "OK"])],
+            ),
+            (
+                "multi-line event inside a reply",
+                &["250-a=1", "650-NS", "250-b=2", "650 OK", "250 OK"],
+                &[(650, &["NS", "OK"]), (250, &["a=1", "b=2", "OK"])],
+            ),
+        ];
+        for (name, input, expected) in cases {
+            let replies = parse(input);
+            let replies: Vec<(u16, Vec<&str>)> = replies
+                .iter()
+                .map(|reply| (reply.code, reply.lines.iter().map(|line| line.text.as_str()).collect()))
+                .collect();
+            let expected: Vec<(u16, Vec<&str>)> =
+                expected.iter().map(|(code, lines)| (*code, lines.to_vec())).collect();
+            assert_eq!(replies, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn parses_data_blocks() {
+        let replies = parse(&[
+            "250+ns/all=",
+            "r relay1 AAAA",
+            "..leading dot",
+            ".",
+            "250-other=1",
+            "250+empty=",
+            ".",
+            "250 OK",
+        ]);
+        assert_eq!(replies.len(), 1);
+        let reply = &replies[0];
+        assert_eq!(
+            reply.values("ns/all"),
+            Some(vec!["r relay1 AAAA".to_string(), ".leading dot".to_string()])
+        );
+        assert_eq!(reply.values("other"), Some(vec!["1".to_string()]));
+        assert_eq!(reply.values("empty"), Some(Vec::new()));
+        assert_eq!(reply.values("missing"), None);
+        assert!(reply.is_ok());
+    }
+
+    #[test]
+    fn data_block_in_an_event() {
+        let replies = parse(&["650+NS", "r relay1 AAAA", ".", "650 OK"]);
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].is_async());
+        assert_eq!(replies[0].lines[0].data, Some(vec!["r relay1 AAAA".to_string()]));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in ["", "25", "250", "abc OK", "250*OK", "2é0 OK"] {
+            let mut parser = ReplyParser::new();
+            assert!(
+                matches!(parser.feed(line), Err(ControlError::Malformed(_))),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn maps_error_codes() {
+        let cases = [
+            (451, ControlError::ResourceExhausted("m".into())),
+            (500, ControlError::ProtocolSyntax("m".into())),
+            (510, ControlError::UnrecognizedCommand("m".into())),
+            (511, ControlError::UnimplementedCommand("m".into())),
+            (512, ControlError::ArgumentSyntax("m".into())),
+            (513, ControlError::UnrecognizedArgument("m".into())),
+            (514, ControlError::AuthenticationRequired("m".into())),
+            (515, ControlError::BadAuthentication("m".into())),
+            (550, ControlError::Unspecified("m".into())),
+            (551, ControlError::Internal("m".into())),
+            (552, ControlError::UnrecognizedEntity("m".into())),
+            (553, ControlError::InvalidConfigValue("m".into())),
+            (554, ControlError::InvalidDescriptor("m".into())),
+            (555, ControlError::UnmanagedEntity("m".into())),
+            (
+                556,
+                ControlError::Other {
+                    code: 556,
+                    message: "m".into(),
+                },
+            ),
+        ];
+        for (code, expected) in cases {
+            let line = format!("{} m", code);
+            let reply = parse(&[line.as_str()]).remove(0);
+            let error = reply.into_result().unwrap_err();
+            assert_eq!(error.code(), Some(code));
+            assert_eq!(error, expected);
+        }
+        for line in ["250 OK", "650 CIRC 1 BUILT"] {
+            assert!(parse(&[line]).remove(0).into_result().is_ok(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn error_message_is_the_last_line() {
+        let reply = parse(&["552-first", "552 Unrecognized key \"foo\""]).remove(0);
+        assert_eq!(
+            reply.into_result(),
+            Err(ControlError::UnrecognizedEntity("Unrecognized key \"foo\"".into()))
+        );
+    }
+
+    #[test]
+    fn splits_arguments() {
+        let (positional, pairs) = parse_args(r#"1 BUILT  PURPOSE=GENERAL REASON="a \"b\"\n" K="#);
+        assert_eq!(positional, ["1", "BUILT"]);
+        assert_eq!(pairs["PURPOSE"], "GENERAL");
+        assert_eq!(pairs["REASON"], "a \"b\"\n");
+        assert_eq!(pairs["K"], "");
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        let value = "pass \"word\"\\\n";
+        let (_, pairs) = parse_args(&format!("K={}", quote(value)));
+        assert_eq!(pairs["K"], value);
+    }
+}
@@ -0,0 +1,401 @@
+//! Identity rotation: NEWNYM with Tor's rate limit honoured, waiting for a
+//! fresh circuit and confirming the exit IP actually changed.
+
+use anyhow::{Context, Result};
+use reqwest::Proxy;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::circuit::CircuitStatus;
+use crate::control::TorControl;
+use crate::events::TorEvent;
+use crate::config::Tuning;
+use crate::exit::ExitSelection;
+use crate::metrics::Metrics;
+
+/// Tor refuses to act on NEWNYM more than once per this interval
+/// (MAX_SIGNAL_NEWNYM_INTERVAL) and delays the request instead.
+const NEWNYM_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct RotationOutcome {
+    pub old_ip: Option<String>,
+    pub new_ip: Option<String>,
+    pub attempts: u32,
+    pub elapsed: Duration,
+    /// Time from each NEWNYM until Tor reported a new circuit built, for the
+    /// attempts where it did.
+    pub circuit_times: Vec<Duration>,
+    /// Times a NEWNYM had to wait out Tor's rate limit.
+    pub rate_limited: u32,
+    /// Country of the new exit IP according to Tor's GeoIP database.
+    pub exit_country: Option<String>,
+    /// The new exit is outside the requested exit countries.
+    pub country_mismatch: bool,
+}
+
+impl RotationOutcome {
+    /// True when we observed a new exit IP distinct from the old one.
+    pub fn changed(&self) -> bool {
+        self.new_ip.is_some() && self.new_ip != self.old_ip
+    }
+
+    /// Changed, and landed in an allowed country.
+    pub fn succeeded(&self) -> bool {
+        self.changed() && !self.country_mismatch
+    }
+}
+
+pub struct Rotator {
+    control: TorControl,
+    socks_port: u16,
+    max_attempts: u32,
+    last_newnym: Option<Instant>,
+    newnym_interval: Duration,
+    exits: ExitSelection,
+    tuning: Arc<Tuning>,
+    metrics: Arc<Metrics>,
+}
+
+impl Rotator {
+    pub fn new(
+        control: TorControl,
+        socks_port: u16,
+        max_attempts: u32,
+        tuning: Arc<Tuning>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            control,
+            socks_port,
+            max_attempts: max_attempts.max(1),
+            last_newnym: None,
+            newnym_interval: NEWNYM_INTERVAL,
+            exits: ExitSelection::default(),
+            tuning,
+            metrics,
+        }
+    }
+
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+    }
+
+    /// Exit countries a rotation has to land in to count as successful.
+    pub fn set_exit_selection(&mut self, exits: ExitSelection) {
+        self.exits = exits;
+    }
+
+    /// Requests new identities until the exit IP differs from `current_ip`
+    /// (fetched first if unknown) and is in an allowed exit country, or the
+    /// attempt limit is reached.
+    pub async fn rotate(&mut self, current_ip: Option<String>) -> Result<RotationOutcome> {
+        let started = Instant::now();
+        let old_ip = match current_ip {
+            Some(ip) => Some(ip),
+            None => match exit_ip(self.socks_port, &self.tuning, &self.metrics).await {
+                Ok(ip) => Some(ip),
+                Err(e) => {
+                    warn!("Could not determine exit IP before rotating: {}", e);
+                    None
+                }
+            },
+        };
+
+        let mut outcome = RotationOutcome {
+            old_ip,
+            new_ip: None,
+            attempts: 0,
+            elapsed: Duration::ZERO,
+            circuit_times: Vec::new(),
+            rate_limited: 0,
+            exit_country: None,
+            country_mismatch: false,
+        };
+
+        while outcome.attempts < self.max_attempts {
+            outcome.attempts += 1;
+            if self.wait_for_newnym_slot().await {
+                outcome.rate_limited += 1;
+            }
+
+            // Subscribe before signalling so we can't miss the build event
+            let mut events = self.control.subscribe();
+            self.control.command("SIGNAL CLEARDNSCACHE").await?;
+            self.control.command("SIGNAL NEWNYM").await?;
+            self.last_newnym = Some(Instant::now());
+
+            // If no circuit shows up the IP check below makes Tor build one
+            if let Some(built) = wait_for_fresh_circuit(&mut events, self.tuning.circuit_timeout).await {
+                outcome.circuit_times.push(built);
+            }
+
+            match exit_ip(self.socks_port, &self.tuning, &self.metrics).await {
+                Ok(ip) => {
+                    outcome.new_ip = Some(ip);
+                    if !outcome.changed() {
+                        warn!(
+                            "Exit IP unchanged after NEWNYM (attempt {}/{})",
+                            outcome.attempts, self.max_attempts
+                        );
+                        continue;
+                    }
+                    self.check_exit_country(&mut outcome).await;
+                    if outcome.succeeded() {
+                        break;
+                    }
+                }
+                Err(e) => warn!(
+                    "Failed to check exit IP (attempt {}/{}): {}",
+                    outcome.attempts, self.max_attempts, e
+                ),
+            }
+        }
+
+        outcome.elapsed = started.elapsed();
+        Ok(outcome)
+    }
+
+    async fn check_exit_country(&self, outcome: &mut RotationOutcome) {
+        outcome.exit_country = None;
+        outcome.country_mismatch = false;
+        let Some(ip) = outcome.new_ip.as_deref().and_then(|ip| ip.parse().ok()) else {
+            return;
+        };
+
+        match self.control.ip_to_country(ip).await {
+            Ok(Some(country)) => {
+                if !self.exits.allows(&country) {
+                    warn!(
+                        "New exit {} is in {}, outside the requested {} (attempt {}/{})",
+                        ip, country, self.exits, outcome.attempts, self.max_attempts
+                    );
+                    outcome.country_mismatch = true;
+                }
+                outcome.exit_country = Some(country);
+            }
+            Ok(None) if !self.exits.is_empty() => {
+                warn!("Cannot verify the exit country of {}: unknown to Tor's GeoIP database", ip);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to look up exit country: {}", e),
+        }
+    }
+
+    /// Waits until Tor will act on a NEWNYM right away, going by what Tor
+    /// reports and, for a Tor that doesn't, by when we last sent one.
+    /// Returns whether it had to wait.
+    async fn wait_for_newnym_slot(&self) -> bool {
+        let since_last = self
+            .last_newnym
+            .map(|last| self.newnym_interval.saturating_sub(last.elapsed()))
+            .unwrap_or_default();
+        let reported = match self.control.newnym_delay().await {
+            Ok(delay) => delay.unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to ask Tor about its NEWNYM rate limit: {}", e);
+                Duration::ZERO
+            }
+        };
+
+        let wait = since_last.max(reported);
+        if wait.is_zero() {
+            return false;
+        }
+        info!("Waiting {:.1}s for Tor's NEWNYM rate limit", wait.as_secs_f32());
+        time::sleep(wait).await;
+        true
+    }
+}
+
+/// Waits for a usable circuit to be built, returning how long that took.
+async fn wait_for_fresh_circuit(events: &mut broadcast::Receiver<TorEvent>, timeout: Duration) -> Option<Duration> {
+    let started = time::Instant::now();
+    let deadline = started + timeout;
+
+    loop {
+        let event = match time::timeout_at(deadline, events.recv()).await {
+            Err(_) => {
+                warn!("No new circuit reported within {}s", timeout.as_secs());
+                return None;
+            }
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+            Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+            Ok(Ok(event)) => event,
+        };
+
+        match event {
+            TorEvent::Circ(circuit) if circuit.is_usable() => {
+                info!("New circuit #{} built", circuit.id);
+                return Some(started.elapsed());
+            }
+            TorEvent::Circ(circuit) if circuit.status == CircuitStatus::Failed => {
+                warn!("Circuit #{} failed: {}", circuit.id, circuit.failure_reason());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Client that goes through Tor without keeping idle connections around, so
+/// no pooled keep-alive connection pins requests to an old circuit.
+pub fn tor_client(socks_port: u16, timeout: Duration) -> Result<reqwest::Client> {
+    let proxy = Proxy::all(format!("socks5h://127.0.0.1:{}", socks_port))
+        .context("Failed to create proxy configuration")?;
+    reqwest::Client::builder()
+        .proxy(proxy)
+        .pool_max_idle_per_host(0)
+        .timeout(timeout)
+        .build()
+        .context("Failed to build client")
+}
+
+/// Fetches the current exit IP over a fresh connection.
+pub async fn exit_ip(socks_port: u16, tuning: &Tuning, metrics: &Metrics) -> Result<String> {
+    let client = tor_client(socks_port, tuning.request_timeout)?;
+    Ok(tuning.ip_providers.lookup(&client, metrics).await?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// An IP echo service answering with `ips` in turn, repeating the last.
+    async fn ip_service(ips: &'static [&'static str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let ip = ips[hits.fetch_add(1, Ordering::SeqCst).min(ips.len() - 1)];
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    let _ = stream.read(&mut request).await;
+                    let response =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", ip.len(), ip);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        url
+    }
+
+    /// A Tor that builds a circuit after every NEWNYM, counting them, puts
+    /// every exit in `country` and answers `GETINFO signal/newnym` with
+    /// `delays` in turn (552 once they run out).
+    async fn rotator(
+        ips: &'static [&'static str],
+        country: &'static str,
+        delays: &'static [u64],
+        max_attempts: u32,
+    ) -> (Rotator, Arc<AtomicUsize>) {
+        let newnyms = Arc::new(AtomicUsize::new(0));
+        let queries = AtomicUsize::new(0);
+        let counter = newnyms.clone();
+        let control = testutil::fake_control(move |command| match command {
+            "SIGNAL NEWNYM" => {
+                counter.fetch_add(1, Ordering::SeqCst);
+                format!("250 OK\n650 CIRC 7 BUILT ${}~exit PURPOSE=GENERAL", "A".repeat(40))
+            }
+            "GETINFO signal/newnym" => match delays.get(queries.fetch_add(1, Ordering::SeqCst)) {
+                Some(delay) => format!("250-signal/newnym={}\n250 OK", delay),
+                None => "552 Unrecognized key \"signal/newnym\"".to_string(),
+            },
+            _ => match command.strip_prefix("GETINFO ip-to-country/") {
+                Some(ip) => format!("250-ip-to-country/{}={}\n250 OK", ip, country),
+                None => "250 OK".to_string(),
+            },
+        })
+        .await;
+        let socks = testutil::socks_port(|_| async { None }).await;
+        let tuning = Arc::new(testutil::tuning(&ip_service(ips).await, None));
+        let mut rotator = Rotator::new(control, socks.port(), max_attempts, tuning, Arc::new(Metrics::default()));
+        rotator.newnym_interval = Duration::ZERO;
+        (rotator, newnyms)
+    }
+
+    fn outcome(old_ip: Option<&str>, new_ip: Option<&str>, country_mismatch: bool) -> RotationOutcome {
+        RotationOutcome {
+            old_ip: old_ip.map(str::to_string),
+            new_ip: new_ip.map(str::to_string),
+            attempts: 1,
+            elapsed: Duration::ZERO,
+            circuit_times: Vec::new(),
+            rate_limited: 0,
+            exit_country: None,
+            country_mismatch,
+        }
+    }
+
+    #[test]
+    fn outcome_succeeds_on_a_new_ip_in_an_allowed_country() {
+        assert!(outcome(Some("192.0.2.1"), Some("192.0.2.2"), false).succeeded());
+        assert!(outcome(None, Some("192.0.2.2"), false).succeeded());
+
+        let unchanged = outcome(Some("192.0.2.1"), Some("192.0.2.1"), false);
+        assert!(!unchanged.changed() && !unchanged.succeeded());
+        let unknown = outcome(Some("192.0.2.1"), None, false);
+        assert!(!unknown.changed() && !unknown.succeeded());
+        let mismatch = outcome(Some("192.0.2.1"), Some("192.0.2.2"), true);
+        assert!(mismatch.changed() && !mismatch.succeeded());
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_exit_ip_changes() {
+        let (mut rotator, newnyms) = rotator(&["192.0.2.1", "192.0.2.1", "192.0.2.2"], "de", &[], 5).await;
+        let outcome = rotator.rotate(None).await.unwrap();
+        assert_eq!(outcome.old_ip.as_deref(), Some("192.0.2.1"));
+        assert_eq!(outcome.new_ip.as_deref(), Some("192.0.2.2"));
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.circuit_times.len(), 2);
+        assert_eq!(outcome.exit_country.as_deref(), Some("de"));
+        assert_eq!(outcome.rate_limited, 0);
+        assert!(outcome.succeeded());
+        assert_eq!(newnyms.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_attempt_limit() {
+        let (mut rotator, newnyms) = rotator(&["192.0.2.1"], "de", &[], 3).await;
+        let outcome = rotator.rotate(Some("192.0.2.1".to_string())).await.unwrap();
+        assert_eq!(outcome.attempts, 3);
+        assert!(!outcome.changed());
+        assert_eq!(newnyms.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_exits_outside_the_requested_countries() {
+        let (mut rotator, _) = rotator(&["192.0.2.2", "192.0.2.3"], "de", &[], 2).await;
+        rotator.set_exit_selection(ExitSelection { countries: vec!["us".to_string()], excluded: Vec::new() });
+        let outcome = rotator.rotate(Some("192.0.2.1".to_string())).await.unwrap();
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.new_ip.as_deref(), Some("192.0.2.3"));
+        assert!(outcome.changed() && outcome.country_mismatch && !outcome.succeeded());
+    }
+
+    #[tokio::test]
+    async fn waits_out_the_delay_tor_reports() {
+        let (mut rotator, _) = rotator(&["192.0.2.2"], "de", &[1, 0], 1).await;
+        let outcome = rotator.rotate(Some("192.0.2.1".to_string())).await.unwrap();
+        assert!(outcome.succeeded());
+        assert_eq!(outcome.rate_limited, 1);
+        assert!(outcome.elapsed >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn spaces_out_newnyms_for_a_tor_that_does_not_report_its_delay() {
+        let (mut rotator, _) = rotator(&["192.0.2.1", "192.0.2.2"], "de", &[], 2).await;
+        rotator.newnym_interval = Duration::from_millis(500);
+        let outcome = rotator.rotate(Some("192.0.2.1".to_string())).await.unwrap();
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(outcome.rate_limited, 1);
+        assert!(outcome.elapsed >= Duration::from_millis(500));
+    }
+}
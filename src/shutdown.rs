@@ -0,0 +1,127 @@
+//! Stopping cleanly on SIGINT/SIGTERM: rotation loops restore what they
+//! changed in Tor, frontends stop accepting and drain their connections,
+//! and control connections are closed with `QUIT`.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{info, warn};
+
+/// How long frontend connections get to finish once we stop accepting.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long Tor gets to acknowledge `QUIT` and close the connection.
+pub const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long Tors we launched get to exit after we quit their control
+/// connection before they are killed.
+pub const TOR_EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells every task holding a clone that it is time to stop.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.send_replace(true);
+    }
+
+    /// Resolves once shutdown has been requested.
+    pub async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        let _ = requested.wait_for(|requested| *requested).await;
+    }
+}
+
+/// Requests shutdown on the first SIGINT or SIGTERM and exits right away on
+/// the second, with the usual `128 + signal` status.
+pub async fn on_signals(shutdown: Shutdown) {
+    let Some(signal) = next_signal().await else {
+        return;
+    };
+    info!("{} received, shutting down (send it again to stop immediately)", signal.name());
+    shutdown.request();
+
+    if let Some(signal) = next_signal().await {
+        warn!("{} received again, exiting without cleaning up", signal.name());
+        std::process::exit(signal.exit_code());
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+impl Signal {
+    fn name(self) -> &'static str {
+        match self {
+            Signal::Interrupt => "SIGINT",
+            Signal::Terminate => "SIGTERM",
+        }
+    }
+
+    fn exit_code(self) -> i32 {
+        match self {
+            Signal::Interrupt => 130,
+            Signal::Terminate => 143,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn next_signal() -> Option<Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (mut interrupts, mut terminates) = match (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) {
+        (Ok(interrupts), Ok(terminates)) => (interrupts, terminates),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Cannot listen for SIGINT/SIGTERM, graceful shutdown is disabled: {}", e);
+            return None;
+        }
+    };
+    tokio::select! {
+        Some(()) = interrupts.recv() => Some(Signal::Interrupt),
+        Some(()) = terminates.recv() => Some(Signal::Terminate),
+        else => None,
+    }
+}
+
+#[cfg(not(unix))]
+async fn next_signal() -> Option<Signal> {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => Some(Signal::Interrupt),
+        Err(e) => {
+            warn!("Cannot listen for Ctrl-C, graceful shutdown is disabled: {}", e);
+            None
+        }
+    }
+}
+
+/// Waits for a frontend's open connections to finish, cutting off whatever
+/// is still open after [`DRAIN_TIMEOUT`].
+pub async fn drain(frontend: &str, mut connections: JoinSet<()>) {
+    if connections.is_empty() {
+        return;
+    }
+    info!("Waiting for {} {} connection(s) to finish", connections.len(), frontend);
+    let finished = time::timeout(DRAIN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if finished.is_err() {
+        warn!(
+            "Closing {} {} connection(s) still open after {}s",
+            connections.len(),
+            frontend,
+            DRAIN_TIMEOUT.as_secs()
+        );
+        connections.shutdown().await;
+    }
+}
+
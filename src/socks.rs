@@ -0,0 +1,575 @@
+//! SOCKS5 (RFC 1928/1929) frontend that forwards every CONNECT to one of
+//! Tor's SocksPorts, plus the client half used to talk to those ports.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, info};
+
+use crate::frontend;
+use crate::killswitch::KillSwitch;
+use crate::pool::TorPool;
+use crate::shutdown::Shutdown;
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+/// Tor's extension resolving a host name at the exit.
+const CMD_RESOLVE: u8 = 0xf0;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Handshakes (ours and Tor's) must finish within this time; Tor itself
+/// gives up on a circuit well before that.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Destination of a CONNECT. Domains are passed on to Tor unresolved so
+/// DNS happens at the exit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl TargetAddr {
+    /// Parses `host:port`, `1.2.3.4:port` or `[::1]:port`, falling back to
+    /// `default_port` when none is given.
+    pub fn parse(s: &str, default_port: u16) -> Result<Self> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(TargetAddr::Ip(addr));
+        }
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => (
+                host,
+                port.parse().map_err(|_| anyhow!("Invalid port in {:?}", s))?,
+            ),
+            _ => (s, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(anyhow!("Missing host in {:?}", s));
+        }
+        Ok(match host.parse::<IpAddr>() {
+            Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+            Err(_) => TargetAddr::Domain(host.to_string(), port),
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            TargetAddr::Domain(host, port) => {
+                let len = u8::try_from(host.len()).map_err(|_| anyhow!("Host name too long"))?;
+                buf.push(ATYP_DOMAIN);
+                buf.push(len);
+                buf.extend_from_slice(host.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R, atyp: u8) -> Result<Option<Self>> {
+        Ok(Some(match atyp {
+            ATYP_IPV4 => {
+                let mut ip = [0u8; 4];
+                reader.read_exact(&mut ip).await?;
+                let port = reader.read_u16().await?;
+                TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0u8; 16];
+                reader.read_exact(&mut ip).await?;
+                let port = reader.read_u16().await?;
+                TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+            }
+            ATYP_DOMAIN => {
+                let len = reader.read_u8().await? as usize;
+                let mut host = vec![0u8; len];
+                reader.read_exact(&mut host).await?;
+                let port = reader.read_u16().await?;
+                let host = String::from_utf8(host).map_err(|_| anyhow!("Host name is not UTF-8"))?;
+                TargetAddr::Domain(host, port)
+            }
+            _ => return Ok(None),
+        }))
+    }
+}
+
+/// How connections are kept apart on Tor's side. Tor isolates streams
+/// with different SOCKS credentials (IsolateSOCKSAuth, on by default), so
+/// we derive the credentials we send upstream from this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Isolation {
+    /// Everything may share circuits (until the next rotation).
+    None,
+    /// Connections from the same client address share circuits.
+    Client,
+    /// Every connection gets its own circuit.
+    Connection,
+}
+
+/// Error from the upstream SOCKS server, carrying its reply code so it can
+/// be relayed to our client unchanged.
+#[derive(Debug)]
+pub struct SocksReplyError(pub u8);
+
+impl fmt::Display for SocksReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.0 {
+            0x01 => "general failure",
+            0x02 => "connection not allowed by ruleset",
+            0x03 => "network unreachable",
+            0x04 => "host unreachable",
+            0x05 => "connection refused",
+            0x06 => "TTL expired",
+            0x07 => "command not supported",
+            0x08 => "address type not supported",
+            // Tor's extended error codes (proposal 304)
+            0xf0 => "onion service descriptor not found",
+            0xf1 => "onion service descriptor invalid",
+            0xf2 => "onion service introduction failed",
+            0xf3 => "onion service rendezvous failed",
+            0xf4 => "onion service missing client authorization",
+            0xf5 => "onion service wrong client authorization",
+            0xf6 => "invalid onion service address",
+            0xf7 => "onion service introduction timed out",
+            _ => "unknown error",
+        };
+        write!(f, "SOCKS error 0x{:02x}: {}", self.0, reason)
+    }
+}
+
+impl std::error::Error for SocksReplyError {}
+
+/// Opens a stream to `target` through the SOCKS5 server at `proxy`,
+/// optionally authenticating with (isolation) credentials.
+pub async fn connect(
+    proxy: SocketAddr,
+    target: &TargetAddr,
+    credentials: Option<(&str, &str)>,
+) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .with_context(|| format!("Failed to connect to SOCKS proxy {}", proxy))?;
+    time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream, CMD_CONNECT, target, credentials))
+        .await
+        .map_err(|_| anyhow!("SOCKS handshake with {} timed out", proxy))??;
+    Ok(stream)
+}
+
+/// Resolves `host` with Tor's `RESOLVE` extension, so the lookup happens at
+/// the exit instead of on our resolver.
+pub async fn resolve(proxy: SocketAddr, host: &str, credentials: Option<(&str, &str)>) -> Result<IpAddr> {
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .with_context(|| format!("Failed to connect to SOCKS proxy {}", proxy))?;
+    let target = TargetAddr::Domain(host.to_string(), 0);
+    let resolved = time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream, CMD_RESOLVE, &target, credentials))
+        .await
+        .map_err(|_| anyhow!("SOCKS handshake with {} timed out", proxy))??;
+    match resolved {
+        TargetAddr::Ip(addr) => Ok(addr.ip()),
+        TargetAddr::Domain(..) => Err(anyhow!("SOCKS proxy {} answered RESOLVE with a host name", proxy)),
+    }
+}
+
+/// Client side of the handshake; returns the bound address from the reply.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    command: u8,
+    target: &TargetAddr,
+    credentials: Option<(&str, &str)>,
+) -> Result<TargetAddr> {
+    let method = if credentials.is_some() { USER_PASS } else { NO_AUTH };
+    stream.write_all(&[VERSION, 1, method]).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(anyhow!("Upstream is not a SOCKS5 server"));
+    }
+    match (choice[1], credentials) {
+        (NO_AUTH, _) => {}
+        (USER_PASS, Some((user, pass))) => {
+            let (ulen, plen) = (
+                u8::try_from(user.len()).map_err(|_| anyhow!("SOCKS username too long"))?,
+                u8::try_from(pass.len()).map_err(|_| anyhow!("SOCKS password too long"))?,
+            );
+            let mut auth = vec![0x01, ulen];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(plen);
+            auth.extend_from_slice(pass.as_bytes());
+            stream.write_all(&auth).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(anyhow!("SOCKS authentication rejected"));
+            }
+        }
+        _ => return Err(anyhow!("SOCKS server accepted none of our authentication methods")),
+    }
+
+    let mut request = vec![VERSION, command, 0x00];
+    target.encode(&mut request)?;
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(anyhow!("Malformed SOCKS reply"));
+    }
+    // Tor includes an address even in error replies; read it regardless.
+    let bound = TargetAddr::read(stream, reply[3])
+        .await?
+        .ok_or_else(|| anyhow!("Unknown address type in SOCKS reply"))?;
+    if reply[1] != REPLY_SUCCEEDED {
+        return Err(SocksReplyError(reply[1]).into());
+    }
+    Ok(bound)
+}
+
+/// Derives the credentials sent to Tor for each frontend connection. Tor
+/// keeps streams with different credentials on different circuits. Shared
+/// by all frontends so their keys never collide.
+pub struct Isolator {
+    isolation: Isolation,
+    /// Bumped after each rotation so new connections stop sharing
+    /// credentials, and therefore circuits, with older ones.
+    epoch: AtomicU64,
+    next_connection: AtomicU64,
+}
+
+impl Isolator {
+    pub fn new(isolation: Isolation) -> Self {
+        Self {
+            isolation,
+            epoch: AtomicU64::new(0),
+            next_connection: AtomicU64::new(0),
+        }
+    }
+
+    /// Called after each identity switch.
+    pub fn next_epoch(&self) {
+        self.epoch.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn credentials(&self, peer: SocketAddr, client_auth: Option<&(String, String)>) -> (String, String) {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        let key = match self.isolation {
+            Isolation::None => "shared".to_string(),
+            Isolation::Client => peer.ip().to_string(),
+            Isolation::Connection => {
+                format!("conn-{}", self.next_connection.fetch_add(1, Ordering::Relaxed))
+            }
+        };
+        // Client supplied credentials are honoured as an extra isolation key,
+        // just like Tor would when talking to it directly. They are hashed,
+        // as each may already take up the 255 bytes a username can have.
+        let user = match client_auth {
+            Some((user, pass)) => {
+                let digest = Sha256::new()
+                    .chain_update((user.len() as u64).to_be_bytes())
+                    .chain_update(user)
+                    .chain_update(pass)
+                    .finalize();
+                format!("rusttator:{}:{}", key, hex::encode(digest))
+            }
+            None => format!("rusttator:{}", key),
+        };
+        (user, epoch.to_string())
+    }
+}
+
+pub struct SocksServer {
+    pool: Arc<TorPool>,
+    isolator: Arc<Isolator>,
+    kill_switch: KillSwitch,
+}
+
+impl SocksServer {
+    pub fn new(pool: Arc<TorPool>, isolator: Arc<Isolator>, kill_switch: KillSwitch) -> Self {
+        Self {
+            pool,
+            isolator,
+            kill_switch,
+        }
+    }
+
+    /// Accepts connections until shutdown, then waits for the open ones to
+    /// finish. While the kill switch is engaged nothing is accepted.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, shutdown: Shutdown) {
+        if let Ok(addr) = listener.local_addr() {
+            info!("🧦 SOCKS5 frontend listening on {}", addr);
+        }
+        let kill_switch = self.kill_switch.clone();
+        frontend::serve("SOCKS5", listener, &kill_switch, shutdown, |client, peer| {
+            let server = self.clone();
+            async move { server.handle(client, peer).await }
+        })
+        .await;
+    }
+
+    async fn handle(&self, mut client: TcpStream, peer: SocketAddr) -> Result<()> {
+        let request = time::timeout(HANDSHAKE_TIMEOUT, read_request(&mut client))
+            .await
+            .map_err(|_| anyhow!("client handshake timed out"))??;
+        let Some((target, client_auth)) = request else {
+            return Ok(());
+        };
+
+        let lease = self.pool.lease();
+        lease.instance.traffic.record_request();
+        let upstream = lease.instance.socks;
+        let (user, pass) = self.isolator.credentials(peer, client_auth.as_ref());
+        debug!("SOCKS {} → {} via {}", peer, target, upstream);
+
+        let mut tor = match connect(upstream, &target, Some((&user, &pass))).await {
+            Ok(tor) => tor,
+            Err(e) => {
+                let code = e.downcast_ref::<SocksReplyError>().map_or(REPLY_GENERAL_FAILURE, |r| r.0);
+                send_reply(&mut client, code).await?;
+                return Err(e.context(format!("connecting to {} via {}", target, upstream)));
+            }
+        };
+        send_reply(&mut client, REPLY_SUCCEEDED).await?;
+
+        let (sent, received) = tokio::io::copy_bidirectional(&mut client, &mut tor).await?;
+        lease.instance.traffic.record_relayed(sent, received);
+        debug!("SOCKS {} → {} closed ({} B sent, {} B received)", peer, target, sent, received);
+        Ok(())
+    }
+}
+
+/// Server side of the handshake. Returns `None` when the request was
+/// answered with an error and the connection should just be closed.
+async fn read_request(client: &mut TcpStream) -> Result<Option<(TargetAddr, Option<(String, String)>)>> {
+    let version = client.read_u8().await?;
+    if version != VERSION {
+        return Err(anyhow!("unsupported SOCKS version {}", version));
+    }
+    let count = client.read_u8().await? as usize;
+    let mut methods = vec![0u8; count];
+    client.read_exact(&mut methods).await?;
+
+    // Prefer username/password so clients can ask for their own isolation
+    let client_auth = if methods.contains(&USER_PASS) {
+        client.write_all(&[VERSION, USER_PASS]).await?;
+        Some(read_credentials(client).await?)
+    } else if methods.contains(&NO_AUTH) {
+        client.write_all(&[VERSION, NO_AUTH]).await?;
+        None
+    } else {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Ok(None);
+    };
+
+    let mut header = [0u8; 4];
+    client.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(anyhow!("malformed SOCKS request"));
+    }
+    let Some(target) = TargetAddr::read(client, header[3]).await? else {
+        send_reply(client, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+        return Ok(None);
+    };
+    if header[1] != CMD_CONNECT {
+        send_reply(client, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Ok(None);
+    }
+    Ok(Some((target, client_auth)))
+}
+
+async fn read_credentials(client: &mut TcpStream) -> Result<(String, String)> {
+    let _version = client.read_u8().await?;
+    let ulen = client.read_u8().await? as usize;
+    let mut user = vec![0u8; ulen];
+    client.read_exact(&mut user).await?;
+    let plen = client.read_u8().await? as usize;
+    let mut pass = vec![0u8; plen];
+    client.read_exact(&mut pass).await?;
+    // Any credentials are accepted; they only select an isolation group.
+    client.write_all(&[0x01, 0x00]).await?;
+    Ok((
+        String::from_utf8_lossy(&user).into_owned(),
+        String::from_utf8_lossy(&pass).into_owned(),
+    ))
+}
+
+/// Sends a reply with an all-zero bound address; clients of a forwarding
+/// proxy have no use for Tor's.
+pub async fn send_reply<W: AsyncWrite + Unpin>(client: &mut W, code: u8) -> Result<()> {
+    client
+        .write_all(&[VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::{Balance, TorInstance};
+    use crate::testutil;
+    use tokio::sync::mpsc;
+
+    type Requests = mpsc::UnboundedReceiver<(TargetAddr, Option<(String, String)>)>;
+
+    /// Tor's SocksPort, as far as the frontend can tell: accepts any
+    /// credentials, reports every CONNECT and echoes what it is sent.
+    async fn upstream() -> (SocketAddr, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (seen, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let Ok(Some(request)) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let _ = seen.send(request);
+                    let _ = send_reply(&mut stream, REPLY_SUCCEEDED).await;
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    /// A SOCKS frontend in front of one stand-in upstream.
+    async fn frontend(isolation: Isolation) -> (SocketAddr, Arc<Isolator>, Arc<TorInstance>, Requests) {
+        let (upstream, requests) = upstream().await;
+        let instance = testutil::instance("tor0", upstream).await;
+        let pool = Arc::new(TorPool::new(vec![instance.clone()], Balance::RoundRobin));
+        let isolator = Arc::new(Isolator::new(isolation));
+        let server = Arc::new(SocksServer::new(pool, isolator.clone(), KillSwitch::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.serve(listener, Shutdown::default()));
+        (addr, isolator, instance, requests)
+    }
+
+    /// Connects through the frontend and returns the credentials it sent
+    /// upstream.
+    async fn upstream_credentials(
+        frontend: SocketAddr,
+        client_auth: Option<(&str, &str)>,
+        requests: &mut Requests,
+    ) -> (String, String) {
+        let target = TargetAddr::Domain("example.com".to_string(), 80);
+        connect(frontend, &target, client_auth).await.unwrap();
+        requests.recv().await.unwrap().1.unwrap()
+    }
+
+    #[tokio::test]
+    async fn same_client_credentials_share_upstream_credentials() {
+        let (frontend, _, _, mut requests) = frontend(Isolation::Client).await;
+        let alice = upstream_credentials(frontend, Some(("alice", "pw")), &mut requests).await;
+        let again = upstream_credentials(frontend, Some(("alice", "pw")), &mut requests).await;
+        let bob = upstream_credentials(frontend, Some(("bob", "pw")), &mut requests).await;
+        let anonymous = upstream_credentials(frontend, None, &mut requests).await;
+        assert_eq!(alice, again);
+        assert_ne!(alice, bob);
+        assert_ne!(alice, anonymous);
+    }
+
+    #[tokio::test]
+    async fn rotation_changes_upstream_credentials() {
+        let (frontend, isolator, _, mut requests) = frontend(Isolation::Client).await;
+        let before = upstream_credentials(frontend, Some(("alice", "pw")), &mut requests).await;
+        isolator.next_epoch();
+        let after = upstream_credentials(frontend, Some(("alice", "pw")), &mut requests).await;
+        assert_ne!(before, after);
+        let again = upstream_credentials(frontend, Some(("alice", "pw")), &mut requests).await;
+        assert_eq!(after, again);
+    }
+
+    #[tokio::test]
+    async fn longest_client_credentials_fit_upstream() {
+        let (frontend, _, _, mut requests) = frontend(Isolation::Client).await;
+        let (user, pass) = ("u".repeat(255), "p".repeat(255));
+        let (upstream_user, _) = upstream_credentials(frontend, Some((&user, &pass)), &mut requests).await;
+        assert!(upstream_user.len() <= 255);
+    }
+
+    #[tokio::test]
+    async fn relays_targets_and_payload_unchanged() {
+        let (frontend, _, instance, mut requests) = frontend(Isolation::None).await;
+        let targets = [
+            TargetAddr::parse("192.0.2.1:80", 0).unwrap(),
+            TargetAddr::parse("[2001:db8::1]:443", 0).unwrap(),
+            TargetAddr::Domain("example.onion".to_string(), 8080),
+        ];
+        for target in &targets {
+            let mut stream = connect(frontend, target, None).await.unwrap();
+            assert_eq!(&requests.recv().await.unwrap().0, target);
+
+            stream.write_all(b"ping").await.unwrap();
+            let mut echo = [0u8; 4];
+            stream.read_exact(&mut echo).await.unwrap();
+            assert_eq!(&echo, b"ping");
+        }
+
+        // Counted once each connection is closed on both ends
+        time::timeout(Duration::from_secs(5), async {
+            while instance.traffic.snapshot().relayed < 24 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let traffic = instance.traffic.snapshot();
+        assert_eq!((traffic.requests, traffic.relayed), (3, 24));
+    }
+
+    #[test]
+    fn client_credentials_are_not_ambiguous() {
+        let isolator = Isolator::new(Isolation::None);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 1));
+        let ab = isolator.credentials(peer, Some(&("a:b".to_string(), "c".to_string())));
+        let a = isolator.credentials(peer, Some(&("a".to_string(), "b:c".to_string())));
+        assert_ne!(ab, a);
+    }
+
+    #[test]
+    fn connection_isolation_never_shares() {
+        let isolator = Isolator::new(Isolation::Connection);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 1));
+        assert_ne!(isolator.credentials(peer, None), isolator.credentials(peer, None));
+    }
+}
@@ -0,0 +1,104 @@
+//! What the rotation loops have observed, shared with the management API.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::rotation::RotationOutcome;
+
+/// Rotations kept for `GET /history`.
+const HISTORY_LEN: usize = 100;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExitStatus {
+    pub ip: Option<String>,
+    /// "City, Country" from the geolocation lookup, with the AS if known.
+    pub location: Option<String>,
+    pub country: Option<String>,
+    /// Autonomous system of the exit, from a MaxMind ASN database.
+    pub asn: Option<String>,
+    /// Whether the IP was verified as a Tor exit.
+    pub is_tor: Option<bool>,
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InstanceStatus {
+    pub exit: ExitStatus,
+    pub policy: String,
+    pub last_rotation: Option<DateTime<Utc>>,
+    pub rotations: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RotationRecord {
+    pub at: DateTime<Utc>,
+    pub instance: String,
+    pub old_ip: Option<String>,
+    pub new_ip: Option<String>,
+    pub exit_country: Option<String>,
+    pub attempts: u32,
+    pub elapsed_secs: f64,
+    pub rate_limited: u32,
+    pub succeeded: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Status {
+    instances: Mutex<BTreeMap<String, InstanceStatus>>,
+    history: Mutex<VecDeque<RotationRecord>>,
+}
+
+impl Status {
+    fn update(&self, instance: &str, f: impl FnOnce(&mut InstanceStatus)) {
+        let mut instances = self.instances.lock().unwrap();
+        f(instances.entry(instance.to_string()).or_default());
+    }
+
+    pub fn set_policy(&self, instance: &str, policy: String) {
+        self.update(instance, |status| status.policy = policy);
+    }
+
+    pub fn set_exit(&self, instance: &str, exit: ExitStatus) {
+        self.update(instance, |status| status.exit = exit);
+    }
+
+    /// `exit` is the new exit as checked right after the rotation; `None`
+    /// if it didn't change.
+    pub fn record_rotation(&self, instance: &str, outcome: &RotationOutcome, exit: Option<ExitStatus>) {
+        let now = Utc::now();
+        self.update(instance, |status| {
+            status.last_rotation = Some(now);
+            status.rotations += 1;
+            if let Some(exit) = exit {
+                status.exit = exit;
+            }
+        });
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(RotationRecord {
+            at: now,
+            instance: instance.to_string(),
+            old_ip: outcome.old_ip.clone(),
+            new_ip: outcome.new_ip.clone(),
+            exit_country: outcome.exit_country.clone(),
+            attempts: outcome.attempts,
+            elapsed_secs: outcome.elapsed.as_secs_f64(),
+            rate_limited: outcome.rate_limited,
+            succeeded: outcome.succeeded(),
+        });
+    }
+
+    pub fn instances(&self) -> BTreeMap<String, InstanceStatus> {
+        self.instances.lock().unwrap().clone()
+    }
+
+    /// Oldest first.
+    pub fn history(&self) -> Vec<RotationRecord> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+}
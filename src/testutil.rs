@@ -0,0 +1,159 @@
+//! Stand-ins for Tor shared by the unit tests.
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::Tuning;
+use crate::control::{ControlEndpoint, TorControl};
+use crate::geoip::Geolocator;
+use crate::ip_provider::{HttpProvider, IpProvider, ProviderChain};
+use crate::pool::TorInstance;
+use crate::socks::{self, TargetAddr};
+
+/// A control port on loopback answering each command with what `respond`
+/// returns for it: complete reply lines, without the final CRLF.
+pub async fn fake_control<F>(respond: F) -> TorControl
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let respond = Arc::new(respond);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(command)) = lines.next_line().await {
+                    let reply = format!("{}\r\n", respond(&command).replace('\n', "\r\n"));
+                    if write.write_all(reply.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    let endpoint = ControlEndpoint::Tcp {
+        host: "127.0.0.1".to_string(),
+        port,
+    };
+    TorControl::connect(&endpoint).await.unwrap()
+}
+
+/// An instance with its SOCKS port at `socks` and a control port that
+/// accepts every command.
+pub async fn instance(name: &str, socks: SocketAddr) -> Arc<TorInstance> {
+    let control = fake_control(|_| "250 OK".to_string()).await;
+    Arc::new(TorInstance::new(name.to_string(), socks, control))
+}
+
+/// An HTTP service on loopback answering every request with `status` and
+/// `body`, counting the requests it got. Returns its URL.
+pub async fn http_server(status: &'static str, body: &'static str) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    (url, hits)
+}
+
+/// Tuning asking `ip_provider` for the exit IP and `tor_check_url`, if
+/// given, whether it is Tor's, with short timeouts and no geolocation.
+pub fn tuning(ip_provider: &str, tor_check_url: Option<String>) -> Tuning {
+    let timeout = Duration::from_secs(5);
+    let provider = HttpProvider::parse(ip_provider, timeout).unwrap();
+    let providers: Vec<Box<dyn IpProvider>> = vec![Box::new(provider)];
+    Tuning {
+        ip_providers: Arc::new(ProviderChain::new(providers, 1).unwrap()),
+        tor_check_url,
+        geo: Arc::new(Geolocator::new(Vec::new(), None, timeout)),
+        check_timeout: timeout,
+        request_timeout: timeout,
+        circuit_timeout: timeout,
+        client_retries: 0,
+        retry_delay: Duration::ZERO,
+    }
+}
+
+/// Tor's SocksPort on loopback: connects to addresses, and to host names
+/// once `resolve` has turned them into one, which is also how it answers
+/// Tor's RESOLVE extension.
+pub async fn socks_port<R, F>(resolve: R) -> SocketAddr
+where
+    R: Fn(String) -> F + Send + Sync + 'static,
+    F: Future<Output = Option<IpAddr>> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let resolve = Arc::new(resolve);
+    tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let resolve = resolve.clone();
+            tokio::spawn(async move {
+                let _ = socks_session(client, resolve.as_ref()).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn socks_session<R, F>(mut client: TcpStream, resolve: &R) -> anyhow::Result<()>
+where
+    R: Fn(String) -> F,
+    F: Future<Output = Option<IpAddr>>,
+{
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting).await?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    client.read_exact(&mut methods).await?;
+    client.write_all(&[0x05, 0x00]).await?;
+
+    let mut header = [0u8; 4];
+    client.read_exact(&mut header).await?;
+    let target = match TargetAddr::read(&mut client, header[3]).await? {
+        Some(TargetAddr::Ip(addr)) => Some(addr),
+        Some(TargetAddr::Domain(host, port)) => resolve(host).await.map(|ip| SocketAddr::new(ip, port)),
+        None => None,
+    };
+    let Some(target) = target else {
+        // Host unreachable
+        return socks::send_reply(&mut client, 0x04).await;
+    };
+    match (header[1], target.ip()) {
+        // RESOLVE: the address goes in the reply
+        (0xf0, IpAddr::V4(ip)) => {
+            let mut reply = vec![0x05, 0x00, 0x00, 0x01];
+            reply.extend_from_slice(&ip.octets());
+            reply.extend_from_slice(&[0, 0]);
+            client.write_all(&reply).await?;
+        }
+        _ => {
+            let mut upstream = TcpStream::connect(target).await?;
+            socks::send_reply(&mut client, 0x00).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        }
+    }
+    Ok(())
+}